@@ -0,0 +1,130 @@
+use atm::output::OutputOptions;
+use atm::state::State;
+use atm::transaction::{Action, Client, Transaction, Tx};
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use csv::Writer;
+
+const TRANSACTIONS: u32 = 100_000;
+
+/// A small deterministic generator so that every run benchmarks the same
+/// input.
+struct Lcg(u64);
+
+impl Lcg {
+    fn next(&mut self) -> u64 {
+        self.0 = self
+            .0
+            .wrapping_mul(6_364_136_223_846_793_005)
+            .wrapping_add(1_442_695_040_888_963_407);
+        self.0 >> 33
+    }
+}
+
+/// Generate a mix of mostly deposits and withdrawals, with some disputes,
+/// resolves and chargebacks of earlier deposits.
+fn generate(seed: u64, clients: u32, count: u32) -> Vec<Transaction> {
+    let mut rng = Lcg(seed);
+    let mut deposits: Vec<(Client, Tx)> = Vec::new();
+    (0..count)
+        .map(|i| {
+            let client = Client::new((rng.next() % u64::from(clients)) as u32 + 1);
+            let tx = Tx::new(i + 1);
+            let roll = rng.next() % 100;
+            let earlier = if deposits.is_empty() {
+                None
+            } else {
+                Some(deposits[rng.next() as usize % deposits.len()])
+            };
+            match (roll, earlier) {
+                (0..=4, Some((client, tx))) => Transaction {
+                    client,
+                    tx,
+                    detail: Action::Dispute,
+                    idempotency_key: None,
+                },
+                (5..=7, Some((client, tx))) => Transaction {
+                    client,
+                    tx,
+                    detail: Action::Resolve,
+                    idempotency_key: None,
+                },
+                (8, Some((client, tx))) => Transaction {
+                    client,
+                    tx,
+                    detail: Action::ChargeBack,
+                    idempotency_key: None,
+                },
+                (9..=39, _) => Transaction {
+                    client,
+                    tx,
+                    detail: Action::Withdrawal(rng.next() % 50_000),
+                    idempotency_key: None,
+                },
+                _ => {
+                    deposits.push((client, tx));
+                    Transaction {
+                        client,
+                        tx,
+                        detail: Action::Deposit(rng.next() % 100_000),
+                        idempotency_key: None,
+                    }
+                }
+            }
+        })
+        .collect()
+}
+
+fn apply(transactions: Vec<Transaction>) -> State {
+    let mut state = State::new();
+    for transaction in transactions {
+        let _possible_client_error = state.handle_transaction(transaction);
+    }
+    state
+}
+
+fn write(state: &State) -> Vec<u8> {
+    let mut vec = Vec::new();
+    state
+        .write_csv(
+            &mut Writer::from_writer(&mut vec),
+            &OutputOptions::default(),
+        )
+        .unwrap();
+    vec
+}
+
+fn bench_engine(c: &mut Criterion) {
+    let mut group = c.benchmark_group("handle_transaction");
+    group.throughput(Throughput::Elements(u64::from(TRANSACTIONS)));
+    for &clients in &[10u32, 1_000, 60_000] {
+        let transactions = generate(42, clients, TRANSACTIONS);
+        // Sanity check so that running the benchmarks as tests catches a
+        // broken engine.
+        let state = apply(transactions.clone());
+        assert!(state.iter_accounts().count() <= clients as usize);
+        assert!(state.iter_accounts().any(|account| account.total() > 0));
+        group.bench_with_input(
+            BenchmarkId::from_parameter(clients),
+            &transactions,
+            |b, transactions| b.iter(|| apply(black_box(transactions.clone()))),
+        );
+    }
+    group.finish();
+
+    let mut group = c.benchmark_group("write_csv");
+    for &clients in &[1_000u32, 60_000] {
+        let state = apply(generate(7, clients, TRANSACTIONS));
+        let output = write(&state);
+        assert_eq!(
+            output.iter().filter(|&&b| b == b'\n').count(),
+            state.iter_accounts().count() + 1
+        );
+        group.bench_with_input(BenchmarkId::from_parameter(clients), &state, |b, state| {
+            b.iter(|| write(black_box(state)))
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_engine);
+criterion_main!(benches);
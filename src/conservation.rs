@@ -0,0 +1,127 @@
+//! A simple conservation law for checking the arithmetic of the engine.
+use crate::output::format_total;
+use crate::state::AccountView;
+use crate::transaction::Action;
+
+/// Totals of the accepted transactions of a run. If the only transactions
+/// were deposits and withdrawals, the sum of `available` over all accounts
+/// must be exactly the deposits less the withdrawals, and nothing can be
+/// `held`.
+#[derive(Debug, Default)]
+pub struct Conservation {
+    deposits: u128,
+    withdrawals: u128,
+    /// Whether any disputes, resolves or (reversed) chargebacks were accepted, in which
+    /// case the check doesn't apply.
+    other: bool,
+}
+
+impl Conservation {
+    pub fn new() -> Self {
+        Conservation::default()
+    }
+
+    /// Record a transaction that was accepted.
+    pub fn record(&mut self, action: Action) {
+        match action {
+            Action::Deposit(amount) => self.deposits += u128::from(amount),
+            Action::Withdrawal(amount) => self.withdrawals += u128::from(amount),
+            Action::Adjust(amount) if amount >= 0 => self.deposits += amount as u128,
+            Action::Adjust(amount) => self.withdrawals += u128::from(amount.unsigned_abs()),
+            Action::Dispute | Action::Resolve | Action::ChargeBack | Action::ReverseChargeback => {
+                self.other = true
+            }
+            // These don't move any funds.
+            Action::Freeze | Action::Unfreeze => (),
+        }
+    }
+
+    /// Whether only deposits and withdrawals were recorded.
+    pub fn applies(&self) -> bool {
+        !self.other
+    }
+
+    /// Check the final `accounts` against the recorded transactions.
+    pub fn check<I: IntoIterator<Item = AccountView>>(&self, accounts: I) -> Result<(), String> {
+        if !self.applies() {
+            return Err("Conservation check only applies to deposits and withdrawals".to_string());
+        }
+        let (mut available, mut held) = (0u128, 0u128);
+        for account in accounts {
+            available += u128::from(account.available);
+            held += u128::from(account.held);
+        }
+        let expected = self.deposits - self.withdrawals;
+        if held != 0 {
+            return Err(format!(
+                "Expected nothing held, but {} is held",
+                format_total(held)
+            ));
+        }
+        if available != expected {
+            return Err(format!(
+                "Available funds total {} but deposits less withdrawals is {}",
+                format_total(available),
+                format_total(expected)
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::State;
+    use crate::transaction::{Client, Transaction, Tx};
+
+    fn run(transactions: &[(u32, u32, Action)]) -> (State, Conservation) {
+        let mut state = State::new();
+        let mut conservation = Conservation::new();
+        for &(client, tx, detail) in transactions {
+            let transaction = Transaction {
+                client: Client::new(client),
+                tx: Tx::new(tx),
+                detail,
+                idempotency_key: None,
+            };
+            if state.handle_transaction(transaction).is_ok() {
+                conservation.record(detail);
+            }
+        }
+        (state, conservation)
+    }
+
+    #[test]
+    fn deposits_and_withdrawals_are_conserved() {
+        let (state, conservation) = run(&[
+            (1, 1, Action::Deposit(5_0000)),
+            (2, 2, Action::Deposit(1_2345)),
+            (1, 3, Action::Withdrawal(2_5000)),
+            (2, 4, Action::Withdrawal(9_0000)),
+            (3, 5, Action::Withdrawal(1)),
+        ]);
+        assert_eq!(conservation.check(state.iter_accounts()), Ok(()));
+    }
+
+    #[test]
+    fn discrepancy_is_detected() {
+        let (state, mut conservation) = run(&[
+            (1, 1, Action::Deposit(5_0000)),
+            (1, 2, Action::Withdrawal(2_5000)),
+        ]);
+        conservation.record(Action::Deposit(1));
+        assert_eq!(
+            conservation.check(state.iter_accounts()),
+            Err("Available funds total 2.5 but deposits less withdrawals is 2.5001".to_string())
+        );
+    }
+
+    #[test]
+    fn disputes_are_not_covered() {
+        let (state, conservation) =
+            run(&[(1, 1, Action::Deposit(5_0000)), (1, 1, Action::Dispute)]);
+        assert!(!conservation.applies());
+        assert!(conservation.check(state.iter_accounts()).is_err());
+    }
+}
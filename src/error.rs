@@ -0,0 +1,312 @@
+use crate::transaction::{Client, Transaction, Tx};
+use std::fmt::Display;
+
+/// The reason a transaction was rejected by an account.
+#[derive(Clone, Debug, PartialEq)]
+pub enum TransactionError {
+    /// The transaction is for a different client than the account.
+    WrongClient {
+        account: Client,
+        transaction: Transaction,
+    },
+    /// The account is locked.
+    Locked(Transaction),
+    /// A withdrawal from an account with an `Action::Freeze` hold.
+    Frozen(Transaction),
+    /// The first transaction of a client wasn't a deposit (with
+    /// `Config::require_deposit_first`).
+    NoPriorDeposit(Transaction),
+    /// A deposit or withdrawal reused the id of an earlier deposit.
+    DuplicateTx(Tx),
+    /// There aren't enough funds available for a withdrawal, dispute or
+    /// direct chargeback.
+    InsufficientFunds(Transaction),
+    /// A deposit or adjustment would make the balance too large to represent.
+    Overflow(Transaction),
+    /// A dispute, resolve or chargeback referred to a deposit that doesn't
+    /// exist.
+    UnknownTx(Tx),
+    /// A resolve or chargeback referred to a deposit that isn't disputed.
+    NotDisputed(Tx),
+    /// A dispute referred to a deposit that is already disputed.
+    AlreadyDisputed(Tx),
+    /// A dispute was denied by `Config::dispute_authorizer`.
+    DisputeNotAuthorized(Transaction),
+    /// Applying the transaction would make `held` negative.
+    NegativeHeld(Tx),
+    /// The account already has the maximum number of open disputes.
+    TooManyOpenDisputes {
+        open: usize,
+        transaction: Transaction,
+    },
+    /// A dispute referred to a deposit that has been partly withdrawn (with
+    /// `Config::fifo_disputes`).
+    FundsWithdrawn {
+        remaining: u64,
+        transaction: Transaction,
+    },
+    /// The type of the transaction is disabled by `Config::disabled_types`.
+    Disabled(Transaction),
+    /// A reverse-chargeback referred to a transaction other than the
+    /// chargeback that locked the account.
+    NotChargedBack(Tx),
+    /// A reverse-chargeback came after `Config::chargeback_grace`, or without
+    /// it being set.
+    ChargebackFinal(Transaction),
+    /// The amount was rejected by `Config::amount_validator`.
+    InvalidAmount {
+        reason: String,
+        transaction: Transaction,
+    },
+    /// Recording the deposit would exceed `Config::max_memory`.
+    MemoryBudgetExceeded {
+        budget: usize,
+        transaction: Transaction,
+    },
+    /// A transaction with the same idempotency key was already accepted, so
+    /// this is a retry.
+    DuplicateKey(String),
+}
+
+impl TransactionError {
+    /// A short code for the kind of error, for tools that process rejections.
+    /// The codes are part of the stable interface: they must not be changed
+    /// or reused once released.
+    ///
+    /// | Variant | Code |
+    /// |---|---|
+    /// | `WrongClient` | `E_WRONG_CLIENT` |
+    /// | `Locked` | `E_LOCKED` |
+    /// | `Frozen` | `E_FROZEN` |
+    /// | `DuplicateTx` | `E_DUPLICATE_TX` |
+    /// | `InsufficientFunds` | `E_INSUFFICIENT_FUNDS` |
+    /// | `Overflow` | `E_OVERFLOW` |
+    /// | `UnknownTx` | `E_UNKNOWN_TX` |
+    /// | `NotDisputed` | `E_NOT_DISPUTED` |
+    /// | `AlreadyDisputed` | `E_ALREADY_DISPUTED` |
+    /// | `NegativeHeld` | `E_NEGATIVE_HELD` |
+    /// | `TooManyOpenDisputes` | `E_TOO_MANY_DISPUTES` |
+    /// | `DuplicateKey` | `E_DUPLICATE_KEY` |
+    /// | `FundsWithdrawn` | `E_FUNDS_WITHDRAWN` |
+    /// | `Disabled` | `E_TYPE_DISABLED` |
+    /// | `MemoryBudgetExceeded` | `E_MEMORY_BUDGET` |
+    /// | `InvalidAmount` | `E_INVALID_AMOUNT` |
+    /// | `NotChargedBack` | `E_NOT_CHARGED_BACK` |
+    /// | `ChargebackFinal` | `E_CHARGEBACK_FINAL` |
+    /// | `NoPriorDeposit` | `E_NO_PRIOR_DEPOSIT` |
+    /// | `DisputeNotAuthorized` | `E_DISPUTE_NOT_AUTHORIZED` |
+    pub fn code(&self) -> &'static str {
+        match self {
+            TransactionError::WrongClient { .. } => "E_WRONG_CLIENT",
+            TransactionError::Locked(_) => "E_LOCKED",
+            TransactionError::Frozen(_) => "E_FROZEN",
+            TransactionError::DuplicateTx(_) => "E_DUPLICATE_TX",
+            TransactionError::InsufficientFunds(_) => "E_INSUFFICIENT_FUNDS",
+            TransactionError::Overflow(_) => "E_OVERFLOW",
+            TransactionError::UnknownTx(_) => "E_UNKNOWN_TX",
+            TransactionError::NotDisputed(_) => "E_NOT_DISPUTED",
+            TransactionError::AlreadyDisputed(_) => "E_ALREADY_DISPUTED",
+            TransactionError::NegativeHeld(_) => "E_NEGATIVE_HELD",
+            TransactionError::TooManyOpenDisputes { .. } => "E_TOO_MANY_DISPUTES",
+            TransactionError::DuplicateKey(_) => "E_DUPLICATE_KEY",
+            TransactionError::FundsWithdrawn { .. } => "E_FUNDS_WITHDRAWN",
+            TransactionError::Disabled(_) => "E_TYPE_DISABLED",
+            TransactionError::MemoryBudgetExceeded { .. } => "E_MEMORY_BUDGET",
+            TransactionError::InvalidAmount { .. } => "E_INVALID_AMOUNT",
+            TransactionError::NotChargedBack(_) => "E_NOT_CHARGED_BACK",
+            TransactionError::ChargebackFinal(_) => "E_CHARGEBACK_FINAL",
+            TransactionError::NoPriorDeposit(_) => "E_NO_PRIOR_DEPOSIT",
+            TransactionError::DisputeNotAuthorized(_) => "E_DISPUTE_NOT_AUTHORIZED",
+        }
+    }
+}
+
+impl Display for TransactionError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TransactionError::WrongClient {
+                account,
+                transaction,
+            } => write!(
+                f,
+                "Transaction cannot be applied to client {:?}: {:?}",
+                account, transaction
+            ),
+            TransactionError::Locked(transaction) => write!(
+                f,
+                "Cannot apply transaction because client account {:?} is locked: {:?}",
+                transaction.client, transaction
+            ),
+            TransactionError::Frozen(transaction) => write!(
+                f,
+                "Cannot withdraw because client account {:?} is frozen: {:?}",
+                transaction.client, transaction
+            ),
+            TransactionError::NoPriorDeposit(transaction) => write!(
+                f,
+                "Client {} has no prior deposit: {:?}",
+                transaction.client, transaction
+            ),
+            TransactionError::DuplicateTx(tx) => write!(f, "Transaction already exists: {:?}", tx),
+            TransactionError::InsufficientFunds(transaction) => {
+                write!(f, "Insufficient funds for transaction: {:?}", transaction)
+            }
+            TransactionError::Overflow(transaction) => {
+                write!(f, "Balance would overflow: {:?}", transaction)
+            }
+            TransactionError::UnknownTx(tx) => write!(f, "Transaction was not found: {:?}", tx),
+            TransactionError::NotDisputed(tx) => {
+                write!(f, "Transaction is not under dispute: {:?}", tx)
+            }
+            TransactionError::AlreadyDisputed(tx) => {
+                write!(f, "Transaction is already under dispute: {:?}", tx)
+            }
+            TransactionError::DisputeNotAuthorized(transaction) => {
+                write!(f, "Dispute not authorized: {:?}", transaction)
+            }
+            TransactionError::NegativeHeld(tx) => {
+                write!(f, "Held funds would become negative: {:?}", tx)
+            }
+            TransactionError::TooManyOpenDisputes { open, transaction } => write!(
+                f,
+                "Client {} already has {} open disputes: {:?}",
+                transaction.client, open, transaction
+            ),
+            TransactionError::Disabled(transaction) => write!(
+                f,
+                "Transaction type {:?} is disabled by policy: {:?}",
+                transaction.detail.type_name(),
+                transaction
+            ),
+            TransactionError::MemoryBudgetExceeded {
+                budget,
+                transaction,
+            } => write!(
+                f,
+                "Memory budget of {} bytes exceeded: {:?}",
+                budget, transaction
+            ),
+            TransactionError::InvalidAmount {
+                reason,
+                transaction,
+            } => write!(f, "Invalid amount ({}): {:?}", reason, transaction),
+            TransactionError::NotChargedBack(tx) => write!(
+                f,
+                "Transaction isn't the chargeback that locked the account: {:?}",
+                tx
+            ),
+            TransactionError::ChargebackFinal(transaction) => {
+                write!(f, "Chargeback can no longer be reversed: {:?}", transaction)
+            }
+            TransactionError::DuplicateKey(key) => write!(
+                f,
+                "Transaction with idempotency key {:?} was already applied",
+                key
+            ),
+            TransactionError::FundsWithdrawn {
+                remaining,
+                transaction,
+            } => write!(
+                f,
+                "Only {} of the deposit remains to be disputed: {:?}",
+                crate::output::format_amount(*remaining),
+                transaction
+            ),
+        }
+    }
+}
+
+impl std::error::Error for TransactionError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transaction::Action;
+
+    #[test]
+    fn documented_codes() {
+        let transaction = Transaction {
+            client: Client::new(1),
+            tx: Tx::new(2),
+            detail: Action::Dispute,
+            idempotency_key: None,
+        };
+        let tx = Tx::new(2);
+        let cases = vec![
+            (
+                TransactionError::WrongClient {
+                    account: Client::new(3),
+                    transaction: transaction.clone(),
+                },
+                "E_WRONG_CLIENT",
+            ),
+            (TransactionError::Locked(transaction.clone()), "E_LOCKED"),
+            (TransactionError::Frozen(transaction.clone()), "E_FROZEN"),
+            (TransactionError::DuplicateTx(tx), "E_DUPLICATE_TX"),
+            (
+                TransactionError::InsufficientFunds(transaction.clone()),
+                "E_INSUFFICIENT_FUNDS",
+            ),
+            (
+                TransactionError::Overflow(transaction.clone()),
+                "E_OVERFLOW",
+            ),
+            (TransactionError::UnknownTx(tx), "E_UNKNOWN_TX"),
+            (TransactionError::NotDisputed(tx), "E_NOT_DISPUTED"),
+            (TransactionError::AlreadyDisputed(tx), "E_ALREADY_DISPUTED"),
+            (TransactionError::NegativeHeld(tx), "E_NEGATIVE_HELD"),
+            (
+                TransactionError::TooManyOpenDisputes {
+                    open: 2,
+                    transaction: transaction.clone(),
+                },
+                "E_TOO_MANY_DISPUTES",
+            ),
+            (
+                TransactionError::DuplicateKey("abc".to_string()),
+                "E_DUPLICATE_KEY",
+            ),
+            (
+                TransactionError::FundsWithdrawn {
+                    remaining: 0,
+                    transaction: transaction.clone(),
+                },
+                "E_FUNDS_WITHDRAWN",
+            ),
+            (
+                TransactionError::Disabled(transaction.clone()),
+                "E_TYPE_DISABLED",
+            ),
+            (
+                TransactionError::MemoryBudgetExceeded {
+                    budget: 10,
+                    transaction: transaction.clone(),
+                },
+                "E_MEMORY_BUDGET",
+            ),
+            (
+                TransactionError::InvalidAmount {
+                    reason: "odd".to_string(),
+                    transaction: transaction.clone(),
+                },
+                "E_INVALID_AMOUNT",
+            ),
+            (TransactionError::NotChargedBack(tx), "E_NOT_CHARGED_BACK"),
+            (
+                TransactionError::ChargebackFinal(transaction.clone()),
+                "E_CHARGEBACK_FINAL",
+            ),
+            (
+                TransactionError::NoPriorDeposit(transaction.clone()),
+                "E_NO_PRIOR_DEPOSIT",
+            ),
+            (
+                TransactionError::DisputeNotAuthorized(transaction),
+                "E_DISPUTE_NOT_AUTHORIZED",
+            ),
+        ];
+        for (error, code) in cases {
+            assert_eq!(error.code(), code, "{}", error);
+        }
+    }
+}
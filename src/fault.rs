@@ -0,0 +1,135 @@
+//! Test hook for corrupting input rows before they are parsed, so the
+//! handling of bad rows can be exercised without crafting fixtures.
+use csv::StringRecord;
+use std::str::FromStr;
+
+/// How a row is corrupted.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum FaultKind {
+    /// Replace the transaction type with one that doesn't exist.
+    BadType,
+    /// Clear the amount.
+    MissingAmount,
+    /// Replace the client with one too large to be parsed.
+    OversizedClient,
+}
+
+impl FaultKind {
+    /// The column that is corrupted and the value it is replaced with.
+    fn corruption(self) -> (&'static str, &'static str) {
+        match self {
+            FaultKind::BadType => ("type", "not-a-type"),
+            FaultKind::MissingAmount => ("amount", ""),
+            FaultKind::OversizedClient => ("client", "99999999999"),
+        }
+    }
+}
+
+/// Corrupt every `every`th row (counting from 1) with `kind`. Parsed from
+/// `every:<n>:<bad-type|missing-amount|oversized-client>`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) struct Fault {
+    every: usize,
+    kind: FaultKind,
+}
+
+impl FromStr for Fault {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parts: Vec<&str> = s.split(':').collect();
+        let (every, kind) = match parts.as_slice() {
+            ["every", every, kind] => (every, kind),
+            _ => return Err(format!("expected every:<n>:<kind>, got {:?}", s)),
+        };
+        let every = match every.parse() {
+            Ok(0) | Err(_) => return Err(format!("invalid row interval {:?}", every)),
+            Ok(every) => every,
+        };
+        let kind = match *kind {
+            "bad-type" => FaultKind::BadType,
+            "missing-amount" => FaultKind::MissingAmount,
+            "oversized-client" => FaultKind::OversizedClient,
+            other => return Err(format!("unknown fault kind {:?}", other)),
+        };
+        Ok(Fault { every, kind })
+    }
+}
+
+/// Applies a `Fault` to a stream of rows.
+#[derive(Debug)]
+pub(crate) struct FaultInjector {
+    fault: Fault,
+    rows: usize,
+}
+
+impl FaultInjector {
+    pub(crate) fn new(fault: Fault) -> Self {
+        FaultInjector { fault, rows: 0 }
+    }
+
+    /// Corrupt `record` if it is one of the rows selected by the fault.
+    /// `headers` are the header row of the file `record` came from.
+    pub(crate) fn apply(&mut self, headers: &StringRecord, record: StringRecord) -> StringRecord {
+        self.rows += 1;
+        if !self.rows.is_multiple_of(self.fault.every) {
+            return record;
+        }
+        let (column, value) = self.fault.kind.corruption();
+        let index = headers.iter().position(|name| name == column);
+        let mut corrupted: StringRecord = record
+            .iter()
+            .enumerate()
+            .map(|(i, field)| if Some(i) == index { value } else { field })
+            .collect();
+        // So that errors for the row still say where it is.
+        corrupted.set_position(record.position().cloned());
+        corrupted
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_fault() {
+        assert_eq!(
+            "every:3:missing-amount".parse(),
+            Ok(Fault {
+                every: 3,
+                kind: FaultKind::MissingAmount
+            })
+        );
+        assert!("every:0:bad-type".parse::<Fault>().is_err());
+        assert!("every:2:typo".parse::<Fault>().is_err());
+        assert!("2:bad-type".parse::<Fault>().is_err());
+    }
+
+    #[test]
+    fn corrupts_every_nth_row() {
+        let headers = StringRecord::from(vec!["type", "client", "tx", "amount"]);
+        let mut injector = FaultInjector::new("every:2:oversized-client".parse().unwrap());
+        let rows: Vec<_> = (1..=4)
+            .map(|tx| {
+                let record = StringRecord::from(vec!["deposit", "1", &tx.to_string(), "1.0"]);
+                injector.apply(&headers, record)
+            })
+            .map(|record| record[1].to_string())
+            .collect();
+        assert_eq!(rows, vec!["1", "99999999999", "1", "99999999999"]);
+    }
+
+    #[test]
+    fn keeps_position() {
+        let headers = StringRecord::from(vec!["type", "client", "tx", "amount"]);
+        let mut injector = FaultInjector::new("every:1:bad-type".parse().unwrap());
+        let mut record = StringRecord::from(vec!["deposit", "1", "1", "1.0"]);
+        let mut position = csv::Position::new();
+        position.set_line(3).set_record(2);
+        record.set_position(Some(position.clone()));
+        let corrupted = injector.apply(&headers, record);
+        assert_ne!(&corrupted[0], "deposit");
+        assert_eq!(corrupted.position(), Some(&position));
+    }
+}
@@ -0,0 +1,143 @@
+use atm::report;
+use atm::verbosity::Verbosity;
+use std::{
+    collections::HashSet,
+    fs::File,
+    io,
+    io::{BufRead, BufReader, Read},
+    path::{Path, PathBuf},
+};
+
+/// Whether `input` should be fetched over HTTP(S) rather than read from the
+/// filesystem.
+pub(crate) fn is_url(input: &Path) -> bool {
+    input
+        .to_str()
+        .map(|s| s.starts_with("http://") || s.starts_with("https://"))
+        .unwrap_or(false)
+}
+
+/// Whether `input` is `-`, meaning standard input.
+pub(crate) fn is_stdin(input: &Path) -> bool {
+    input == Path::new("-")
+}
+
+/// Check whether any of the inputs refer to the same file (after resolving
+/// relative paths and links), since processing a file twice would apply its
+/// transactions twice. URLs and `-` aren't files, so they're compared as
+/// written. If `allow_duplicates` is set, repeats are dropped with a warning,
+/// otherwise they are an error.
+pub(crate) fn dedupe(inputs: &[PathBuf], allow_duplicates: bool) -> Result<Vec<PathBuf>, String> {
+    let mut seen = HashSet::new();
+    let mut deduped = Vec::new();
+    for input in inputs {
+        let key = if is_url(input) || is_stdin(input) {
+            input.clone()
+        } else {
+            input
+                .canonicalize()
+                .map_err(|e| format!("Could not open {:?}: {}", input, e))?
+        };
+        if seen.insert(key) {
+            deduped.push(input.clone());
+        } else if allow_duplicates {
+            report!(
+                Verbosity::Normal,
+                "Warning: skipping repeated input {:?}",
+                input
+            );
+        } else {
+            return Err(format!("Input {:?} was given more than once", input));
+        }
+    }
+    Ok(deduped)
+}
+
+/// The first bytes of a zstd frame.
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+
+/// Open the input for reading. `-` is standard input. URLs are streamed
+/// using a blocking GET with the given extra `headers`, following redirects.
+/// With `mmap` regular files are memory mapped, anything else (standard
+/// input, URLs, pipes, devices) is read as usual. Input that starts with the
+/// zstd magic number is decompressed.
+pub(crate) fn open(
+    input: &Path,
+    headers: &[(String, String)],
+    mmap: bool,
+) -> io::Result<Box<dyn Read>> {
+    let reader = if is_stdin(input) {
+        Box::new(io::stdin().lock())
+    } else if is_url(input) {
+        open_url(input.to_str().unwrap(), headers)?
+    } else {
+        let file = File::open(input)?;
+        if mmap && file.metadata()?.is_file() {
+            map(file)?
+        } else {
+            Box::new(file)
+        }
+    };
+    let mut reader = BufReader::new(reader);
+    if reader.fill_buf()?.starts_with(&ZSTD_MAGIC) {
+        decompress(reader)
+    } else {
+        Ok(Box::new(reader))
+    }
+}
+
+#[cfg(feature = "zstd")]
+fn decompress(reader: BufReader<Box<dyn Read>>) -> io::Result<Box<dyn Read>> {
+    Ok(Box::new(zstd::Decoder::with_buffer(reader)?))
+}
+
+#[cfg(not(feature = "zstd"))]
+fn decompress(_reader: BufReader<Box<dyn Read>>) -> io::Result<Box<dyn Read>> {
+    Err(io::Error::other(
+        "Cannot read zstd input: built without the `zstd` feature",
+    ))
+}
+
+#[cfg(feature = "mmap")]
+fn map(file: File) -> io::Result<Box<dyn Read>> {
+    // SAFETY: The mapping is only read. If the file is changed while it is
+    // being read the transactions may be garbled, as they could be when
+    // reading it normally.
+    let map = unsafe { memmap2::Mmap::map(&file)? };
+    Ok(Box::new(io::Cursor::new(map)))
+}
+
+#[cfg(not(feature = "mmap"))]
+fn map(_file: File) -> io::Result<Box<dyn Read>> {
+    Err(io::Error::other(
+        "Cannot mmap: built without the `mmap` feature",
+    ))
+}
+
+#[cfg(feature = "http")]
+fn open_url(url: &str, headers: &[(String, String)]) -> io::Result<Box<dyn Read>> {
+    let mut request = ureq::get(url);
+    for (name, value) in headers {
+        request = request.set(name, value);
+    }
+    let response = request
+        .call()
+        .map_err(|e| io::Error::other(e.to_string()))?;
+    Ok(Box::new(response.into_reader()))
+}
+
+#[cfg(not(feature = "http"))]
+fn open_url(url: &str, _headers: &[(String, String)]) -> io::Result<Box<dyn Read>> {
+    Err(io::Error::other(format!(
+        "Cannot read {}: built without the `http` feature",
+        url
+    )))
+}
+
+/// Parse a `Name: value` header.
+pub(crate) fn parse_header(s: &str) -> Result<(String, String), String> {
+    let (name, value) = s
+        .split_once(':')
+        .ok_or_else(|| format!("Header must be of the form `Name: value`: {:?}", s))?;
+    Ok((name.trim().to_string(), value.trim().to_string()))
+}
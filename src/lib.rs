@@ -0,0 +1,59 @@
+//! A simple payments engine: apply a stream of client transactions (deposits,
+//! withdrawals and disputes) to a set of accounts and report the final state
+//! of each account.
+
+use csv::{ReaderBuilder, Trim, Writer};
+use output::OutputOptions;
+use state::State;
+use std::io::{Read, Write};
+use transaction::{
+    check_field_count, check_headers, is_blank, ParseOptions, Transaction, TransactionRow,
+};
+
+pub mod conservation;
+pub mod error;
+pub mod merge;
+pub mod merkle;
+pub mod output;
+pub mod parallel;
+pub mod perf;
+#[cfg(feature = "proto")]
+pub mod proto;
+pub mod reconcile;
+pub mod state;
+pub mod transaction;
+pub mod uniqueness;
+pub mod verbosity;
+pub mod wal;
+
+/// Read transactions as CSV (with headers) from `input`, apply them to an
+/// empty `State` and write the final accounts as CSV to `output`, all using
+/// the default options. Blank lines are skipped and other rows that can't be
+/// parsed are an error, but transactions rejected by the accounts are skipped.
+pub fn process_csv<R: Read, W: Write>(input: R, output: W) -> Result<(), String> {
+    let mut reader = ReaderBuilder::new()
+        .trim(Trim::All)
+        .has_headers(true)
+        .flexible(true)
+        .from_reader(input);
+    let headers = reader.headers().map_err(|e| e.to_string())?.clone();
+    check_headers(&headers)?;
+    let mut state = State::new();
+    for record in reader.records() {
+        let record = record.map_err(|e| e.to_string())?;
+        if is_blank(&record) {
+            continue;
+        }
+        check_field_count(&headers, &record)?;
+        let row: TransactionRow = record
+            .deserialize(Some(&headers))
+            .map_err(|e| e.to_string())?;
+        let transaction = Transaction::from_row(row, &ParseOptions::default())?;
+        let _possible_client_error = state.handle_transaction(transaction);
+    }
+    let mut writer = Writer::from_writer(output);
+    state
+        .write_csv(&mut writer, &OutputOptions::default())
+        .map_err(|e| e.to_string())?;
+    writer.flush().map_err(|e| e.to_string())
+}
@@ -1,29 +1,606 @@
-use csv::{ReaderBuilder, Trim, Writer};
-use state::State;
-use std::{convert::TryFrom, env, io, path::PathBuf};
-use transaction::{Transaction, TransactionRow};
+use atm::conservation::Conservation;
+use atm::error::TransactionError;
+use atm::merge::{merge_by_tx, MergeByTx};
+use atm::merkle;
+use atm::output::{
+    anonymize, verify_checksum, write_accounts_csv, write_accounts_json, write_client_mapping,
+    Audit, Changelog, ChecksumWriter, DeadLetter, DisputeBalanceLog, ErrorLog, Format,
+    OutputOptions, Summary, Trace,
+};
+use atm::parallel;
+use atm::perf::Perf;
+use atm::reconcile::reconcile;
+use atm::report;
+use atm::state::{AccountView, DuplicateTx, State, StateBuilder};
+use atm::transaction::{
+    check_field_count, check_headers, default_headers, is_blank, looks_like_header,
+    record_position, ParseOptions, Transaction, TransactionRow, COLUMNS, OPTIONAL_COLUMNS,
+};
+use atm::uniqueness::{SeenTxs, TxUniqueness};
+use atm::verbosity::{self, Verbosity};
+use csv::{ReaderBuilder, StringRecord, Trim, Writer, WriterBuilder};
+use fault::FaultInjector;
+use options::{Options, USAGE};
+use std::{
+    cell::RefCell,
+    env,
+    fs::File,
+    io::{self, Read, Write},
+    path::{Path, PathBuf},
+    process,
+    rc::Rc,
+    sync::mpsc,
+    thread,
+    time::Instant,
+};
 
-mod state;
-mod transaction;
+mod fault;
+mod input;
+mod options;
+mod repl;
+mod selftest;
 
 fn main() {
-    let args: Vec<_> = env::args().collect();
-    if args.len() != 2 {
-        panic!("Usage: cargo run -- <atm-transactions-file>");
+    let args: Vec<String> = env::args().skip(1).collect();
+    match args.first().map(String::as_str) {
+        Some("selftest") => {
+            match selftest::run() {
+                Ok(()) => println!("Self test passed"),
+                Err(err) => {
+                    eprintln!("{}", err);
+                    process::exit(1);
+                }
+            }
+            return;
+        }
+        Some("repl") => {
+            let stdin = io::stdin();
+            repl::run(stdin.lock(), io::stdout()).unwrap();
+            return;
+        }
+        Some("verify-snapshot") => {
+            let rescale = args.get(1).map(String::as_str) == Some("--rescale");
+            let path = args
+                .get(if rescale { 2 } else { 1 })
+                .unwrap_or_else(|| panic!("Missing snapshot file\n{}", USAGE));
+            match verify_snapshot(path, rescale) {
+                Ok(()) => println!("Snapshot is valid"),
+                Err(errors) => {
+                    for err in errors {
+                        eprintln!("{}", err);
+                    }
+                    process::exit(1);
+                }
+            }
+            return;
+        }
+        Some("verify-checksum") => {
+            let path = args
+                .get(1)
+                .unwrap_or_else(|| panic!("Missing output file\n{}", USAGE));
+            let verified = File::open(path)
+                .map_err(|e| format!("Could not open {:?}: {}", path, e))
+                .and_then(verify_checksum);
+            match verified {
+                Ok(()) => println!("Checksum is valid"),
+                Err(err) => {
+                    eprintln!("{}", err);
+                    process::exit(1);
+                }
+            }
+            return;
+        }
+        _ => (),
     }
-    let arg: PathBuf = args[1].parse().unwrap();
-    let mut csv_reader = ReaderBuilder::new()
+    let options =
+        Options::parse(args.into_iter()).unwrap_or_else(|err| panic!("{}\n{}", err, USAGE));
+    verbosity::set(options.verbosity);
+    let inputs =
+        input::dedupe(&options.inputs, options.allow_duplicate_inputs).unwrap_or_else(|err| {
+            eprintln!("{}", err);
+            process::exit(1);
+        });
+    let parse = &options.parse;
+    // Shared by the parsing of the rows and the processing of the
+    // transactions. As the transactions are processed as soon as they're
+    // parsed, the row held by it is that of the transaction being processed.
+    let dead_letter = options.dead_letter.as_ref().map(|path| {
+        let dead_letter = DeadLetter::new(File::create(path).unwrap(), options.delimiter);
+        Rc::new(RefCell::new(dead_letter))
+    });
+    let transactions: Box<dyn Iterator<Item = Transaction>> = if options.merge_by_tx {
+        Box::new(read_merged(&inputs, &options))
+    } else {
+        let mut fault_injector = options.inject_fault.map(FaultInjector::new);
+        // Flushed when the transactions have all been read and it's dropped.
+        let mut rewrite = options.rewrite.as_ref().map(|path| {
+            // In the input's dialect, so that it can replace the input.
+            let mut writer = WriterBuilder::new()
+                .delimiter(options.delimiter)
+                .from_path(path)
+                .unwrap();
+            writer
+                .write_record(COLUMNS.iter().chain(OPTIONAL_COLUMNS.iter()))
+                .unwrap();
+            writer
+        });
+        let rejected_rows = dead_letter.clone();
+        // Counted in data rows, whether or not they turn out to be valid.
+        let csv_rows = inputs
+            .iter()
+            .flat_map(|path| {
+                let reader = input::open(path, &options.headers, options.mmap).unwrap();
+                read_records(path, reader, options.delimiter)
+            })
+            .skip(options.skip)
+            .take(options.limit.unwrap_or(usize::MAX));
+        Box::new(csv_rows.filter_map(move |(header_row, record)| {
+            let record = match (&mut fault_injector, record) {
+                (Some(injector), Ok(record)) => Ok(injector.apply(&header_row, record)),
+                (_, record) => record,
+            };
+            let mut rejected_rows = rejected_rows.as_ref().map(|rows| rows.borrow_mut());
+            parse_record(
+                &header_row,
+                record,
+                parse,
+                rewrite.as_mut(),
+                rejected_rows.as_deref_mut(),
+            )
+        }))
+    };
+
+    let mut rows = 0;
+    let mut seen_txs = SeenTxs::new(options.tx_scope);
+    let tx_uniqueness = options.tx_uniqueness;
+    let transactions = transactions
+        .inspect(|_| rows += 1)
+        .filter(|transaction| options.as_of_tx.is_none_or(|tx| transaction.tx <= tx))
+        .filter(|transaction| {
+            if tx_uniqueness == TxUniqueness::Off {
+                return true;
+            }
+            match seen_txs.check(transaction) {
+                Ok(()) => true,
+                Err(err) if tx_uniqueness == TxUniqueness::Row => {
+                    report!(Verbosity::Normal, "Skipping transaction: {}", err);
+                    if let Some(dead_letter) = &dead_letter {
+                        dead_letter.borrow_mut().reject_held(&err).unwrap();
+                    }
+                    false
+                }
+                Err(err) => {
+                    eprintln!("Aborting: {}", err);
+                    process::exit(1);
+                }
+            }
+        });
+    let mut builder = StateBuilder::new()
+        .lenient_post_lock(options.lenient_post_lock)
+        .allow_locked_adjust(options.allow_locked_adjust)
+        .dispute_timeout(options.dispute_timeout)
+        .lenient_resolve(options.lenient_resolve)
+        .allow_direct_chargeback(options.allow_direct_chargeback)
+        .partial_hold(options.partial_hold)
+        .dispute_clamp(options.dispute_clamp)
+        .require_deposit_first(options.require_deposit_first)
+        .duplicate_tx(options.duplicate_tx)
+        .max_open_disputes(options.max_open_disputes)
+        .chargeback_grace(options.chargeback_grace)
+        .fifo_disputes(options.fifo_disputes)
+        .disabled_types(options.disabled_types.clone())
+        .record_dispute_events(options.dispute_log.is_some())
+        .max_memory(options.max_memory);
+    if let Some(dir) = &options.spill_dir {
+        builder = builder.spill(dir.clone(), options.spill_cache);
+    }
+    let mut conservation = Conservation::new();
+    let mut rejected = 0;
+    let state = if options.workers > 1 {
+        parallel::process_sharded(transactions, options.workers, options.shard, &builder)
+    } else {
+        let mut state = builder.build();
+        let mut changelog = options
+            .changelog
+            .as_ref()
+            .map(|path| Changelog::new(File::create(path).unwrap(), &options.output).unwrap());
+        let mut dispute_log = options.dispute_balance_log.as_ref().map(|path| {
+            DisputeBalanceLog::new(File::create(path).unwrap(), &options.output).unwrap()
+        });
+        let mut errors = options
+            .errors
+            .as_ref()
+            .map(|path| ErrorLog::new(File::create(path).unwrap(), &options.output).unwrap());
+        let mut audit = options
+            .audit
+            .clone()
+            .map(|dir| Audit::new(dir, &options.output));
+        let mut trace = options
+            .trace
+            .as_ref()
+            .map(|path| Trace::new(io::BufWriter::new(File::create(path).unwrap())));
+        // Rejections by policy are reported separately from the ones by the
+        // accounts.
+        let mut disabled = 0;
+        let mut perf = options.perf.then(Perf::new);
+        for transaction in transactions {
+            let (client, tx, detail) = (transaction.client, transaction.tx, transaction.detail);
+            // Kept for the outputs that need the whole transaction after applying it.
+            let copy = (trace.is_some() || audit.is_some()).then(|| transaction.clone());
+            let before = audit.is_some().then(|| state.account(client)).flatten();
+            let result = match &mut perf {
+                Some(perf) => {
+                    let start = Instant::now();
+                    let result = state.handle_transaction(transaction);
+                    perf.record(start.elapsed());
+                    result
+                }
+                None => state.handle_transaction(transaction),
+            };
+            if let (Some(trace), Some(transaction)) = (&mut trace, &copy) {
+                trace
+                    .record(transaction, &result, state.account(client))
+                    .unwrap();
+            }
+            match &result {
+                Ok(()) => report!(
+                    Verbosity::Debug,
+                    "Applied {} of client {} tx {}",
+                    detail.type_name(),
+                    client,
+                    tx
+                ),
+                Err(err) => report!(
+                    Verbosity::Verbose,
+                    "Rejected {} of client {} tx {} [{}]: {}",
+                    detail.type_name(),
+                    client,
+                    tx,
+                    err.code(),
+                    err
+                ),
+            }
+            rejected += usize::from(result.is_err());
+            match &result {
+                Ok(()) => conservation.record(detail),
+                Err(TransactionError::Disabled(_)) => disabled += 1,
+                Err(_) => (),
+            }
+            if let (Ok(()), Some(audit), Some(transaction)) = (&result, &mut audit, &copy) {
+                audit.record(transaction, before, &state.account(client).unwrap());
+            }
+            if let (Ok(()), Some(dispute_log)) = (&result, &mut dispute_log) {
+                let account = state.account(client).unwrap();
+                dispute_log.record(tx, detail, &account).unwrap();
+            }
+            if let (Err(err), Some(dead_letter)) = (&result, &dead_letter) {
+                dead_letter
+                    .borrow_mut()
+                    .reject_held(&err.to_string())
+                    .unwrap();
+            }
+            match (result, &mut changelog, &mut errors) {
+                (Ok(()), Some(changelog), _) => {
+                    let account = state.account(client).unwrap();
+                    changelog.record(tx, &account).unwrap();
+                }
+                (Err(err), _, Some(errors)) => errors.record(client, tx, &err).unwrap(),
+                _ => (),
+            }
+        }
+        if let Some(changelog) = &mut changelog {
+            changelog.flush().unwrap();
+        }
+        if let Some(dispute_log) = &mut dispute_log {
+            dispute_log.flush().unwrap();
+        }
+        if let Some(dead_letter) = &dead_letter {
+            dead_letter.borrow_mut().flush().unwrap();
+        }
+        if let Some(errors) = &mut errors {
+            errors.flush().unwrap();
+        }
+        if let Some(trace) = &mut trace {
+            trace.flush().unwrap();
+        }
+        if let Some(audit) = &audit {
+            audit.write().unwrap();
+        }
+        if disabled > 0 {
+            report!(
+                Verbosity::Normal,
+                "Rejected {} transactions of disabled types",
+                disabled
+            );
+        }
+        if let Some(perf) = &perf {
+            eprintln!("{}", perf);
+        }
+        state
+    };
+    if rows == 0 && options.fail_on_empty {
+        eprintln!("No transactions were found in {:?}", inputs);
+        process::exit(1);
+    }
+
+    if options.duplicate_tx == DuplicateTx::Error && state.duplicates() > 0 {
+        eprintln!("Rejected {} duplicate transactions", state.duplicates());
+        process::exit(1);
+    }
+
+    if options.conserve_check {
+        if !conservation.applies() {
+            report!(
+                Verbosity::Normal,
+                "Warning: skipping --conserve-check as the input has disputes"
+            );
+        } else if let Err(err) = conservation.check(state.iter_accounts()) {
+            eprintln!("Conservation check failed: {}", err);
+            process::exit(1);
+        }
+    }
+
+    if let Some(path) = &options.reconcile {
+        let expected =
+            File::open(path).unwrap_or_else(|e| panic!("Could not open {:?}: {}", path, e));
+        match reconcile(
+            expected,
+            state.iter_accounts(),
+            options.tolerance,
+            options.delimiter,
+            &options.parse,
+        ) {
+            Ok(differences) if differences.is_empty() => (),
+            Ok(differences) => {
+                for difference in &differences {
+                    eprintln!("{}", difference);
+                }
+                eprintln!("Reconciliation failed: {} differences", differences.len());
+                process::exit(1);
+            }
+            Err(err) => {
+                eprintln!("Invalid expected balances {:?}: {}", path, err);
+                process::exit(1);
+            }
+        }
+    }
+
+    if options.merkle_root {
+        let root = merkle::merkle_root(state.iter_accounts());
+        eprintln!("merkle_root={}", merkle::to_hex(&root));
+    }
+    if options.json_summary {
+        eprintln!(
+            "{}",
+            Summary::new(state.iter_accounts(), rejected).to_json()
+        );
+    }
+
+    if let Some(path) = &options.snapshot {
+        state.save_snapshot(File::create(path).unwrap()).unwrap();
+    }
+    if let Some(path) = &options.dispute_log {
+        state
+            .write_dispute_log(File::create(path).unwrap())
+            .unwrap();
+    }
+    if let Some(path) = &options.dump_internal {
+        state
+            .write_internal_dump(File::create(path).unwrap())
+            .unwrap();
+    }
+
+    // Collected up front so that every output uses the same ids.
+    let anonymized = options.anonymize.then(|| {
+        let mut accounts = state.accounts_sorted_by(options.output.sort_by);
+        if options.output.hide_empty {
+            accounts.retain(|account| !account.is_empty());
+        }
+        let mapping = anonymize(&mut accounts);
+        if let Some(path) = &options.mapping {
+            write_client_mapping(File::create(path).unwrap(), &mapping).unwrap();
+        }
+        accounts
+    });
+    let sorted_accounts = || {
+        anonymized
+            .clone()
+            .unwrap_or_else(|| state.accounts_sorted_by(options.output.sort_by))
+    };
+
+    if let Some(split) = &options.split_output {
+        split.write(sorted_accounts(), &options.output).unwrap();
+        return;
+    }
+    if options.output_csv.is_some() || options.output_json.is_some() {
+        let accounts = sorted_accounts();
+        if let Some(path) = &options.output_csv {
+            let mut writer = options.output.csv_writer(File::create(path).unwrap());
+            write_accounts_csv(&mut writer, accounts.iter().copied(), &options.output).unwrap();
+            writer.flush().unwrap();
+        }
+        if let Some(path) = &options.output_json {
+            let writer = io::BufWriter::new(File::create(path).unwrap());
+            write_accounts_json(writer, accounts, &options.output).unwrap();
+        }
+        return;
+    }
+    let written = match options.output.format {
+        Format::Csv if options.checksum => {
+            let mut writer = options.output.csv_writer(ChecksumWriter::new(io::stdout()));
+            write_csv(&mut writer, &state, anonymized.as_deref(), &options.output)
+                .and_then(|()| writer.flush().map_err(csv::Error::from))
+                .and_then(|()| {
+                    // Already flushed, so getting the inner writer can't fail.
+                    let checksum = writer.into_inner().ok().unwrap();
+                    checksum.finish().map(drop).map_err(csv::Error::from)
+                })
+        }
+        Format::Csv => {
+            let mut writer = options.output.csv_writer(io::stdout());
+            write_csv(&mut writer, &state, anonymized.as_deref(), &options.output)
+                .and_then(|()| writer.flush().map_err(csv::Error::from))
+        }
+        #[cfg(feature = "proto")]
+        Format::Protobuf => atm::proto::write_accounts(sorted_accounts(), &mut io::stdout())
+            .map_err(csv::Error::from),
+        #[cfg(not(feature = "proto"))]
+        Format::Protobuf => unreachable!("protobuf output requires the `proto` feature"),
+    };
+    match written {
+        Ok(()) => (),
+        // The reader went away (e.g. `atm input.csv | head`), so there's
+        // nobody left to tell.
+        Err(err) if is_broken_pipe(&err) => process::exit(0),
+        Err(err) => panic!("Failed to write output: {}", err),
+    }
+}
+
+/// Write the accounts of `state` as CSV, or the `anonymized` ones instead if
+/// given.
+fn write_csv<W: Write>(
+    writer: &mut Writer<W>,
+    state: &State,
+    anonymized: Option<&[AccountView]>,
+    options: &OutputOptions,
+) -> csv::Result<()> {
+    match anonymized {
+        Some(accounts) => write_accounts_csv(writer, accounts.iter().copied(), options),
+        None => state.write_csv(writer, options),
+    }
+}
+
+fn is_broken_pipe(err: &csv::Error) -> bool {
+    matches!(err.kind(), csv::ErrorKind::Io(err) if err.kind() == io::ErrorKind::BrokenPipe)
+}
+
+/// Read the rows of one input, paired with the header row they should be
+/// deserialized with.
+fn read_records(
+    path: &Path,
+    reader: impl Read,
+    delimiter: u8,
+) -> impl Iterator<Item = (Rc<StringRecord>, csv::Result<StringRecord>)> {
+    let csv_reader = ReaderBuilder::new()
         .trim(Trim::All) // Input file might have extra spaces.
-        .has_headers(true) // Input file must have headers.
-        .from_path(arg)
-        .unwrap();
+        .delimiter(delimiter)
+        .flexible(true) // Blank lines are skipped, other rows are checked below.
+        .has_headers(false) // Whether there is a header row is checked below.
+        .from_reader(reader);
+    // Later inputs may be continuations without their own header row, so
+    // the first row is only treated as a header if it looks like one.
+    let mut records = csv_reader
+        .into_records()
+        .filter(|record| !matches!(record, Ok(record) if is_blank(record)));
+    let (header_row, first) = match records.next() {
+        Some(Ok(record)) if looks_like_header(&record) => {
+            if let Err(err) = check_headers(&record) {
+                eprintln!("Invalid header row of {:?}: {}", path, err);
+                process::exit(1);
+            }
+            (record, None)
+        }
+        first => (default_headers(), first),
+    };
+    let header_row = Rc::new(header_row);
+    first
+        .into_iter()
+        .chain(records)
+        .map(move |record| (Rc::clone(&header_row), record))
+}
 
-    let mut state = State::new();
-    for row in csv_reader.deserialize::<TransactionRow>() {
-        let transaction = Transaction::try_from(row.unwrap()).unwrap();
-        let _possible_client_error = state.handle_transaction(transaction);
+/// Parse a row into a `Transaction`, logging and skipping it if it's invalid.
+/// Valid rows are also written to `rewrite` as they were written in the input.
+/// Invalid rows are written to `dead_letter`, and valid ones are held by it in
+/// case the transaction is rejected.
+fn parse_record(
+    header_row: &StringRecord,
+    record: csv::Result<StringRecord>,
+    parse: &ParseOptions,
+    rewrite: Option<&mut Writer<File>>,
+    dead_letter: Option<&mut DeadLetter<File>>,
+) -> Option<Transaction> {
+    let fields = dead_letter
+        .is_some()
+        .then(|| record.as_ref().ok().cloned().unwrap_or_default());
+    // Errors from the csv crate already say where the record is.
+    let position = record.as_ref().map(record_position).unwrap_or_default();
+    match record
+        .map_err(|e| e.to_string())
+        .and_then(|record| check_field_count(header_row, &record).map(|()| record))
+        .and_then(|record| {
+            record
+                .deserialize::<TransactionRow>(Some(header_row))
+                .map_err(|e| e.to_string())
+        })
+        .and_then(|row| {
+            let raw = rewrite.is_some().then(|| row.to_record());
+            Transaction::from_row(row, parse)
+                .map(|transaction| (transaction, raw))
+                .map_err(|err| format!("{}{}", err, position))
+        }) {
+        Ok((transaction, raw)) => {
+            if let (Some(writer), Some(raw)) = (rewrite, raw) {
+                writer.write_record(&raw).unwrap();
+            }
+            if let (Some(dead_letter), Some(fields)) = (dead_letter, fields) {
+                dead_letter.hold(fields);
+            }
+            Some(transaction)
+        }
+        Err(err) => {
+            report!(Verbosity::Normal, "Skipping row: {}", err);
+            if let (Some(dead_letter), Some(fields)) = (dead_letter, fields) {
+                dead_letter.reject(&fields, &err).unwrap();
+            }
+            None
+        }
     }
+}
+
+/// Read and parse each input on its own thread, merging the transactions into
+/// one stream ordered by tx id.
+fn read_merged(inputs: &[PathBuf], options: &Options) -> MergeByTx<mpsc::IntoIter<Transaction>> {
+    let streams = inputs
+        .iter()
+        .map(|path| {
+            let (sender, receiver) = mpsc::sync_channel(1024);
+            let path = path.clone();
+            let headers = options.headers.clone();
+            let (delimiter, parse, mmap) = (options.delimiter, options.parse.clone(), options.mmap);
+            thread::spawn(move || {
+                let reader = input::open(&path, &headers, mmap).unwrap_or_else(|err| {
+                    eprintln!("Could not open {:?}: {}", path, err);
+                    process::exit(1);
+                });
+                for (header_row, record) in read_records(&path, reader, delimiter) {
+                    if let Some(transaction) = parse_record(&header_row, record, &parse, None, None)
+                    {
+                        if sender.send(transaction).is_err() {
+                            return;
+                        }
+                    }
+                }
+            });
+            receiver.into_iter()
+        })
+        .collect();
+    merge_by_tx(streams)
+}
 
-    let mut writer = Writer::from_writer(io::stdout());
-    state.write_csv(&mut writer).unwrap();
+/// Load a snapshot and check the invariants of all of its accounts,
+/// converting its amounts if it was written with a different scale and
+/// `rescale` is set.
+fn verify_snapshot(path: &str, rescale: bool) -> Result<(), Vec<String>> {
+    let file = File::open(path).map_err(|e| vec![format!("Could not open {:?}: {}", path, e)])?;
+    let state = StateBuilder::new()
+        .rescale_snapshot(rescale)
+        .load_snapshot(file)
+        .map_err(|e| vec![format!("Invalid snapshot {:?}: {}", path, e)])?;
+    let violations = state.check_invariants();
+    if violations.is_empty() {
+        Ok(())
+    } else {
+        Err(violations)
+    }
 }
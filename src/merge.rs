@@ -0,0 +1,85 @@
+//! Merging several streams of transactions, each already ordered by tx id,
+//! into one ordered stream.
+use crate::transaction::{Transaction, Tx};
+use std::{cmp::Reverse, collections::BinaryHeap};
+
+/// A k-way merge of `streams` by tx id. Transactions with the same tx id are
+/// taken from the earlier stream first, and within a stream the order is
+/// kept even if the stream isn't actually sorted.
+pub struct MergeByTx<I: Iterator<Item = Transaction>> {
+    streams: Vec<I>,
+    /// The next transaction of each stream that isn't finished.
+    heads: Vec<Option<Transaction>>,
+    /// The tx id of each head, and the stream it's from.
+    queue: BinaryHeap<Reverse<(Tx, usize)>>,
+}
+
+pub fn merge_by_tx<I: Iterator<Item = Transaction>>(mut streams: Vec<I>) -> MergeByTx<I> {
+    let mut heads = Vec::with_capacity(streams.len());
+    let mut queue = BinaryHeap::with_capacity(streams.len());
+    for (index, stream) in streams.iter_mut().enumerate() {
+        let head = stream.next();
+        if let Some(transaction) = &head {
+            queue.push(Reverse((transaction.tx, index)));
+        }
+        heads.push(head);
+    }
+    MergeByTx {
+        streams,
+        heads,
+        queue,
+    }
+}
+
+impl<I: Iterator<Item = Transaction>> Iterator for MergeByTx<I> {
+    type Item = Transaction;
+
+    fn next(&mut self) -> Option<Transaction> {
+        let Reverse((_, index)) = self.queue.pop()?;
+        let next = self.streams[index].next();
+        if let Some(transaction) = &next {
+            self.queue.push(Reverse((transaction.tx, index)));
+        }
+        std::mem::replace(&mut self.heads[index], next)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transaction::{Action, Client};
+
+    fn deposit(tx: u32, client: u32) -> Transaction {
+        Transaction {
+            client: Client::new(client),
+            tx: Tx::new(tx),
+            detail: Action::Deposit(10_000),
+            idempotency_key: None,
+        }
+    }
+
+    #[test]
+    fn merges_by_tx() {
+        let streams = vec![
+            vec![deposit(1, 1), deposit(4, 1), deposit(5, 1)].into_iter(),
+            vec![].into_iter(),
+            vec![deposit(2, 2), deposit(3, 2), deposit(6, 2)].into_iter(),
+        ];
+        let txs: Vec<Tx> = merge_by_tx(streams)
+            .map(|transaction| transaction.tx)
+            .collect();
+        assert_eq!(txs, (1..=6).map(Tx::new).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn ties_take_earlier_stream_first() {
+        let streams = vec![
+            vec![deposit(1, 1), deposit(2, 1)].into_iter(),
+            vec![deposit(1, 2), deposit(2, 2)].into_iter(),
+        ];
+        let clients: Vec<u32> = merge_by_tx(streams)
+            .map(|transaction| transaction.client.id())
+            .collect();
+        assert_eq!(clients, vec![1, 2, 1, 2]);
+    }
+}
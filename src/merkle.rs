@@ -0,0 +1,113 @@
+//! A Merkle root over the final accounts, to attest to the result of a run.
+use crate::output::format_amount;
+use crate::state::AccountView;
+use sha2::{Digest, Sha256};
+
+type Hash = [u8; 32];
+
+/// The row hashed for an account: `client,available,held,total,locked`.
+fn leaf(account: &AccountView) -> Hash {
+    let row = format!(
+        "{},{},{},{},{}",
+        account.client,
+        format_amount(account.available),
+        format_amount(account.held),
+        format_amount(account.total()),
+        account.locked
+    );
+    // Leaves and nodes are prefixed differently so that a node can't be
+    // passed off as a leaf.
+    Sha256::new()
+        .chain_update([0])
+        .chain_update(row)
+        .finalize()
+        .into()
+}
+
+fn node(left: &Hash, right: &Hash) -> Hash {
+    Sha256::new()
+        .chain_update([1])
+        .chain_update(left)
+        .chain_update(right)
+        .finalize()
+        .into()
+}
+
+/// The root of a binary Merkle tree over `accounts`, which should be in a
+/// deterministic order (e.g. from `State::iter_accounts`). A node without a
+/// sibling is carried up to the next level unchanged. With no accounts the
+/// root is the hash of nothing.
+pub fn merkle_root(accounts: impl IntoIterator<Item = AccountView>) -> Hash {
+    let mut level: Vec<Hash> = accounts.into_iter().map(|account| leaf(&account)).collect();
+    if level.is_empty() {
+        return Sha256::digest([]).into();
+    }
+    while level.len() > 1 {
+        level = level
+            .chunks(2)
+            .map(|pair| match pair {
+                [left, right] => node(left, right),
+                [single] => *single,
+                _ => unreachable!("chunks of 2"),
+            })
+            .collect();
+    }
+    level[0]
+}
+
+/// Format a hash as lowercase hex.
+pub fn to_hex(hash: &Hash) -> String {
+    hash.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transaction::Client;
+
+    fn account(client: u32, available: u64) -> AccountView {
+        AccountView {
+            client: Client::new(client),
+            available,
+            held: 0,
+            locked: false,
+            locked_by: None,
+            withdrawals_blocked: false,
+            peak_held: 0,
+            held_check: Some(0),
+            deposits: 1,
+        }
+    }
+
+    #[test]
+    fn root_is_stable() {
+        let accounts = || (1..=5).map(|client| account(client, 10_000));
+        assert_eq!(merkle_root(accounts()), merkle_root(accounts()));
+        assert_ne!(
+            merkle_root(accounts()),
+            merkle_root(accounts().take(4)),
+            "a missing account changes the root"
+        );
+    }
+
+    #[test]
+    fn root_changes_with_a_balance() {
+        let accounts: Vec<_> = (1..=5).map(|client| account(client, 10_000)).collect();
+        let mut changed = accounts.clone();
+        changed[4].available += 1;
+        assert_ne!(merkle_root(accounts.clone()), merkle_root(changed.clone()));
+        changed[4].available -= 1;
+        changed[2].locked = true;
+        assert_ne!(merkle_root(accounts), merkle_root(changed));
+    }
+
+    #[test]
+    fn single_account_root_is_its_leaf() {
+        let account = account(1, 0);
+        assert_eq!(merkle_root(vec![account]), leaf(&account));
+        assert_eq!(
+            to_hex(&merkle_root(Vec::new())),
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        );
+    }
+}
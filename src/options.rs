@@ -0,0 +1,598 @@
+use crate::fault::Fault;
+use crate::input::parse_header;
+use atm::output::{parse_columns, Column, Format, OutputOptions, SortBy, SplitOutput};
+use atm::parallel::Shard;
+use atm::state::DuplicateTx;
+use atm::transaction::{parse_scaled_amount, ParseOptions, Tx, DECIMALS, TYPES};
+use atm::uniqueness::{TxScope, TxUniqueness};
+use atm::verbosity::Verbosity;
+use std::{fmt::Display, path::PathBuf, str::FromStr};
+
+pub(crate) const USAGE: &str = "Usage: cargo run -- [options] <atm-transactions-file>...
+       cargo run -- selftest
+       cargo run -- repl
+       cargo run -- verify-snapshot [--rescale] <snapshot-file>
+       cargo run -- verify-checksum <output-file>
+
+Multiple input files are processed in order as if they were one file. An input
+may be - to read standard input, or an http:// or https:// URL if built with the
+`http` feature, and may be zstd compressed if built with the `zstd` feature.
+
+Options:
+    --quiet
+        Only report fatal errors on stderr
+    -v, -vv
+        Also report each rejected transaction, and with -vv each applied one
+    --allow-duplicate-inputs
+        Skip an input given more than once with a warning instead of failing
+    --merge-by-tx
+        Read the inputs concurrently and apply their transactions in tx order,
+        assuming each input is already in tx order
+    --mmap
+        Memory map input files rather than reading them. Requires the `mmap`
+        feature
+    --header <name: value>
+        Extra header to send when fetching a URL (repeatable)
+    --max-precision <n>
+        Reject amounts with more than n decimal places (default and at most 4)
+    --ignore-dispute-amount
+        Accept and discard an amount on dispute, resolve, chargeback and
+        reverse-chargeback rows instead of rejecting the row
+    --decimal-sep <char>
+        The decimal separator of amounts in the input and output (default .)
+    --delimiter <char>
+        The field delimiter of the input and CSV output (default ,)
+    --workers <n>
+        Process clients on n worker threads (default 1)
+    --shard <modulo|hash>
+        How clients are assigned to workers (default modulo)
+    --format <csv|protobuf>
+        Output format (default csv). protobuf requires the `proto` feature
+    --columns <names>
+        Comma separated output columns, in order
+        (default client,available,held,total,locked)
+    --with-lock-tx
+        Add a locked_by_tx column with the chargeback that locked each account
+    --with-held-check
+        Add a held_check column with held recomputed from the disputed deposits
+    --with-status
+        Add a status column: frozen if locked, empty if the total is 0,
+        fully-held if only held is non-zero, otherwise active
+    --with-peak-held
+        Add a peak_held column with the most each account had held at once
+    --no-header
+        Leave out the header row of the CSV output
+    --header-names <names>
+        Comma separated names for the header row, one per output column
+    --hide-empty
+        Leave out accounts whose transactions were all rejected
+    --merkle-root
+        Print a Merkle root over the final accounts to stderr
+    --json-summary
+        Print the number of accounts, locked accounts and rejected transactions,
+        and the total available and held, as one line of JSON to stderr
+    --human
+        Format amounts for people to read, e.g. \"1,234.5000\"
+    --checksum
+        Append a row #crc32=<checksum> to the CSV output, see verify-checksum
+    --sort-by <client|first-seen|total|held>
+        Order accounts by client id, by when the client first appeared in the
+        input or by the largest total or held amount (default client)
+    --unsorted
+        Write accounts in no particular order, which is faster for many
+        accounts but differs from run to run
+    --split-output active=<file>,locked=<file>
+        Write active and locked accounts to separate CSV files instead of stdout
+    --anonymize
+        Replace client ids in the output with their position in it (1, 2, ...)
+    --mapping <file>
+        With --anonymize, write the real client for each new id to file
+    --output-csv <file>
+        Write the accounts as CSV to file instead of stdout
+    --output-json <file>
+        Write the accounts as JSON lines to file instead of stdout. Can be
+        combined with --output-csv to write both from one run
+    --tx-uniqueness <off|row|run>
+        Whether a reused deposit/withdrawal tx id is ignored, rejects the row, or
+        aborts the run (default off)
+    --tx-scope <account|global>
+        Whether tx ids must be unique per client or across all clients (default account)
+    --as-of-tx <tx>
+        Only process transactions with a tx id of at most tx. Disputes, resolves
+        and chargebacks are filtered by the id of the transaction they refer to
+    --skip <n>
+        Skip the first n data rows of the input, valid or not
+    --limit <n>
+        Process at most n data rows, after any skipped by --skip
+    --dispute-timeout <n>
+        Resolve disputes still open after n more transactions for the client
+    --max-open-disputes <n>
+        Reject a dispute if the account already has n transactions under dispute
+    --chargeback-grace <n>
+        Allow a reverse-chargeback of the chargeback that locked an account
+        within n more transactions for the client, unlocking it
+    --disable <types>
+        Comma separated transaction types (e.g. withdrawal,chargeback) to reject
+        without applying them
+    --fifo-disputes
+        Pay withdrawals from the oldest deposits first and reject disputes of
+        deposits that have been withdrawn
+    --lenient-resolve
+        Ignore resolves of transactions that aren't under dispute instead of
+        rejecting them
+    --allow-direct-chargeback
+        Allow chargebacks of transactions that aren't disputed
+    --partial-hold
+        Let a dispute for more than is available hold what is available, and hold
+        the rest from later deposits
+    --dispute-clamp
+        Like --partial-hold, but only for the part of the deposit that has been
+        withdrawn (oldest deposits first)
+    --require-deposit-first
+        Reject any transaction but a deposit for a client without an account
+    --duplicate-tx <ignore|update|error>
+        Whether a deposit reusing the id of an earlier deposit is rejected, replaces
+        the earlier amount (unless disputed), or is rejected and fails the run
+        (default ignore)
+    --allow-locked-adjust
+        Apply adjust transactions to locked accounts
+    --lenient-post-lock
+        Allow resolves on locked accounts, clamping held at 0
+    --max-memory <bytes>
+        Reject deposits once the estimated memory used by the accounts would
+        exceed bytes
+    --spill-dir <dir>
+        Keep only the most recently used accounts in memory, storing the rest in dir
+    --spill-cache <n>
+        How many accounts to keep in memory with --spill-dir (default 10000)
+    --changelog <file>
+        Write the new balances of the client after each accepted transaction to file
+    --dispute-balance-log <file>
+        Write the new balances of the client after each accepted dispute,
+        resolve, chargeback or reverse-chargeback to file
+    --audit <dir>
+        Write a file per client to dir listing each accepted transaction with its
+        amount, its change to available and held, and the balances afterwards
+    --trace <file>
+        Write a JSON line to file for every transaction, with whether it was
+        applied, why not and the balances afterwards
+    --dispute-log <file>
+        Write each dispute being opened, resolved, expired, charged back or
+        reversed to file, grouped by client
+    --rewrite <file>
+        Write the valid input rows to file, keeping amounts as they were written
+    --snapshot <file>
+        Write the full final state to file, which verify-snapshot can check
+        (with --rescale if it was written with a different number of decimals)
+    --errors <file>
+        Write each rejected transaction to file with a stable error code
+    --dead-letter <file>
+        Write each row that couldn't be parsed or was rejected to file as it was
+        read, followed by the reason, so that it can be fixed and resubmitted
+    --conserve-check
+        Check that the final balances equal the accepted deposits less withdrawals.
+        Only applies if there are no disputes, resolves or chargebacks. Not
+        supported with --duplicate-tx update
+    --reconcile <file>
+        Compare the final available, held and total of each client with the
+        CSV file of expected balances, reporting differences and failing if
+        there are any
+    --tolerance <amount>
+        How much amounts may differ with --reconcile (default 0)
+    --perf
+        Report the number of transactions, the time taken and the p50/p99 time
+        per transaction to stderr
+    --fail-on-empty
+        Exit with an error if the input has no transactions
+    --dump-internal <file>
+        Debug only: write the engine's internal per-deposit state to file";
+
+/// Parse a comma separated list of transaction types.
+fn parse_types(s: &str) -> Result<Vec<String>, String> {
+    s.split(',')
+        .map(|type_| match type_.trim() {
+            type_ if TYPES.contains(&type_) => Ok(type_.to_string()),
+            other => Err(format!("Unknown transaction type: {:?}", other)),
+        })
+        .collect()
+}
+
+/// Options read from the command line.
+#[derive(Debug, Default)]
+pub(crate) struct Options {
+    pub inputs: Vec<PathBuf>,
+    /// Skip repeated inputs rather than treating them as an error.
+    pub allow_duplicate_inputs: bool,
+    /// How much to report on stderr.
+    pub verbosity: Verbosity,
+    /// Read the inputs concurrently and merge them by tx id.
+    pub merge_by_tx: bool,
+    /// Memory map the input files.
+    pub mmap: bool,
+    /// Print a Merkle root over the final accounts.
+    pub merkle_root: bool,
+    /// Print totals over the final accounts as JSON.
+    pub json_summary: bool,
+    /// Extra headers to send when the input is a URL.
+    pub headers: Vec<(String, String)>,
+    pub parse: ParseOptions,
+    /// The field delimiter of the input (and the CSV output).
+    pub delimiter: u8,
+    pub output: OutputOptions,
+    /// Append a checksum row to the output.
+    pub checksum: bool,
+    /// Write active and locked accounts to separate files.
+    pub split_output: Option<SplitOutput>,
+    /// Replace client ids with their position in the output.
+    pub anonymize: bool,
+    /// Where to write the mapping from anonymized to real client ids.
+    pub mapping: Option<PathBuf>,
+    /// Write the accounts to these files rather than stdout.
+    pub output_csv: Option<PathBuf>,
+    pub output_json: Option<PathBuf>,
+    /// How to handle reused transaction ids.
+    pub tx_uniqueness: TxUniqueness,
+    pub tx_scope: TxScope,
+    /// Only process transactions with a tx id up to this one.
+    pub as_of_tx: Option<Tx>,
+    /// How many data rows to skip at the start of the input.
+    pub skip: usize,
+    /// The most data rows to process after those skipped.
+    pub limit: Option<usize>,
+    /// Resolve disputes still open after this many further transactions.
+    pub dispute_timeout: Option<u64>,
+    /// Maximum number of transactions under dispute per account.
+    pub max_open_disputes: Option<usize>,
+    /// How many transactions a chargeback can be reversed within.
+    pub chargeback_grace: Option<u64>,
+    /// Transaction types that are rejected.
+    pub disabled_types: Vec<String>,
+    /// Reject disputes of deposits that have been withdrawn.
+    pub fifo_disputes: bool,
+    /// Ignore resolves without a prior dispute.
+    pub lenient_resolve: bool,
+    /// Allow chargebacks without a prior dispute.
+    pub allow_direct_chargeback: bool,
+    /// Only hold what is available when a dispute is opened.
+    pub partial_hold: bool,
+    /// Only hold what is available when the rest was withdrawn.
+    pub dispute_clamp: bool,
+    /// Reject a first transaction for a client that isn't a deposit.
+    pub require_deposit_first: bool,
+    /// What to do with a deposit that reuses a deposit's id.
+    pub duplicate_tx: DuplicateTx,
+    /// Allow adjustments of locked accounts.
+    pub allow_locked_adjust: bool,
+    /// Allow resolves on locked accounts.
+    pub lenient_post_lock: bool,
+    /// Number of worker threads to process transactions on.
+    pub workers: usize,
+    /// How clients are distributed between workers.
+    pub shard: Shard,
+    /// Estimated memory above which deposits are rejected.
+    pub max_memory: Option<usize>,
+    /// Where to store accounts that don't fit in memory.
+    pub spill_dir: Option<PathBuf>,
+    /// How many accounts to keep in memory when spilling.
+    pub spill_cache: usize,
+    /// Where to write the balances after each accepted transaction.
+    pub changelog: Option<PathBuf>,
+    /// Where to write the balances after each accepted dispute event.
+    pub dispute_balance_log: Option<PathBuf>,
+    /// Where to write a JSON event for every transaction.
+    pub trace: Option<PathBuf>,
+    /// The directory to write the audit trail of each account to.
+    pub audit: Option<PathBuf>,
+    /// Where to write the dispute events of each account.
+    pub dispute_log: Option<PathBuf>,
+    /// Where to write the valid input rows, unnormalized.
+    pub rewrite: Option<PathBuf>,
+    /// Where to save a snapshot of the final state.
+    pub snapshot: Option<PathBuf>,
+    /// Where to write transactions rejected by the accounts.
+    pub errors: Option<PathBuf>,
+    /// Where to write rejected rows as they were read.
+    pub dead_letter: Option<PathBuf>,
+    /// Check the final balances against the accepted deposits and withdrawals.
+    pub conserve_check: bool,
+    /// Expected balances to compare the final balances with.
+    pub reconcile: Option<PathBuf>,
+    /// The difference allowed by `reconcile`, in 1/10_000's.
+    pub tolerance: u64,
+    /// Time the processing of each transaction.
+    pub perf: bool,
+    /// Treat input without any transactions as an error.
+    pub fail_on_empty: bool,
+    /// Debug only: where to dump the internal state of all accounts.
+    pub dump_internal: Option<PathBuf>,
+    /// Testing only: corrupt input rows before they are parsed. This is
+    /// deliberately left out of `USAGE`.
+    pub inject_fault: Option<Fault>,
+}
+
+impl Options {
+    /// Parse the command line arguments (not including the program name).
+    pub(crate) fn parse<I: Iterator<Item = String>>(mut args: I) -> Result<Self, String> {
+        fn value<I: Iterator<Item = String>>(args: &mut I, flag: &str) -> Result<String, String> {
+            args.next()
+                .ok_or_else(|| format!("Missing value for {}", flag))
+        }
+        fn parsed<T, I>(args: &mut I, flag: &str) -> Result<T, String>
+        where
+            T: FromStr,
+            T::Err: Display,
+            I: Iterator<Item = String>,
+        {
+            value(args, flag)?
+                .parse()
+                .map_err(|e| format!("Invalid value for {}: {}", flag, e))
+        }
+
+        let mut options = Options {
+            workers: 1,
+            spill_cache: 10_000,
+            delimiter: b',',
+            ..Options::default()
+        };
+        // Applied after all arguments so that it doesn't matter whether it
+        // comes before or after --columns.
+        let (mut with_lock_tx, mut with_held_check, mut with_status, mut with_peak_held) =
+            (false, false, false, false);
+        let mut quiet = false;
+        let mut tolerance = None;
+        let mut unsorted = false;
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--quiet" => quiet = true,
+                "-v" => options.verbosity = Verbosity::Verbose,
+                "-vv" => options.verbosity = Verbosity::Debug,
+                "--allow-duplicate-inputs" => options.allow_duplicate_inputs = true,
+                "--merge-by-tx" => options.merge_by_tx = true,
+                "--mmap" if cfg!(feature = "mmap") => options.mmap = true,
+                "--mmap" => return Err("--mmap requires the `mmap` feature".to_string()),
+                "--header" => options
+                    .headers
+                    .push(parse_header(&value(&mut args, &arg)?)?),
+                "--max-precision" => {
+                    let precision = parsed(&mut args, &arg)?;
+                    // Amounts are stored with `DECIMALS` places, so more
+                    // would be rounded rather than rejected.
+                    if precision > DECIMALS {
+                        return Err(format!("--max-precision can't be more than {}", DECIMALS));
+                    }
+                    options.parse.max_precision = precision;
+                }
+                "--ignore-dispute-amount" => options.parse.ignore_dispute_amount = true,
+                "--decimal-sep" => {
+                    let separator: char = parsed(&mut args, &arg)?;
+                    if separator.is_ascii_digit() || separator == '+' {
+                        return Err(format!("Invalid decimal separator: {:?}", separator));
+                    }
+                    options.parse.decimal_separator = separator;
+                    options.output.decimal_separator = separator;
+                }
+                "--delimiter" => {
+                    let delimiter: char = parsed(&mut args, &arg)?;
+                    if !delimiter.is_ascii() {
+                        return Err(format!("Delimiter must be ASCII: {:?}", delimiter));
+                    }
+                    options.delimiter = delimiter as u8;
+                    options.output.delimiter = delimiter as u8;
+                }
+                "--workers" => options.workers = parsed(&mut args, &arg)?,
+                "--shard" => options.shard = parsed(&mut args, &arg)?,
+                "--format" => options.output.format = parsed(&mut args, &arg)?,
+                "--columns" => options.output.columns = parse_columns(&value(&mut args, &arg)?)?,
+                "--human" => options.output.human = true,
+                "--with-lock-tx" => with_lock_tx = true,
+                "--with-held-check" => with_held_check = true,
+                "--with-status" => with_status = true,
+                "--with-peak-held" => with_peak_held = true,
+                "--hide-empty" => options.output.hide_empty = true,
+                "--no-header" => options.output.no_header = true,
+                "--header-names" => {
+                    let names = value(&mut args, &arg)?;
+                    options.output.header_names = Some(
+                        names
+                            .split(',')
+                            .map(|name| name.trim().to_string())
+                            .collect(),
+                    )
+                }
+                "--merkle-root" => options.merkle_root = true,
+                "--json-summary" => options.json_summary = true,
+                "--checksum" => options.checksum = true,
+                "--sort-by" => options.output.sort_by = parsed(&mut args, &arg)?,
+                "--unsorted" => unsorted = true,
+                "--split-output" => options.split_output = Some(parsed(&mut args, &arg)?),
+                "--anonymize" => options.anonymize = true,
+                "--mapping" => options.mapping = Some(parsed(&mut args, &arg)?),
+                "--output-csv" => options.output_csv = Some(parsed(&mut args, &arg)?),
+                "--output-json" => options.output_json = Some(parsed(&mut args, &arg)?),
+                "--tx-uniqueness" => options.tx_uniqueness = parsed(&mut args, &arg)?,
+                "--tx-scope" => options.tx_scope = parsed(&mut args, &arg)?,
+                "--as-of-tx" => options.as_of_tx = Some(Tx::new(parsed(&mut args, &arg)?)),
+                "--skip" => options.skip = parsed(&mut args, &arg)?,
+                "--limit" => options.limit = Some(parsed(&mut args, &arg)?),
+                "--dispute-timeout" => options.dispute_timeout = Some(parsed(&mut args, &arg)?),
+                "--max-open-disputes" => options.max_open_disputes = Some(parsed(&mut args, &arg)?),
+                "--chargeback-grace" => options.chargeback_grace = Some(parsed(&mut args, &arg)?),
+                "--disable" => options.disabled_types = parse_types(&value(&mut args, &arg)?)?,
+                "--fifo-disputes" => options.fifo_disputes = true,
+                "--lenient-resolve" => options.lenient_resolve = true,
+                "--allow-direct-chargeback" => options.allow_direct_chargeback = true,
+                "--partial-hold" => options.partial_hold = true,
+                "--dispute-clamp" => options.dispute_clamp = true,
+                "--require-deposit-first" => options.require_deposit_first = true,
+                "--duplicate-tx" => options.duplicate_tx = parsed(&mut args, &arg)?,
+                "--allow-locked-adjust" => options.allow_locked_adjust = true,
+                "--lenient-post-lock" => options.lenient_post_lock = true,
+                "--max-memory" => options.max_memory = Some(parsed(&mut args, &arg)?),
+                "--spill-dir" => options.spill_dir = Some(parsed(&mut args, &arg)?),
+                "--spill-cache" => options.spill_cache = parsed(&mut args, &arg)?,
+                "--changelog" => options.changelog = Some(parsed(&mut args, &arg)?),
+                "--audit" => options.audit = Some(parsed(&mut args, &arg)?),
+                "--dispute-balance-log" => {
+                    options.dispute_balance_log = Some(parsed(&mut args, &arg)?)
+                }
+                "--trace" => options.trace = Some(parsed(&mut args, &arg)?),
+                "--dispute-log" => options.dispute_log = Some(parsed(&mut args, &arg)?),
+                "--rewrite" => options.rewrite = Some(parsed(&mut args, &arg)?),
+                "--snapshot" => options.snapshot = Some(parsed(&mut args, &arg)?),
+                "--errors" => options.errors = Some(parsed(&mut args, &arg)?),
+                "--conserve-check" => options.conserve_check = true,
+                "--dead-letter" => options.dead_letter = Some(parsed(&mut args, &arg)?),
+                "--reconcile" => options.reconcile = Some(parsed(&mut args, &arg)?),
+                // Parsed after all arguments, with the final --decimal-sep.
+                "--tolerance" => tolerance = Some(value(&mut args, &arg)?),
+                "--perf" => options.perf = true,
+                "--fail-on-empty" => options.fail_on_empty = true,
+                "--dump-internal" => options.dump_internal = Some(parsed(&mut args, &arg)?),
+                "--inject-fault" => options.inject_fault = Some(parsed(&mut args, &arg)?),
+                flag if flag.starts_with("--") => {
+                    return Err(format!("Unknown option: {}", flag));
+                }
+                _ => options.inputs.push(arg.into()),
+            }
+        }
+        if quiet {
+            if options.verbosity != Verbosity::Normal {
+                return Err("--quiet can't be used with -v or -vv".to_string());
+            }
+            options.verbosity = Verbosity::Quiet;
+        }
+        if with_lock_tx && !options.output.columns.contains(&Column::LockedByTx) {
+            options.output.columns.push(Column::LockedByTx);
+        }
+        if with_held_check && !options.output.columns.contains(&Column::HeldCheck) {
+            options.output.columns.push(Column::HeldCheck);
+        }
+        if with_status && !options.output.columns.contains(&Column::Status) {
+            options.output.columns.push(Column::Status);
+        }
+        if with_peak_held && !options.output.columns.contains(&Column::PeakHeld) {
+            options.output.columns.push(Column::PeakHeld);
+        }
+        if let Some(names) = &options.output.header_names {
+            if options.output.no_header {
+                return Err("--header-names can't be used with --no-header".to_string());
+            }
+            if names.len() != options.output.columns.len() {
+                return Err(format!(
+                    "--header-names has {} names but there are {} columns",
+                    names.len(),
+                    options.output.columns.len()
+                ));
+            }
+        }
+        if options.merge_by_tx && options.inject_fault.is_some() {
+            return Err("--inject-fault can't be used with --merge-by-tx".to_string());
+        }
+        if options.merge_by_tx && options.dead_letter.is_some() {
+            return Err("--dead-letter can't be used with --merge-by-tx".to_string());
+        }
+        if options.workers > 1 && options.dead_letter.is_some() {
+            return Err("--dead-letter can't be used with multiple --workers".to_string());
+        }
+        if options.merge_by_tx && (options.skip > 0 || options.limit.is_some()) {
+            return Err("--skip and --limit can't be used with --merge-by-tx".to_string());
+        }
+        if options.merge_by_tx && options.rewrite.is_some() {
+            return Err("--rewrite can't be used with --merge-by-tx".to_string());
+        }
+        if options.dispute_clamp && options.fifo_disputes {
+            return Err("--dispute-clamp can't be used with --fifo-disputes".to_string());
+        }
+        if options.dispute_clamp && options.partial_hold {
+            return Err("--dispute-clamp can't be used with --partial-hold".to_string());
+        }
+        if options.workers == 0 {
+            return Err("--workers must be at least 1".to_string());
+        }
+        if options.workers > 1 && options.changelog.is_some() {
+            return Err("--changelog can't be used with multiple --workers".to_string());
+        }
+        if options.workers > 1 && options.dispute_balance_log.is_some() {
+            return Err("--dispute-balance-log can't be used with multiple --workers".to_string());
+        }
+        if options.workers > 1 && options.audit.is_some() {
+            return Err("--audit can't be used with multiple --workers".to_string());
+        }
+        if options.workers > 1 && options.trace.is_some() {
+            return Err("--trace can't be used with multiple --workers".to_string());
+        }
+        // Each worker has its own `State`, so each would get the whole budget.
+        if options.workers > 1 && options.max_memory.is_some() {
+            return Err("--max-memory can't be used with multiple --workers".to_string());
+        }
+        if options.workers > 1 && options.spill_dir.is_some() {
+            return Err("--spill-dir can't be used with multiple --workers".to_string());
+        }
+        if options.dispute_log.is_some() && options.spill_dir.is_some() {
+            return Err("--dispute-log can't be used with --spill-dir".to_string());
+        }
+        if options.spill_cache == 0 {
+            return Err("--spill-cache must be at least 1".to_string());
+        }
+        if options.conserve_check && options.duplicate_tx == DuplicateTx::Update {
+            return Err("--conserve-check can't be used with --duplicate-tx update".to_string());
+        }
+        if options.workers > 1 && options.conserve_check {
+            return Err("--conserve-check can't be used with multiple --workers".to_string());
+        }
+        if options.workers > 1 && options.json_summary {
+            return Err("--json-summary can't be used with multiple --workers".to_string());
+        }
+        if options.workers > 1 && options.perf {
+            return Err("--perf can't be used with multiple --workers".to_string());
+        }
+        if options.workers > 1 && options.errors.is_some() {
+            return Err("--errors can't be used with multiple --workers".to_string());
+        }
+        if options.workers > 1 && options.output.sort_by == SortBy::FirstSeen {
+            return Err("--sort-by first-seen can't be used with multiple --workers".to_string());
+        }
+        if options.checksum
+            && (options.split_output.is_some() || options.output.format != Format::Csv)
+        {
+            return Err("--checksum only supports csv output to stdout".to_string());
+        }
+        if options.split_output.is_some() && options.output.format != Format::Csv {
+            return Err("--split-output only supports csv output".to_string());
+        }
+        if unsorted {
+            if options.output.sort_by != SortBy::Client {
+                return Err("--unsorted can't be used with --sort-by".to_string());
+            }
+            // The ids would be assigned in a different order on every run.
+            if options.anonymize {
+                return Err("--unsorted can't be used with --anonymize".to_string());
+            }
+            options.output.sort_by = SortBy::Unsorted;
+        }
+        if let Some(tolerance) = tolerance {
+            if options.reconcile.is_none() {
+                return Err("--tolerance requires --reconcile".to_string());
+            }
+            options.tolerance = parse_scaled_amount(&tolerance, &options.parse)
+                .map_err(|e| format!("Invalid --tolerance: {}", e))?;
+        }
+        if options.mapping.is_some() && !options.anonymize {
+            return Err("--mapping requires --anonymize".to_string());
+        }
+        let output_files = options.output_csv.is_some() || options.output_json.is_some();
+        if output_files && (options.split_output.is_some() || options.checksum) {
+            return Err(
+                "--output-csv and --output-json can't be used with --split-output or --checksum"
+                    .to_string(),
+            );
+        }
+        if output_files && options.output.format != Format::Csv {
+            return Err("--output-csv and --output-json can't be used with --format".to_string());
+        }
+        if options.inputs.is_empty() {
+            return Err("Missing input file".to_string());
+        }
+        Ok(options)
+    }
+}
@@ -0,0 +1,1242 @@
+use crate::error::TransactionError;
+use crate::state::AccountView;
+use crate::transaction::{Action, Client, Transaction, Tx, DECIMALS, SCALE};
+use csv::{StringRecord, Writer, WriterBuilder};
+use serde::{ser::SerializeMap, Serialize};
+use std::{
+    collections::HashMap,
+    fs::{self, File},
+    io::{self, Read, Write},
+    mem,
+    path::PathBuf,
+    str::FromStr,
+};
+
+/// A column that can be included in the CSV output.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Column {
+    Client,
+    Available,
+    Held,
+    Total,
+    Locked,
+    /// The chargeback that locked the account, empty if it isn't locked.
+    LockedByTx,
+    /// `held` recomputed from the disputed deposits, prefixed with
+    /// `mismatch:` if it differs from `held`. Empty for locked accounts.
+    HeldCheck,
+    /// See `AccountView::status`.
+    Status,
+    /// See `AccountView::peak_held`.
+    PeakHeld,
+}
+
+impl Column {
+    /// The default set of columns, in order.
+    pub const ALL: [Column; 5] = [
+        Column::Client,
+        Column::Available,
+        Column::Held,
+        Column::Total,
+        Column::Locked,
+    ];
+
+    /// Columns that are only written if asked for.
+    pub const OPTIONAL: [Column; 4] = [
+        Column::LockedByTx,
+        Column::HeldCheck,
+        Column::Status,
+        Column::PeakHeld,
+    ];
+
+    /// The name used in the header row.
+    pub fn name(self) -> &'static str {
+        match self {
+            Column::Client => "client",
+            Column::Available => "available",
+            Column::Held => "held",
+            Column::Total => "total",
+            Column::Locked => "locked",
+            Column::LockedByTx => "locked_by_tx",
+            Column::HeldCheck => "held_check",
+            Column::Status => "status",
+            Column::PeakHeld => "peak_held",
+        }
+    }
+}
+
+impl FromStr for Column {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Column::ALL
+            .iter()
+            .chain(Column::OPTIONAL.iter())
+            .copied()
+            .find(|column| column.name() == s)
+            .ok_or_else(|| format!("Unknown column: {:?}", s))
+    }
+}
+
+/// Format an amount given as a number of 1/10_000's, without trailing zeros
+/// in the fractional part (or the decimal point if there is none). Integer
+/// arithmetic keeps it exact for every `u64`, so it parses back to the same
+/// amount.
+pub fn format_amount(amount: u64) -> String {
+    format_total(u128::from(amount))
+}
+
+/// `format_amount` for a sum of amounts, which may not fit in a `u64`.
+pub fn format_total(amount: u128) -> String {
+    let scale = u128::from(SCALE);
+    let (whole, fraction) = (amount / scale, amount % scale);
+    if fraction == 0 {
+        return whole.to_string();
+    }
+    let fraction = format!("{:0width$}", fraction, width = DECIMALS);
+    format!("{}.{}", whole, fraction.trim_end_matches('0'))
+}
+
+/// Format an amount given as a number of 1/10_000's for people to read: the
+/// whole part is grouped in thousands with commas and all `DECIMALS` decimal
+/// places are shown, e.g. `1,234,567.8900`.
+pub fn format_amount_human(amount: u64) -> String {
+    let whole = (amount / SCALE).to_string();
+    let mut grouped = String::new();
+    for (i, digit) in whole.chars().enumerate() {
+        if i > 0 && (whole.len() - i).is_multiple_of(3) {
+            grouped.push(',');
+        }
+        grouped.push(digit);
+    }
+    format!("{}.{:0width$}", grouped, amount % SCALE, width = DECIMALS)
+}
+
+/// Parse a comma separated list of column names.
+pub fn parse_columns(s: &str) -> Result<Vec<Column>, String> {
+    s.split(',').map(|name| name.trim().parse()).collect()
+}
+
+/// The format the final account states are written in.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Format {
+    #[default]
+    Csv,
+    /// A stream of length-delimited protobuf messages, see `proto::Account`.
+    /// Only available with the `proto` feature.
+    Protobuf,
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "csv" => Ok(Format::Csv),
+            "protobuf" if cfg!(feature = "proto") => Ok(Format::Protobuf),
+            "protobuf" => Err("protobuf output requires the `proto` feature".to_string()),
+            other => Err(format!("Unknown format: {:?}", other)),
+        }
+    }
+}
+
+/// The order accounts are written in.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum SortBy {
+    /// By client id.
+    #[default]
+    Client,
+    /// In the order each client first appeared in the input.
+    FirstSeen,
+    /// Largest total first, ties by client id.
+    Total,
+    /// Largest held first, ties by client id.
+    Held,
+    /// In whatever order the accounts are stored, which isn't deterministic
+    /// and can change from run to run. Avoids sorting and collecting the
+    /// accounts.
+    Unsorted,
+}
+
+impl FromStr for SortBy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "client" => Ok(SortBy::Client),
+            "first-seen" => Ok(SortBy::FirstSeen),
+            "total" => Ok(SortBy::Total),
+            "held" => Ok(SortBy::Held),
+            other => Err(format!("Unknown sort order: {:?}", other)),
+        }
+    }
+}
+
+/// Options controlling how the final account states are written.
+#[derive(Clone, Debug)]
+pub struct OutputOptions {
+    pub format: Format,
+    /// Which columns to write and in what order.
+    pub columns: Vec<Column>,
+    /// Format amounts with `format_amount_human` rather than `format_amount`.
+    /// Such amounts contain commas, so the fields are quoted.
+    pub human: bool,
+    pub sort_by: SortBy,
+    /// The character between the whole and fractional parts of amounts.
+    pub decimal_separator: char,
+    /// The field delimiter of CSV output.
+    pub delimiter: u8,
+    /// Leave out accounts that are empty (see `AccountView::is_empty`).
+    pub hide_empty: bool,
+    /// Don't write a header row to CSV output.
+    pub no_header: bool,
+    /// Names for the header row instead of those of the columns, one per
+    /// column.
+    pub header_names: Option<Vec<String>>,
+}
+
+impl Default for OutputOptions {
+    fn default() -> Self {
+        OutputOptions {
+            format: Format::default(),
+            columns: Column::ALL.to_vec(),
+            human: false,
+            sort_by: SortBy::default(),
+            decimal_separator: '.',
+            delimiter: b',',
+            hide_empty: false,
+            no_header: false,
+            header_names: None,
+        }
+    }
+}
+
+impl OutputOptions {
+    /// Format an amount given as a number of 1/10_000's. With a `,` decimal
+    /// separator human readable amounts are grouped with `.` instead.
+    pub fn format_amount(&self, amount: u64) -> String {
+        let formatted = if self.human {
+            format_amount_human(amount)
+        } else {
+            format_amount(amount)
+        };
+        if self.decimal_separator == '.' {
+            return formatted;
+        }
+        formatted
+            .chars()
+            .map(|c| match c {
+                '.' => self.decimal_separator,
+                ',' if self.decimal_separator == ',' => '.',
+                c => c,
+            })
+            .collect()
+    }
+
+    /// A CSV writer using the configured delimiter.
+    pub fn csv_writer<W: Write>(&self, writer: W) -> Writer<W> {
+        WriterBuilder::new()
+            .delimiter(self.delimiter)
+            .from_writer(writer)
+    }
+}
+
+/// Write `accounts` as a CSV, formatted according to `options`.
+pub fn write_accounts_csv<W, I>(
+    writer: &mut Writer<W>,
+    accounts: I,
+    options: &OutputOptions,
+) -> csv::Result<()>
+where
+    W: Write,
+    I: IntoIterator<Item = AccountView>,
+{
+    match (&options.header_names, options.no_header) {
+        (_, true) => (),
+        (Some(names), false) => writer.write_record(names)?,
+        (None, false) => writer.write_record(options.columns.iter().map(|column| column.name()))?,
+    }
+    for account in accounts {
+        if options.hide_empty && account.is_empty() {
+            continue;
+        }
+        writer.write_record(
+            options
+                .columns
+                .iter()
+                .map(|column| cell(*column, &account, options)),
+        )?;
+    }
+    Ok(())
+}
+
+/// The value of `column` for `account`, formatted according to `options`.
+fn cell(column: Column, account: &AccountView, options: &OutputOptions) -> String {
+    let amount = |amount| options.format_amount(amount);
+    match column {
+        Column::Client => account.client.to_string(),
+        Column::Available => amount(account.available),
+        Column::Held => amount(account.held),
+        Column::Total => amount(account.total()),
+        Column::Locked => account.locked.to_string(),
+        Column::LockedByTx => account.locked_by.map_or(String::new(), |tx| tx.to_string()),
+        Column::HeldCheck => match account.held_check {
+            None => String::new(),
+            Some(held) if held == account.held => amount(held),
+            Some(held) => format!("mismatch:{}", amount(held)),
+        },
+        Column::Status => account.status().to_string(),
+        Column::PeakHeld => amount(account.peak_held),
+    }
+}
+
+/// An account as a JSON object, see `write_accounts_json`.
+struct JsonAccount<'a> {
+    account: &'a AccountView,
+    options: &'a OutputOptions,
+}
+
+impl Serialize for JsonAccount<'_> {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let columns = &self.options.columns;
+        let mut map = serializer.serialize_map(Some(columns.len()))?;
+        for &column in columns {
+            match column {
+                Column::Client => map.serialize_entry(column.name(), &self.account.client)?,
+                Column::Locked => map.serialize_entry(column.name(), &self.account.locked)?,
+                column => {
+                    map.serialize_entry(column.name(), &cell(column, self.account, self.options))?
+                }
+            }
+        }
+        map.end()
+    }
+}
+
+/// Write `accounts` as JSON lines, an object per account with a field for
+/// each of `options.columns` in order (named as in the CSV header, ignoring
+/// `OutputOptions::header_names`). The client is a number and locked is a
+/// boolean; the other fields are formatted as in the CSV output.
+pub fn write_accounts_json<W, I>(
+    mut writer: W,
+    accounts: I,
+    options: &OutputOptions,
+) -> io::Result<()>
+where
+    W: Write,
+    I: IntoIterator<Item = AccountView>,
+{
+    for account in accounts {
+        if options.hide_empty && account.is_empty() {
+            continue;
+        }
+        let account = JsonAccount {
+            account: &account,
+            options,
+        };
+        serde_json::to_writer(&mut writer, &account)?;
+        writeln!(writer)?;
+    }
+    writer.flush()
+}
+
+/// Replace the client of each of `accounts` with its position (from 1), so
+/// that results can be shared without the real client ids. The same accounts
+/// in the same order always get the same ids. Returns the real client for
+/// each new id.
+pub fn anonymize(accounts: &mut [AccountView]) -> Vec<(Client, Client)> {
+    accounts
+        .iter_mut()
+        .zip(1..)
+        .map(|(account, id)| {
+            let anonymized = Client::new(id);
+            (anonymized, mem::replace(&mut account.client, anonymized))
+        })
+        .collect()
+}
+
+/// Write the mapping returned by `anonymize` as a CSV with the columns
+/// `anonymized` and `client`.
+pub fn write_client_mapping<W: Write>(writer: W, mapping: &[(Client, Client)]) -> csv::Result<()> {
+    let mut writer = Writer::from_writer(writer);
+    writer.write_record(["anonymized", "client"])?;
+    for (anonymized, client) in mapping {
+        writer.write_record(&[anonymized.to_string(), client.to_string()])?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+/// Totals over all accounts, written as a single JSON line by
+/// `--json-summary`.
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct Summary {
+    pub accounts: usize,
+    pub locked: usize,
+    /// The number of transactions rejected while applying them.
+    pub rejected: usize,
+    /// Amounts are written as strings (formatted as in the CSV output) so
+    /// that JSON parsers don't round them. The totals can exceed a `u64`.
+    #[serde(serialize_with = "serialize_total")]
+    pub total_available: u128,
+    #[serde(serialize_with = "serialize_total")]
+    pub total_held: u128,
+}
+
+fn serialize_total<S: serde::Serializer>(amount: &u128, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&format_total(*amount))
+}
+
+impl Summary {
+    pub fn new<I>(accounts: I, rejected: usize) -> Self
+    where
+        I: IntoIterator<Item = AccountView>,
+    {
+        accounts.into_iter().fold(
+            Summary {
+                rejected,
+                ..Summary::default()
+            },
+            |mut summary, account| {
+                summary.accounts += 1;
+                summary.locked += usize::from(account.locked);
+                summary.total_available += u128::from(account.available);
+                summary.total_held += u128::from(account.held);
+                summary
+            },
+        )
+    }
+
+    /// The summary as compact JSON, without a trailing newline.
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).expect("a summary always serializes")
+    }
+}
+
+/// Separate files for active and locked accounts. Parsed from
+/// `active=<path>,locked=<path>`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SplitOutput {
+    pub active: PathBuf,
+    pub locked: PathBuf,
+}
+
+impl FromStr for SplitOutput {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (mut active, mut locked) = (None, None);
+        for part in s.split(',') {
+            let (bucket, path) = part
+                .split_once('=')
+                .ok_or_else(|| format!("expected <bucket>=<path>, got {:?}", part))?;
+            let slot = match bucket.trim() {
+                "active" => &mut active,
+                "locked" => &mut locked,
+                other => return Err(format!("unknown bucket {:?}", other)),
+            };
+            if slot.replace(PathBuf::from(path.trim())).is_some() {
+                return Err(format!("bucket {:?} given more than once", bucket.trim()));
+            }
+        }
+        match (active, locked) {
+            (Some(active), Some(locked)) => Ok(SplitOutput { active, locked }),
+            _ => Err("both active=<path> and locked=<path> are required".to_string()),
+        }
+    }
+}
+
+impl SplitOutput {
+    /// Write the active and locked `accounts` to their files.
+    pub fn write(&self, accounts: Vec<AccountView>, options: &OutputOptions) -> csv::Result<()> {
+        let (locked, active): (Vec<_>, Vec<_>) =
+            accounts.into_iter().partition(|account| account.locked);
+        for (path, accounts) in [(&self.active, active), (&self.locked, locked)] {
+            let mut writer = options.csv_writer(File::create(path)?);
+            write_accounts_csv(&mut writer, accounts, options)?;
+            writer.flush()?;
+        }
+        Ok(())
+    }
+}
+
+/// The prefix of the trailing row written by `ChecksumWriter::finish`.
+const CHECKSUM_PREFIX: &str = "#crc32=";
+
+/// Wraps a writer, computing the CRC32 of everything written through it so
+/// that a checksum row can be appended at the end.
+pub struct ChecksumWriter<W: Write> {
+    inner: W,
+    hasher: crc32fast::Hasher,
+}
+
+impl<W: Write> ChecksumWriter<W> {
+    pub fn new(inner: W) -> Self {
+        ChecksumWriter {
+            inner,
+            hasher: crc32fast::Hasher::new(),
+        }
+    }
+
+    /// Write the row `#crc32=<checksum>` with the checksum (as 8 hex digits)
+    /// of everything written before it.
+    pub fn finish(mut self) -> io::Result<W> {
+        let checksum = self.hasher.finalize();
+        writeln!(self.inner, "{}{:08x}", CHECKSUM_PREFIX, checksum)?;
+        self.inner.flush()?;
+        Ok(self.inner)
+    }
+}
+
+impl<W: Write> Write for ChecksumWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.hasher.update(&buf[..written]);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Check the checksum row written by `ChecksumWriter::finish` at the end of
+/// `reader` against the data before it.
+pub fn verify_checksum<R: Read>(mut reader: R) -> Result<(), String> {
+    let mut contents = Vec::new();
+    reader
+        .read_to_end(&mut contents)
+        .map_err(|e| e.to_string())?;
+    let body = contents.strip_suffix(b"\n").unwrap_or(&contents);
+    let (data, last) = match body.iter().rposition(|&b| b == b'\n') {
+        Some(newline) => body.split_at(newline + 1),
+        None => (&[][..], body),
+    };
+    let expected = std::str::from_utf8(last)
+        .ok()
+        .and_then(|row| row.strip_prefix(CHECKSUM_PREFIX))
+        .ok_or("Missing checksum row")?;
+    let actual = format!("{:08x}", crc32fast::hash(data));
+    if expected == actual {
+        Ok(())
+    } else {
+        Err(format!(
+            "Checksum mismatch: expected {} but the data has {}",
+            expected, actual
+        ))
+    }
+}
+
+/// Writes a row with the new balances of the affected account after each
+/// accepted transaction, so that consumers can follow the changes
+/// incrementally rather than only seeing the final state.
+pub struct Changelog<W: Write> {
+    writer: Writer<W>,
+    options: OutputOptions,
+}
+
+impl<W: Write> Changelog<W> {
+    /// Create a changelog, writing the header row. The delimiter and amounts
+    /// are as in the account output with `options`.
+    pub fn new(writer: W, options: &OutputOptions) -> csv::Result<Self> {
+        let mut writer = options.csv_writer(writer);
+        writer.write_record(["client", "tx", "available", "held", "locked"])?;
+        Ok(Changelog {
+            writer,
+            options: options.clone(),
+        })
+    }
+
+    /// Record the state of `account` after `tx` was applied to it.
+    pub fn record(&mut self, tx: Tx, account: &AccountView) -> csv::Result<()> {
+        self.writer.write_record(&[
+            account.client.to_string(),
+            tx.to_string(),
+            self.options.format_amount(account.available),
+            self.options.format_amount(account.held),
+            account.locked.to_string(),
+        ])
+    }
+
+    pub fn flush(&mut self) -> std::io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+/// Writes a row with the new balances of the affected account after each
+/// accepted dispute, resolve, chargeback or reverse-chargeback, so that the
+/// movement of held funds can be followed without the rest of the changelog.
+pub struct DisputeBalanceLog<W: Write> {
+    writer: Writer<W>,
+    options: OutputOptions,
+}
+
+impl<W: Write> DisputeBalanceLog<W> {
+    /// Create a dispute balance log, writing the header row. The delimiter
+    /// and amounts are as in the account output with `options`.
+    pub fn new(writer: W, options: &OutputOptions) -> csv::Result<Self> {
+        let mut writer = options.csv_writer(writer);
+        writer.write_record(["client", "tx", "event", "available", "held"])?;
+        Ok(DisputeBalanceLog {
+            writer,
+            options: options.clone(),
+        })
+    }
+
+    /// Record the state of `account` after `detail` was applied to it for
+    /// `tx`. Other kinds of transaction are ignored.
+    pub fn record(&mut self, tx: Tx, detail: Action, account: &AccountView) -> csv::Result<()> {
+        match detail {
+            Action::Dispute | Action::Resolve | Action::ChargeBack | Action::ReverseChargeback => {
+                self.writer.write_record(&[
+                    account.client.to_string(),
+                    tx.to_string(),
+                    detail.type_name().to_string(),
+                    self.options.format_amount(account.available),
+                    self.options.format_amount(account.held),
+                ])
+            }
+            _ => Ok(()),
+        }
+    }
+
+    pub fn flush(&mut self) -> std::io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+/// The change from `before` to `after`, with a sign if it's negative.
+fn format_change(options: &OutputOptions, before: u64, after: u64) -> String {
+    if after < before {
+        format!("-{}", options.format_amount(before - after))
+    } else {
+        options.format_amount(after - before)
+    }
+}
+
+/// Collects, for each client, every accepted transaction with its effect on
+/// the balances, so that the final balances can be reconciled with the
+/// transactions that make them up. The rows are kept in memory and written
+/// by `Audit::write`, a file per client.
+pub struct Audit {
+    dir: PathBuf,
+    options: OutputOptions,
+    trails: HashMap<Client, Vec<StringRecord>>,
+}
+
+impl Audit {
+    const HEADER: [&'static str; 7] = [
+        "tx",
+        "type",
+        "amount",
+        "available_change",
+        "held_change",
+        "available",
+        "held",
+    ];
+
+    /// The delimiter and amounts are as in the account output with
+    /// `options`.
+    pub fn new(dir: PathBuf, options: &OutputOptions) -> Self {
+        Audit {
+            dir,
+            options: options.clone(),
+            trails: HashMap::new(),
+        }
+    }
+
+    /// Record that `transaction` changed the account from `before` (`None`
+    /// if it didn't exist) to `after`. The first row of a client whose
+    /// account already had funds, e.g. from a snapshot, is an `opening` row
+    /// with those balances.
+    pub fn record(
+        &mut self,
+        transaction: &Transaction,
+        before: Option<AccountView>,
+        after: &AccountView,
+    ) {
+        let options = &self.options;
+        let (available, held) = before.map_or((0, 0), |before| (before.available, before.held));
+        let trail = self.trails.entry(after.client).or_default();
+        if trail.is_empty() && (available, held) != (0, 0) {
+            trail.push(StringRecord::from(vec![
+                String::new(),
+                "opening".to_string(),
+                String::new(),
+                options.format_amount(available),
+                options.format_amount(held),
+                options.format_amount(available),
+                options.format_amount(held),
+            ]));
+        }
+        let amount = match transaction.detail {
+            Action::Deposit(amount) | Action::Withdrawal(amount) => options.format_amount(amount),
+            Action::Adjust(amount) if amount < 0 => {
+                format!("-{}", options.format_amount(amount.unsigned_abs()))
+            }
+            Action::Adjust(amount) => options.format_amount(amount.unsigned_abs()),
+            _ => String::new(),
+        };
+        trail.push(StringRecord::from(vec![
+            transaction.tx.to_string(),
+            transaction.detail.type_name().to_string(),
+            amount,
+            format_change(options, available, after.available),
+            format_change(options, held, after.held),
+            options.format_amount(after.available),
+            options.format_amount(after.held),
+        ]));
+    }
+
+    /// Write the rows of each client to `<client>.csv` in the directory,
+    /// creating it if needed.
+    pub fn write(&self) -> csv::Result<()> {
+        fs::create_dir_all(&self.dir)?;
+        for (client, trail) in &self.trails {
+            let file = File::create(self.dir.join(format!("{}.csv", client)))?;
+            let mut writer = self.options.csv_writer(file);
+            writer.write_record(Self::HEADER)?;
+            for row in trail {
+                writer.write_record(row)?;
+            }
+            writer.flush()?;
+        }
+        Ok(())
+    }
+}
+
+/// Writes each rejected row (whether it couldn't be parsed or was rejected by
+/// the accounts) with its fields as they were read followed by the reason, so
+/// that it can be fixed and processed again. There is no header row, as rows
+/// that couldn't be parsed may not have the expected fields.
+pub struct DeadLetter<W: Write> {
+    writer: Writer<W>,
+    held: Option<StringRecord>,
+}
+
+impl<W: Write> DeadLetter<W> {
+    /// Write rows separated by `delimiter`, which should be that of the
+    /// input so that the rows can be read back with the same options.
+    pub fn new(writer: W, delimiter: u8) -> Self {
+        let writer = WriterBuilder::new()
+            .has_headers(false)
+            .flexible(true)
+            .delimiter(delimiter)
+            .from_writer(writer);
+        DeadLetter { writer, held: None }
+    }
+
+    /// Keep the fields of the row being processed, in case the transaction
+    /// parsed from it is rejected.
+    pub fn hold(&mut self, fields: StringRecord) {
+        self.held = Some(fields);
+    }
+
+    /// Record that the row kept by `hold` was rejected.
+    pub fn reject_held(&mut self, reason: &str) -> csv::Result<()> {
+        match self.held.take() {
+            Some(fields) => self.reject(&fields, reason),
+            None => Ok(()),
+        }
+    }
+
+    /// Record that the row with `fields` was rejected.
+    pub fn reject(&mut self, fields: &StringRecord, reason: &str) -> csv::Result<()> {
+        self.writer.write_record(fields.iter().chain([reason]))
+    }
+
+    pub fn flush(&mut self) -> std::io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+/// Writes a row for each transaction rejected by the accounts, with the
+/// stable error code (see `TransactionError::code`) and a readable message.
+pub struct ErrorLog<W: Write> {
+    writer: Writer<W>,
+}
+
+impl<W: Write> ErrorLog<W> {
+    /// Create an error log, writing the header row. Fields are separated by
+    /// the delimiter of `options`.
+    pub fn new(writer: W, options: &OutputOptions) -> csv::Result<Self> {
+        let mut writer = options.csv_writer(writer);
+        writer.write_record(["client", "tx", "code", "message"])?;
+        Ok(ErrorLog { writer })
+    }
+
+    /// Record that the transaction `tx` for `client` was rejected.
+    pub fn record(&mut self, client: Client, tx: Tx, error: &TransactionError) -> csv::Result<()> {
+        self.writer.write_record(&[
+            client.to_string(),
+            tx.to_string(),
+            error.code().to_string(),
+            error.to_string(),
+        ])
+    }
+
+    pub fn flush(&mut self) -> std::io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+/// A line of the trace, see `Trace`.
+#[derive(Debug, Serialize)]
+struct TraceEvent {
+    client: Client,
+    tx: Tx,
+    #[serde(rename = "type")]
+    type_: &'static str,
+    amount: Option<String>,
+    /// `"applied"` or `"rejected"`.
+    decision: &'static str,
+    /// The error code and message if the transaction was rejected.
+    code: Option<&'static str>,
+    reason: Option<String>,
+    /// The balances of the account afterwards, if it exists.
+    available: Option<String>,
+    held: Option<String>,
+    locked: Option<bool>,
+}
+
+/// Writes a JSON object per line for every transaction handled by the
+/// engine, whether it was applied or rejected, for debugging.
+pub struct Trace<W: Write> {
+    writer: W,
+}
+
+impl<W: Write> Trace<W> {
+    pub fn new(writer: W) -> Self {
+        Trace { writer }
+    }
+
+    /// Record the `result` of handling `transaction` and the account
+    /// afterwards.
+    pub fn record(
+        &mut self,
+        transaction: &Transaction,
+        result: &Result<(), TransactionError>,
+        account: Option<AccountView>,
+    ) -> io::Result<()> {
+        let amount = match transaction.detail {
+            Action::Deposit(amount) | Action::Withdrawal(amount) => Some(format_amount(amount)),
+            Action::Adjust(amount) if amount < 0 => {
+                Some(format!("-{}", format_amount(amount.unsigned_abs())))
+            }
+            Action::Adjust(amount) => Some(format_amount(amount.unsigned_abs())),
+            Action::Dispute
+            | Action::Resolve
+            | Action::ChargeBack
+            | Action::ReverseChargeback
+            | Action::Freeze
+            | Action::Unfreeze => None,
+        };
+        let event = TraceEvent {
+            client: transaction.client,
+            tx: transaction.tx,
+            type_: transaction.detail.type_name(),
+            amount,
+            decision: if result.is_ok() {
+                "applied"
+            } else {
+                "rejected"
+            },
+            code: result.as_ref().err().map(TransactionError::code),
+            reason: result.as_ref().err().map(ToString::to_string),
+            available: account.map(|account| format_amount(account.available)),
+            held: account.map(|account| format_amount(account.held)),
+            locked: account.map(|account| account.locked),
+        };
+        serde_json::to_writer(&mut self.writer, &event)?;
+        writeln!(self.writer)
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::State;
+    use crate::transaction::TransactionRow;
+    use csv::{ReaderBuilder, Trim};
+    use std::convert::TryFrom;
+
+    /// Apply the transactions in `data` (CSV without a header row) to a new
+    /// `State`, calling `observe` with each transaction, its result and the
+    /// state afterwards.
+    fn run(
+        data: &str,
+        mut observe: impl FnMut(&Transaction, &Result<(), TransactionError>, &State),
+    ) -> State {
+        let mut rdr = ReaderBuilder::new()
+            .trim(Trim::All)
+            .has_headers(false)
+            .from_reader(data.as_bytes());
+        let mut state = State::new();
+        for row in rdr.deserialize::<TransactionRow>() {
+            let transaction = Transaction::try_from(row.unwrap()).unwrap();
+            let result = state.handle_transaction(transaction.clone());
+            observe(&transaction, &result, &state);
+        }
+        state
+    }
+
+    fn account(state: &State, transaction: &Transaction) -> AccountView {
+        state.account(transaction.client).unwrap()
+    }
+
+    #[test]
+    fn dispute_balance_log_has_row_per_dispute_event() {
+        let data = r#"deposit, 1, 1, 5.0
+            deposit, 1, 2, 2.0
+            dispute, 1, 1,
+            withdrawal, 1, 3, 1.0
+            resolve, 1, 1,"#;
+        let mut vec = Vec::new();
+        let mut log = DisputeBalanceLog::new(&mut vec, &OutputOptions::default()).unwrap();
+        run(data, |transaction, result, state| {
+            assert_eq!(result, &Ok(()));
+            let account = account(state, transaction);
+            log.record(transaction.tx, transaction.detail, &account)
+                .unwrap();
+        });
+        log.flush().unwrap();
+        drop(log);
+        assert_eq!(
+            String::from_utf8(vec).unwrap(),
+            r#"client,tx,event,available,held
+1,1,dispute,2,5
+1,1,resolve,6,0
+"#
+        );
+    }
+
+    #[test]
+    fn changelog_has_row_per_accepted_transaction() {
+        let data = r#"deposit, 1, 1, 5.0
+            deposit, 2, 2, 2.0
+            withdrawal, 1, 3, 10.0
+            dispute, 1, 1,
+            resolve, 1, 1,
+            withdrawal, 1, 4, 1.5"#;
+        let mut vec = Vec::new();
+        let mut changelog = Changelog::new(&mut vec, &OutputOptions::default()).unwrap();
+        run(data, |transaction, result, state| {
+            if result.is_ok() {
+                let account = account(state, transaction);
+                changelog.record(transaction.tx, &account).unwrap();
+            }
+        });
+        changelog.flush().unwrap();
+        drop(changelog);
+        assert_eq!(
+            String::from_utf8(vec).unwrap(),
+            r#"client,tx,available,held,locked
+1,1,5,0,false
+2,2,2,0,false
+1,1,0,5,false
+1,1,5,0,false
+1,4,3.5,0,false
+"#
+        );
+    }
+
+    #[test]
+    fn logs_use_output_dialect() {
+        let options = OutputOptions {
+            delimiter: b';',
+            decimal_separator: ',',
+            ..OutputOptions::default()
+        };
+        let (mut changes, mut disputes, mut rejections) = (Vec::new(), Vec::new(), Vec::new());
+        let mut changelog = Changelog::new(&mut changes, &options).unwrap();
+        let mut dispute_log = DisputeBalanceLog::new(&mut disputes, &options).unwrap();
+        let mut errors = ErrorLog::new(&mut rejections, &options).unwrap();
+        run(
+            "deposit, 1, 1, 2.5\ndispute, 1, 1,\nwithdrawal, 1, 2, 1",
+            |transaction, result, state| match result {
+                Ok(()) => {
+                    let account = account(state, transaction);
+                    changelog.record(transaction.tx, &account).unwrap();
+                    dispute_log
+                        .record(transaction.tx, transaction.detail, &account)
+                        .unwrap();
+                }
+                Err(err) => errors
+                    .record(transaction.client, transaction.tx, err)
+                    .unwrap(),
+            },
+        );
+        changelog.flush().unwrap();
+        dispute_log.flush().unwrap();
+        errors.flush().unwrap();
+        drop((changelog, dispute_log, errors));
+        assert_eq!(
+            String::from_utf8(changes).unwrap(),
+            "client;tx;available;held;locked\n1;1;2,5;0;false\n1;1;0;2,5;false\n"
+        );
+        assert_eq!(
+            String::from_utf8(disputes).unwrap(),
+            "client;tx;event;available;held\n1;1;dispute;0;2,5\n"
+        );
+        assert!(String::from_utf8(rejections)
+            .unwrap()
+            .starts_with("client;tx;code;message\n1;2;E_INSUFFICIENT_FUNDS;"));
+    }
+
+    #[test]
+    fn error_log_has_code_column() {
+        let data = r#"deposit, 1, 1, 5.0
+            withdrawal, 1, 2, 10.0
+            dispute, 1, 7,"#;
+        let mut vec = Vec::new();
+        let mut errors = ErrorLog::new(&mut vec, &OutputOptions::default()).unwrap();
+        run(data, |transaction, result, _| {
+            if let Err(err) = result {
+                errors
+                    .record(transaction.client, transaction.tx, err)
+                    .unwrap();
+            }
+        });
+        errors.flush().unwrap();
+        drop(errors);
+        let log = String::from_utf8(vec).unwrap();
+        let codes: Vec<_> = log
+            .lines()
+            .map(|line| line.split(',').take(3).collect::<Vec<_>>().join(","))
+            .collect();
+        assert_eq!(
+            codes,
+            vec![
+                "client,tx,code",
+                "1,2,E_INSUFFICIENT_FUNDS",
+                "1,7,E_UNKNOWN_TX"
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_split_output() {
+        assert_eq!(
+            "locked=l.csv,active=a.csv".parse(),
+            Ok(SplitOutput {
+                active: "a.csv".into(),
+                locked: "l.csv".into()
+            })
+        );
+        assert!("active=a.csv".parse::<SplitOutput>().is_err());
+        assert!("active=a.csv,locked=l.csv,active=b.csv"
+            .parse::<SplitOutput>()
+            .is_err());
+        assert!("active=a.csv,frozen=l.csv".parse::<SplitOutput>().is_err());
+    }
+
+    #[test]
+    fn human_amounts() {
+        assert_eq!(format_amount_human(0), "0.0000");
+        assert_eq!(format_amount_human(5), "0.0005");
+        assert_eq!(format_amount_human(99_990_000), "9,999.0000");
+        assert_eq!(format_amount_human(1_234_567_000), "123,456.7000");
+        assert_eq!(format_amount_human(12_345_678_900), "1,234,567.8900");
+    }
+
+    fn write_with_checksum() -> Vec<u8> {
+        let state = run("deposit, 1, 1, 1.5", |_, _, _| ());
+        let mut writer = Writer::from_writer(ChecksumWriter::new(Vec::new()));
+        state
+            .write_csv(&mut writer, &OutputOptions::default())
+            .unwrap();
+        writer.into_inner().ok().unwrap().finish().unwrap()
+    }
+
+    #[test]
+    fn checksum_round_trip() {
+        let output = write_with_checksum();
+        let text = String::from_utf8(output.clone()).unwrap();
+        assert!(text.starts_with("client,available,held,total,locked\n1,1.5,0,1.5,false\n#crc32="));
+        assert_eq!(verify_checksum(&output[..]), Ok(()));
+    }
+
+    #[test]
+    fn tampered_checksum_fails() {
+        let output = String::from_utf8(write_with_checksum()).unwrap();
+        let tampered = output.replace("1.5", "2.5");
+        assert!(verify_checksum(tampered.as_bytes())
+            .unwrap_err()
+            .starts_with("Checksum mismatch"));
+        let without_row = output.lines().take(2).collect::<Vec<_>>().join("\n");
+        assert_eq!(
+            verify_checksum(without_row.as_bytes()),
+            Err("Missing checksum row".to_string())
+        );
+    }
+
+    #[test]
+    fn comma_decimal_separator() {
+        let options = OutputOptions {
+            decimal_separator: ',',
+            ..OutputOptions::default()
+        };
+        assert_eq!(options.format_amount(55_000), "5,5");
+        assert_eq!(options.format_amount(50_000), "5");
+        let human = OutputOptions {
+            human: true,
+            ..options
+        };
+        assert_eq!(human.format_amount(12_345_678_900), "1.234.567,8900");
+    }
+
+    #[test]
+    fn human_output_is_quoted() {
+        let state = run(
+            "deposit, 1, 1, 1234000\ndeposit, 1, 2, 567.89",
+            |_, _, _| (),
+        );
+        let options = OutputOptions {
+            human: true,
+            ..OutputOptions::default()
+        };
+        let mut vec = Vec::new();
+        state
+            .write_csv(&mut Writer::from_writer(&mut vec), &options)
+            .unwrap();
+        assert_eq!(
+            String::from_utf8(vec).unwrap(),
+            r#"client,available,held,total,locked
+1,"1,234,567.8900",0.0000,"1,234,567.8900",false
+"#
+        );
+    }
+
+    #[test]
+    fn json_lines() {
+        let state = run(
+            "deposit, 7, 1, 1.5\ndeposit, 7, 2, 2\ndispute, 7, 2,\nchargeback, 7, 2,",
+            |_, _, _| (),
+        );
+        let options = OutputOptions {
+            columns: vec![
+                Column::Client,
+                Column::Total,
+                Column::Locked,
+                Column::PeakHeld,
+            ],
+            ..OutputOptions::default()
+        };
+        let mut vec = Vec::new();
+        write_accounts_json(&mut vec, state.iter_accounts(), &options).unwrap();
+        assert_eq!(
+            String::from_utf8(vec).unwrap(),
+            "{\"client\":7,\"total\":\"1.5\",\"locked\":true,\"peak_held\":\"2\"}\n"
+        );
+    }
+
+    #[test]
+    fn anonymized_clients() {
+        let state = run(
+            "deposit, 42, 1, 0.0042\ndeposit, 7, 2, 0.0007\ndeposit, 19, 3, 0.0019",
+            |_, _, _| (),
+        );
+        let mut accounts: Vec<_> = state.iter_accounts().collect();
+        let mapping = anonymize(&mut accounts);
+        assert_eq!(
+            accounts
+                .iter()
+                .map(|account| (account.client.id(), account.available))
+                .collect::<Vec<_>>(),
+            vec![(1, 7), (2, 19), (3, 42)]
+        );
+        let mut vec = Vec::new();
+        write_client_mapping(&mut vec, &mapping).unwrap();
+        assert_eq!(
+            String::from_utf8(vec).unwrap(),
+            "anonymized,client\n1,7\n2,19\n3,42\n"
+        );
+    }
+
+    #[test]
+    fn held_check_column() {
+        let state = run("deposit, 1, 1, 1\ndispute, 1, 1,", |_, _, _| ());
+        let account = state.account(Client::new(1)).unwrap();
+        let options = OutputOptions {
+            columns: vec![Column::Client, Column::HeldCheck],
+            ..OutputOptions::default()
+        };
+        let locked = run(
+            "deposit, 1, 1, 1\ndispute, 1, 1,\nchargeback, 1, 1,",
+            |_, _, _| (),
+        )
+        .account(Client::new(1))
+        .unwrap();
+        // The check only differs from held if the account is corrupt.
+        let corrupt = AccountView {
+            held_check: Some(20_000),
+            ..account
+        };
+        let mut vec = Vec::new();
+        write_accounts_csv(
+            &mut Writer::from_writer(&mut vec),
+            vec![account, locked, corrupt],
+            &options,
+        )
+        .unwrap();
+        assert_eq!(
+            String::from_utf8(vec).unwrap(),
+            "client,held_check\n1,1\n1,\n1,mismatch:2\n"
+        );
+    }
+
+    #[test]
+    fn summary_totals_past_u64() {
+        let account = run("deposit, 1, 1, 1", |_, _, _| ())
+            .account(Client::new(1))
+            .unwrap();
+        let full = AccountView {
+            available: u64::MAX,
+            held: u64::MAX,
+            ..account
+        };
+        let summary = Summary::new(vec![full, full], 0);
+        assert_eq!(summary.total_available, 2 * u128::from(u64::MAX));
+        assert!(summary
+            .to_json()
+            .contains(r#""total_held":"3689348814741910.323""#));
+    }
+
+    #[test]
+    fn status_column() {
+        let data = r#"deposit, 1, 1, 5.0
+            deposit, 2, 2, 5.0
+            dispute, 2, 2,
+            deposit, 3, 3, 5.0
+            dispute, 3, 3,
+            chargeback, 3, 3,
+            deposit, 4, 4, 5.0
+            withdrawal, 4, 5, 5.0"#;
+        let state = run(data, |_, result, _| assert_eq!(result, &Ok(())));
+        let options = OutputOptions {
+            columns: vec![Column::Client, Column::Status],
+            ..OutputOptions::default()
+        };
+        let mut vec = Vec::new();
+        state
+            .write_csv(&mut Writer::from_writer(&mut vec), &options)
+            .unwrap();
+        assert_eq!(
+            String::from_utf8(vec).unwrap(),
+            "client,status\n1,active\n2,fully-held\n3,frozen\n4,empty\n"
+        );
+    }
+
+    #[test]
+    fn parse_column_list() {
+        assert_eq!(
+            parse_columns("client,locked,available"),
+            Ok(vec![Column::Client, Column::Locked, Column::Available])
+        );
+        assert!(parse_columns("client,balance").is_err());
+    }
+}
@@ -0,0 +1,252 @@
+use crate::state::{State, StateBuilder};
+use crate::transaction::{Client, Transaction};
+use std::{collections::HashMap, str::FromStr, sync::mpsc, thread};
+
+/// How clients are assigned to workers. All transactions for a client are
+/// always handled by the same worker, so each worker can own the accounts of
+/// its clients outright.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Shard {
+    /// `client % workers`. Cheap, but clusters of client ids (e.g. all
+    /// multiples of 4) can land on the same worker.
+    #[default]
+    Modulo,
+    /// Mix the bits of the client id before taking the modulus so that
+    /// clustered ids are spread evenly.
+    Hash,
+}
+
+impl FromStr for Shard {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "modulo" => Ok(Shard::Modulo),
+            "hash" => Ok(Shard::Hash),
+            other => Err(format!("Unknown shard function: {:?}", other)),
+        }
+    }
+}
+
+impl Shard {
+    /// The worker responsible for `client`.
+    pub fn worker(self, client: Client, workers: usize) -> usize {
+        let id = u64::from(client.id());
+        let key = match self {
+            Shard::Modulo => id,
+            Shard::Hash => {
+                // The splitmix64 finalizer.
+                let mut z = id.wrapping_add(0x9e37_79b9_7f4a_7c15);
+                z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+                z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+                z ^ (z >> 31)
+            }
+        };
+        (key % workers as u64) as usize
+    }
+}
+
+/// What a worker is sent.
+enum Message {
+    Transaction(Transaction),
+    /// Reply once all the transactions sent before have been applied.
+    Flush(mpsc::Sender<()>),
+}
+
+/// Apply `transactions` using `workers` threads, each owning the accounts of
+/// the clients `shard` assigns to it. The resulting `State` is the same as if
+/// the transactions had been applied in order on a single thread because the
+/// relative order of transactions for each client is preserved.
+///
+/// The workers share one set of idempotency keys. When a key comes up again
+/// for a different worker, the worker that last saw it is flushed first, so
+/// whether the key was accepted is settled just as in a sequential run.
+pub fn process_sharded<I>(
+    transactions: I,
+    workers: usize,
+    shard: Shard,
+    builder: &StateBuilder,
+) -> State
+where
+    I: IntoIterator<Item = Transaction>,
+{
+    let builder = builder.clone().idempotency_keys(Default::default());
+    let (senders, handles): (Vec<_>, Vec<_>) = (0..workers)
+        .map(|_| {
+            let (sender, receiver) = mpsc::channel::<Message>();
+            let builder = builder.clone();
+            let handle = thread::spawn(move || {
+                let mut state = builder.build();
+                for message in receiver {
+                    match message {
+                        Message::Transaction(transaction) => {
+                            let _possible_client_error = state.handle_transaction(transaction);
+                        }
+                        Message::Flush(done) => done.send(()).unwrap(),
+                    }
+                }
+                state
+            });
+            (sender, handle)
+        })
+        .unzip();
+    // The worker each idempotency key was last sent to.
+    let mut key_workers = HashMap::new();
+    for transaction in transactions {
+        let worker = shard.worker(transaction.client, workers);
+        if let Some(key) = &transaction.idempotency_key {
+            match key_workers.insert(key.clone(), worker) {
+                Some(previous) if previous != worker => {
+                    let (done, flushed) = mpsc::channel();
+                    senders[previous].send(Message::Flush(done)).unwrap();
+                    flushed.recv().unwrap();
+                }
+                _ => {}
+            }
+        }
+        senders[worker]
+            .send(Message::Transaction(transaction))
+            .unwrap();
+    }
+    drop(senders);
+
+    let mut state = builder.build();
+    for handle in handles {
+        state.merge(handle.join().unwrap());
+    }
+    state
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::output::{OutputOptions, SortBy};
+    use crate::transaction::{Action, TransactionRow, Tx};
+    use csv::{ReaderBuilder, Trim, Writer};
+    use std::convert::TryFrom;
+
+    fn read_transactions(s: &str) -> Vec<Transaction> {
+        let mut rdr = ReaderBuilder::new()
+            .trim(Trim::All)
+            .has_headers(false)
+            .from_reader(s.as_bytes());
+        rdr.deserialize()
+            .map(|row| {
+                let row: TransactionRow = row.unwrap();
+                Transaction::try_from(row).unwrap()
+            })
+            .collect()
+    }
+
+    fn to_csv(state: &State) -> String {
+        let mut vec = Vec::new();
+        state
+            .write_csv(
+                &mut Writer::from_writer(&mut vec),
+                &OutputOptions::default(),
+            )
+            .unwrap();
+        String::from_utf8(vec).unwrap()
+    }
+
+    const DATA: &str = r#"deposit, 1, 1, 1.0
+        deposit, 2, 2, 2.0
+        deposit, 4, 3, 4.0
+        deposit, 8, 4, 8.0
+        deposit, 1, 5, 2.0
+        withdrawal, 1, 6, 1.5
+        withdrawal, 2, 7, 3.0
+        dispute, 4, 3,
+        dispute, 8, 4,
+        chargeback, 8, 4,
+        deposit, 8, 9, 1.0"#;
+
+    #[test]
+    fn shard_modes_match_sequential() {
+        let mut sequential = State::new();
+        for transaction in read_transactions(DATA) {
+            let _ = sequential.handle_transaction(transaction);
+        }
+        let expected = to_csv(&sequential);
+        for &shard in &[Shard::Modulo, Shard::Hash] {
+            let state = process_sharded(read_transactions(DATA), 4, shard, &StateBuilder::new());
+            assert_eq!(to_csv(&state), expected, "{:?}", shard);
+        }
+    }
+
+    #[test]
+    fn merged_state_keeps_accounting() {
+        let mut sequential = State::new();
+        for transaction in read_transactions(DATA) {
+            let _ = sequential.handle_transaction(transaction);
+        }
+        let mut state = process_sharded(
+            read_transactions(DATA),
+            4,
+            Shard::Modulo,
+            &StateBuilder::new(),
+        );
+        assert_eq!(state.estimated_memory(), sequential.estimated_memory());
+
+        // A new account is seen after all the merged ones, which each have
+        // their own place.
+        for transaction in read_transactions("deposit, 3, 10, 1.0") {
+            state.handle_transaction(transaction).unwrap();
+        }
+        let clients: Vec<u32> = state
+            .accounts_sorted_by(SortBy::FirstSeen)
+            .iter()
+            .map(|account| account.client.id())
+            .collect();
+        assert_eq!(clients.len(), 5);
+        assert_eq!(clients.last(), Some(&3));
+    }
+
+    #[test]
+    fn idempotency_keys_shared_across_workers() {
+        let keyed = |client, tx, detail, key: &str| Transaction {
+            client: Client::new(client),
+            tx: Tx::new(tx),
+            detail,
+            idempotency_key: Some(key.to_string()),
+        };
+        let transactions = || {
+            vec![
+                keyed(1, 1, Action::Deposit(1_0000), "a"),
+                // A retry of "a" for another client, on another worker.
+                keyed(2, 2, Action::Deposit(1_0000), "a"),
+                // Rejected, so "b" is still free on the next worker.
+                keyed(3, 3, Action::Withdrawal(1_0000), "b"),
+                keyed(4, 4, Action::Deposit(1_0000), "b"),
+                keyed(1, 5, Action::Deposit(1_0000), "b"),
+            ]
+        };
+        let mut sequential = State::new();
+        for transaction in transactions() {
+            let _ = sequential.handle_transaction(transaction);
+        }
+        let state = process_sharded(transactions(), 4, Shard::Modulo, &StateBuilder::new());
+        assert_eq!(to_csv(&state), to_csv(&sequential));
+        assert_eq!(
+            to_csv(&state),
+            "client,available,held,total,locked\n1,1,0,1,false\n3,0,0,0,false\n4,1,0,1,false\n"
+        );
+    }
+
+    #[test]
+    fn hash_spreads_clustered_clients() {
+        let workers = 4;
+        let load = |shard: Shard| {
+            let mut load = vec![0; workers];
+            for id in (0..4000).step_by(4) {
+                load[shard.worker(Client::new(id), workers)] += 1;
+            }
+            load
+        };
+        // Every id is a multiple of 4, so modulo puts them all on one worker.
+        assert_eq!(load(Shard::Modulo), vec![1000, 0, 0, 0]);
+        for count in load(Shard::Hash) {
+            assert!(count > 200 && count < 300, "{}", count);
+        }
+    }
+}
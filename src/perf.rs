@@ -0,0 +1,92 @@
+//! Lightweight timing of `State::handle_transaction` for `--perf`.
+use std::{
+    convert::TryFrom,
+    fmt,
+    time::{Duration, Instant},
+};
+
+/// Counts transactions and their processing times in power of two
+/// nanosecond buckets, so recording is cheap and percentiles are accurate to
+/// within a factor of two.
+#[derive(Debug)]
+pub struct Perf {
+    start: Instant,
+    rows: u64,
+    /// `buckets[i]` counts the latencies in `[2^(i-1), 2^i)` nanoseconds
+    /// (`buckets[0]` is for 0).
+    buckets: [u64; 64],
+}
+
+impl Default for Perf {
+    fn default() -> Self {
+        Perf::new()
+    }
+}
+
+impl Perf {
+    /// Start timing the run from now.
+    pub fn new() -> Self {
+        Perf {
+            start: Instant::now(),
+            rows: 0,
+            buckets: [0; 64],
+        }
+    }
+
+    /// Record the time taken to process one transaction.
+    pub fn record(&mut self, latency: Duration) {
+        let nanos = u64::try_from(latency.as_nanos()).unwrap_or(u64::MAX);
+        let bucket = (64 - nanos.leading_zeros() as usize).min(63);
+        self.buckets[bucket] += 1;
+        self.rows += 1;
+    }
+
+    /// The upper bound of the bucket containing the `percentile`th latency.
+    pub fn percentile(&self, percentile: f64) -> Duration {
+        let target = ((self.rows as f64) * percentile / 100.0).ceil().max(1.0) as u64;
+        let mut seen = 0;
+        for (bucket, count) in self.buckets.iter().enumerate() {
+            seen += count;
+            if seen >= target {
+                return Duration::from_nanos(1u64.checked_shl(bucket as u32).unwrap_or(u64::MAX));
+            }
+        }
+        Duration::ZERO
+    }
+}
+
+/// A single `perf:` line of `key=value` fields.
+impl fmt::Display for Perf {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let elapsed = self.start.elapsed();
+        write!(
+            f,
+            "perf: rows={} elapsed_ms={:.3} rows_per_sec={:.0} p50_ns={} p99_ns={}",
+            self.rows,
+            elapsed.as_secs_f64() * 1_000.0,
+            self.rows as f64 / elapsed.as_secs_f64(),
+            self.percentile(50.0).as_nanos(),
+            self.percentile(99.0).as_nanos()
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn percentiles() {
+        let mut perf = Perf::new();
+        for _ in 0..98 {
+            perf.record(Duration::from_nanos(100));
+        }
+        perf.record(Duration::from_micros(5));
+        perf.record(Duration::from_micros(50));
+        assert_eq!(perf.rows, 100);
+        assert_eq!(perf.percentile(50.0), Duration::from_nanos(128));
+        assert_eq!(perf.percentile(99.0), Duration::from_nanos(8_192));
+        assert_eq!(perf.percentile(100.0), Duration::from_nanos(65_536));
+        assert_eq!(Perf::new().percentile(50.0), Duration::ZERO);
+    }
+}
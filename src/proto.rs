@@ -0,0 +1,123 @@
+use crate::state::AccountView;
+use prost::Message;
+use std::{
+    convert::TryFrom,
+    io::{self, Write},
+};
+
+/// The protobuf message for the final state of an account. Amounts are the
+/// number of 1/10_000's.
+#[derive(Clone, PartialEq, Message)]
+pub struct Account {
+    #[prost(uint32, tag = "1")]
+    pub client: u32,
+    #[prost(int64, tag = "2")]
+    pub available: i64,
+    #[prost(int64, tag = "3")]
+    pub held: i64,
+    #[prost(int64, tag = "4")]
+    pub total: i64,
+    #[prost(bool, tag = "5")]
+    pub locked: bool,
+}
+
+impl TryFrom<&AccountView> for Account {
+    type Error = io::Error;
+
+    fn try_from(view: &AccountView) -> Result<Self, Self::Error> {
+        let amount = |amount: u64| {
+            i64::try_from(amount).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+        };
+        Ok(Account {
+            client: view.client.id(),
+            available: amount(view.available)?,
+            held: amount(view.held)?,
+            total: amount(view.total())?,
+            locked: view.locked,
+        })
+    }
+}
+
+/// Write each account as a length-delimited `Account` message.
+pub fn write_accounts<I, W>(accounts: I, writer: &mut W) -> io::Result<()>
+where
+    I: IntoIterator<Item = AccountView>,
+    W: Write,
+{
+    let mut buf = Vec::new();
+    for view in accounts {
+        buf.clear();
+        Account::try_from(&view)?
+            .encode_length_delimited(&mut buf)
+            .map_err(io::Error::other)?;
+        writer.write_all(&buf)?;
+    }
+    writer.flush()
+}
+
+/// Read a stream of length-delimited `Account` messages.
+pub fn read_accounts(mut buf: &[u8]) -> Result<Vec<Account>, prost::DecodeError> {
+    let mut accounts = Vec::new();
+    while !buf.is_empty() {
+        accounts.push(Account::decode_length_delimited(&mut buf)?);
+    }
+    Ok(accounts)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::State;
+    use crate::transaction::{Transaction, TransactionRow};
+    use csv::{ReaderBuilder, Trim};
+
+    #[test]
+    fn round_trip() {
+        let data = r#"deposit, 1, 1, 1.0
+            deposit, 2, 2, 2.0
+            deposit, 1, 3, 2.0
+            withdrawal, 1, 4, 1.5
+            dispute, 2, 2,
+            deposit, 3, 5, 4.0
+            dispute, 3, 5,
+            chargeback, 3, 5,"#;
+        let mut rdr = ReaderBuilder::new()
+            .trim(Trim::All)
+            .has_headers(false)
+            .from_reader(data.as_bytes());
+        let mut state = State::new();
+        for row in rdr.deserialize::<TransactionRow>() {
+            let _ = state.handle_transaction(Transaction::try_from(row.unwrap()).unwrap());
+        }
+        let mut buf = Vec::new();
+        write_accounts(state.accounts_snapshot(), &mut buf).unwrap();
+        let decoded = read_accounts(&buf).unwrap();
+        let expected: Vec<_> = state
+            .accounts_snapshot()
+            .iter()
+            .map(|view| Account::try_from(view).unwrap())
+            .collect();
+        assert_eq!(decoded, expected);
+        assert_eq!(
+            decoded[0],
+            Account {
+                client: 1,
+                available: 1_5000,
+                held: 0,
+                total: 1_5000,
+                locked: false
+            }
+        );
+        assert_eq!(
+            decoded[1],
+            Account {
+                client: 2,
+                available: 0,
+                held: 2_0000,
+                total: 2_0000,
+                locked: false
+            }
+        );
+        assert!(decoded[2].locked);
+    }
+}
@@ -0,0 +1,215 @@
+//! Compare the final accounts with balances from elsewhere, allowing for
+//! small differences such as rounding.
+use crate::output::format_amount;
+use crate::state::AccountView;
+use crate::transaction::{parse_scaled_amount, Client, ParseOptions};
+use csv::{ReaderBuilder, Trim};
+use std::{collections::BTreeMap, io::Read};
+
+/// The compared fields, in the order they're reported.
+const FIELDS: [&str; 3] = ["available", "held", "total"];
+
+/// Compare the `available`, `held` and `total` of `accounts` with the
+/// expected balances read as CSV from `expected`, which must have a header
+/// row with (at least) `client`, `available`, `held` and `total` columns.
+/// Fields are separated by `delimiter`, as in the input. Amounts are read
+/// with `options` and may differ by up to `tolerance` 1/10_000's.
+///
+/// Returns a message for each difference, including clients that are only
+/// in one of the two, ordered by client. An error means the expected
+/// balances couldn't be read.
+pub fn reconcile<R, I>(
+    expected: R,
+    accounts: I,
+    tolerance: u64,
+    delimiter: u8,
+    options: &ParseOptions,
+) -> Result<Vec<String>, String>
+where
+    R: Read,
+    I: IntoIterator<Item = AccountView>,
+{
+    let mut reader = ReaderBuilder::new()
+        .trim(Trim::All)
+        .delimiter(delimiter)
+        .from_reader(expected);
+    let headers = reader.headers().map_err(|e| e.to_string())?.clone();
+    let column = |name: &str| {
+        headers
+            .iter()
+            .position(|header| header == name)
+            .ok_or_else(|| format!("Expected balances have no {} column", name))
+    };
+    let client_column = column("client")?;
+    let columns = [column(FIELDS[0])?, column(FIELDS[1])?, column(FIELDS[2])?];
+
+    let mut expected = BTreeMap::new();
+    for (line, record) in reader.records().enumerate() {
+        let with_line = |e: String| format!("Line {}: {}", line + 2, e);
+        let record = record.map_err(|e| with_line(e.to_string()))?;
+        let field = |index: usize| {
+            record
+                .get(index)
+                .ok_or_else(|| with_line("missing field".to_string()))
+        };
+        let client: u32 = field(client_column)?
+            .parse()
+            .map_err(|e| with_line(format!("invalid client: {}", e)))?;
+        let mut amounts = [0; 3];
+        for (amount, &index) in amounts.iter_mut().zip(&columns) {
+            *amount = parse_scaled_amount(field(index)?, options).map_err(with_line)?;
+        }
+        if expected.insert(Client::new(client), amounts).is_some() {
+            return Err(with_line(format!(
+                "client {} appears more than once",
+                client
+            )));
+        }
+    }
+
+    let mut differences = BTreeMap::new();
+    for account in accounts {
+        let report = match expected.remove(&account.client) {
+            None => vec!["not in the expected balances".to_string()],
+            Some(amounts) => {
+                let actual = [account.available, account.held, account.total()];
+                FIELDS
+                    .iter()
+                    .zip(actual.iter().zip(&amounts))
+                    .filter(|(_, (actual, expected))| actual.abs_diff(**expected) > tolerance)
+                    .map(|(name, (actual, expected))| {
+                        format!(
+                            "{} is {} but expected {}",
+                            name,
+                            format_amount(*actual),
+                            format_amount(*expected)
+                        )
+                    })
+                    .collect()
+            }
+        };
+        differences.insert(account.client, report);
+    }
+    for client in expected.keys() {
+        differences.insert(*client, vec!["missing from the output".to_string()]);
+    }
+    Ok(differences
+        .into_iter()
+        .flat_map(|(client, reports)| {
+            reports
+                .into_iter()
+                .map(move |report| format!("Client {}: {}", client, report))
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::State;
+    use crate::transaction::{Action, Transaction, Tx};
+
+    fn state() -> State {
+        let mut state = State::new();
+        for (client, tx, detail) in [
+            (1, 1, Action::Deposit(1_5000)),
+            (2, 2, Action::Deposit(2_0000)),
+            (2, 2, Action::Dispute),
+            (3, 3, Action::Deposit(1)),
+        ] {
+            let transaction = Transaction {
+                client: Client::new(client),
+                tx: Tx::new(tx),
+                detail,
+                idempotency_key: None,
+            };
+            state.handle_transaction(transaction).unwrap();
+        }
+        state
+    }
+
+    #[test]
+    fn matches_within_tolerance() {
+        let expected = "client,available,held,total,locked
+1,1.5009,0,1.5009,false
+2,0,2,2,false
+3,0.0001,0,0.0001,false
+";
+        let differences = reconcile(
+            expected.as_bytes(),
+            state().iter_accounts(),
+            10,
+            b',',
+            &ParseOptions::default(),
+        );
+        assert_eq!(differences, Ok(vec![]));
+    }
+
+    #[test]
+    fn reports_differences() {
+        let expected = "client,total,held,available
+1,1.6,0,1.5
+2,2,0,2
+4,1,0,1
+";
+        let differences = reconcile(
+            expected.as_bytes(),
+            state().iter_accounts(),
+            10,
+            b',',
+            &ParseOptions::default(),
+        );
+        assert_eq!(
+            differences,
+            Ok(vec![
+                "Client 1: total is 1.5 but expected 1.6".to_string(),
+                "Client 2: available is 0 but expected 2".to_string(),
+                "Client 2: held is 2 but expected 0".to_string(),
+                "Client 3: not in the expected balances".to_string(),
+                "Client 4: missing from the output".to_string(),
+            ])
+        );
+    }
+
+    #[test]
+    fn same_dialect_as_input() {
+        let expected = "client;available;held;total
+1;1,5;0;1,5
+2;0;2;2
+3;0,0001;0;0,0001
+";
+        let options = ParseOptions {
+            decimal_separator: ',',
+            ..ParseOptions::default()
+        };
+        let differences = reconcile(
+            expected.as_bytes(),
+            state().iter_accounts(),
+            0,
+            b';',
+            &options,
+        );
+        assert_eq!(differences, Ok(vec![]));
+    }
+
+    #[test]
+    fn invalid_expected_balances() {
+        let read = |expected: &str| {
+            reconcile(
+                expected.as_bytes(),
+                state().iter_accounts(),
+                0,
+                b',',
+                &ParseOptions::default(),
+            )
+        };
+        assert_eq!(
+            read("client,available,total\n"),
+            Err("Expected balances have no held column".to_string())
+        );
+        assert_eq!(
+            read("client,available,held,total\n1,x,0,0\n"),
+            Err("Line 2: Invalid amount \"x\"".to_string())
+        );
+    }
+}
@@ -0,0 +1,87 @@
+//! Interactive mode: apply transactions typed one per line and show the
+//! resulting balances straight away.
+use atm::output::{format_amount, OutputOptions};
+use atm::state::{AccountView, State};
+use atm::transaction::{default_headers, Client, ParseOptions, Transaction, TransactionRow};
+use csv::{ReaderBuilder, StringRecord, Trim, Writer};
+use std::io::{self, BufRead, Write};
+
+const HELP: &str = "Enter a transaction as `type, client, tx, amount` or one of
+    :show <client>  Show the balances of a client
+    :dump           Show the balances of all clients as CSV
+    :help           Show this message
+    :quit           Exit";
+
+fn show(output: &mut impl Write, account: &AccountView) -> io::Result<()> {
+    writeln!(
+        output,
+        "client={} available={} held={} total={} locked={}",
+        account.client,
+        format_amount(account.available),
+        format_amount(account.held),
+        format_amount(account.total()),
+        account.locked
+    )
+}
+
+/// Parse a single transaction line. The amount field may be left off for
+/// transactions that don't have one.
+fn parse_line(line: &str) -> Result<Transaction, String> {
+    let mut record = StringRecord::new();
+    ReaderBuilder::new()
+        .trim(Trim::All)
+        .has_headers(false)
+        .from_reader(line.as_bytes())
+        .read_record(&mut record)
+        .map_err(|e| e.to_string())?;
+    if record.len() == 3 {
+        record.push_field("");
+    }
+    let row: TransactionRow = record
+        .deserialize(Some(&default_headers()))
+        .map_err(|e| e.to_string())?;
+    Transaction::from_row(row, &ParseOptions::default())
+}
+
+/// Read commands and transactions from `input` until it ends or `:quit`,
+/// writing the responses to `output`. Rejected transactions are reported
+/// without stopping.
+pub(crate) fn run(input: impl BufRead, mut output: impl Write) -> io::Result<()> {
+    let mut state = State::new();
+    for line in input.lines() {
+        let line = line?;
+        let mut words = line.split_whitespace();
+        match words.next() {
+            None => continue,
+            Some(":quit") => break,
+            Some(":help") => writeln!(output, "{}", HELP)?,
+            Some(":dump") => {
+                let mut writer = Writer::from_writer(&mut output);
+                state.write_csv(&mut writer, &OutputOptions::default())?;
+                writer.flush()?;
+            }
+            Some(":show") => match words.next().map(str::parse) {
+                Some(Ok(id)) => match state.account(Client::new(id)) {
+                    Some(account) => show(&mut output, &account)?,
+                    None => writeln!(output, "No account for client {}", id)?,
+                },
+                _ => writeln!(output, "Usage: :show <client>")?,
+            },
+            Some(command) if command.starts_with(':') => {
+                writeln!(output, "Unknown command {}, try :help", command)?
+            }
+            Some(_) => match parse_line(&line) {
+                Err(err) => writeln!(output, "Invalid transaction: {}", err)?,
+                Ok(transaction) => {
+                    let client = transaction.client;
+                    match state.handle_transaction(transaction) {
+                        Ok(()) => show(&mut output, &state.account(client).unwrap())?,
+                        Err(err) => writeln!(output, "Rejected [{}]: {}", err.code(), err)?,
+                    }
+                }
+            },
+        }
+        output.flush()?;
+    }
+    Ok(())
+}
@@ -0,0 +1,31 @@
+use atm::process_csv;
+
+/// The example from the problem statement.
+const INPUT: &str = "type, client, tx, amount
+deposit, 1, 1, 1.0
+deposit, 2, 2, 2.0
+deposit, 1, 3, 2.0
+withdrawal, 1, 4, 1.5
+withdrawal, 2, 5, 3.0
+";
+
+const EXPECTED: &str = "client,available,held,total,locked
+1,1.5,0,1.5,false
+2,2,0,2,false
+";
+
+/// Run the built-in example and check the output is what we expect. On a
+/// mismatch the returned error describes the difference.
+pub(crate) fn run() -> Result<(), String> {
+    let mut output = Vec::new();
+    process_csv(INPUT.as_bytes(), &mut output)?;
+    let output = String::from_utf8(output).map_err(|e| e.to_string())?;
+    if output == EXPECTED {
+        Ok(())
+    } else {
+        Err(format!(
+            "Self test failed.\nExpected:\n{}\nGot:\n{}",
+            EXPECTED, output
+        ))
+    }
+}
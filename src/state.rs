@@ -1,42 +1,330 @@
-use crate::transaction::{Action, Client, Transaction, Tx};
-use csv::Writer;
-use std::{collections::HashMap, io::Write};
+use crate::error::TransactionError;
+use crate::output::{write_accounts_csv, OutputOptions, SortBy};
+use crate::report;
+use crate::transaction::{Action, Client, Transaction, Tx, SCALE};
+use crate::verbosity::Verbosity;
+use crate::wal::{self, SyncWrite};
+use csv::{ReaderBuilder, StringRecord, Writer, WriterBuilder};
+use spill::SpillStore;
+use std::{
+    borrow::Cow,
+    cmp::Reverse,
+    collections::{HashMap, HashSet},
+    convert::TryFrom,
+    fmt,
+    io::{self, Read, Write},
+    mem,
+    path::PathBuf,
+    str::FromStr,
+    sync::{Arc, Mutex},
+};
+
+mod spill;
+
+/// Version of the format written by `State::save_snapshot`.
+pub const SNAPSHOT_VERSION: u32 = 8;
+
+/// Policies that change how transactions are applied to accounts. The
+/// defaults give the strict behavior.
+#[derive(Clone, Debug, Default)]
+pub struct Config {
+    /// Allow adjustments of accounts that have been locked.
+    pub allow_locked_adjust: bool,
+    /// Allow resolving disputes on an account after it has been locked. If
+    /// `held` would become negative it is clamped to zero with a warning.
+    pub lenient_post_lock: bool,
+    /// Automatically resolve disputes that are still open after this many
+    /// further transactions for the same client. The age of a dispute is
+    /// counted per account (rather than across all clients) so that it
+    /// doesn't depend on how busy other clients are.
+    pub dispute_timeout: Option<u64>,
+    /// Treat a resolve of a transaction that isn't under dispute as a no-op
+    /// rather than an error.
+    pub lenient_resolve: bool,
+    /// Allow a chargeback of a transaction that isn't under dispute. It is
+    /// treated as a dispute immediately followed by a chargeback.
+    pub allow_direct_chargeback: bool,
+    /// When a dispute is for more than is available, only hold what is
+    /// available and hold the rest as later deposits arrive.
+    pub partial_hold: bool,
+    /// Reject a dispute if the account already has this many transactions
+    /// under dispute.
+    pub max_open_disputes: Option<usize>,
+    /// Track which deposits withdrawals are paid from, oldest transaction
+    /// first, and only allow a dispute if none of the deposit has been
+    /// withdrawn.
+    pub fifo_disputes: bool,
+    /// Like `partial_hold`, but only for the part of the deposit that has
+    /// been withdrawn (attributing withdrawals to the oldest deposits first,
+    /// as with `fifo_disputes`). A dispute that is short for any other reason
+    /// is still rejected.
+    pub dispute_clamp: bool,
+    /// Reject any transaction other than a deposit for a client without an
+    /// account, so that a client's first transaction must be a deposit.
+    pub require_deposit_first: bool,
+    /// What to do with a deposit that reuses the id of an earlier deposit for
+    /// the same client.
+    pub duplicate_tx: DuplicateTx,
+    /// Record each dispute being opened, resolved or charged back so that they
+    /// can be written with `State::write_dispute_log`.
+    pub record_dispute_events: bool,
+    /// Reject deposits once the estimated memory used by accounts and
+    /// recorded deposits (see `State::estimated_memory`) would exceed this
+    /// many bytes.
+    pub max_memory: Option<usize>,
+    /// Allow a reverse-chargeback within this many further transactions for
+    /// the client after the chargeback that locked the account. Without it
+    /// chargebacks are final.
+    pub chargeback_grace: Option<u64>,
+    /// Convert the amounts of a snapshot written with a different `SCALE` to
+    /// this one in `StateBuilder::load_snapshot`, rather than rejecting it.
+    pub rescale_snapshot: bool,
+    /// Transaction types (see `transaction::TYPES`) that are rejected
+    /// outright, before they reach an account.
+    pub disabled_types: Vec<String>,
+    /// A check of every deposit and withdrawal amount before it is applied.
+    pub amount_validator: Option<AmountValidator>,
+    /// A check of every dispute before any funds are held. Without it all
+    /// disputes are authorized.
+    pub dispute_authorizer: Option<DisputeAuthorizer>,
+}
+
+/// What to do with a deposit that reuses the id of an earlier deposit for
+/// the same client, see `Config::duplicate_tx`.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum DuplicateTx {
+    /// Reject it with `TransactionError::DuplicateTx`, leaving the earlier
+    /// deposit in place.
+    #[default]
+    Ignore,
+    /// Replace the amount of the earlier deposit, adjusting `available` by the
+    /// difference. A deposit that is under dispute can't be updated.
+    Update,
+    /// Reject it like `Ignore`, but count it in `State::duplicates` so that
+    /// the run can be failed.
+    Error,
+}
+
+impl FromStr for DuplicateTx {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "ignore" => Ok(DuplicateTx::Ignore),
+            "update" => Ok(DuplicateTx::Update),
+            "error" => Ok(DuplicateTx::Error),
+            other => Err(format!("Unknown duplicate tx policy: {:?}", other)),
+        }
+    }
+}
+
+/// A user supplied check of an amount in 1/10_000's, see
+/// `StateBuilder::amount_validator`.
+#[derive(Clone)]
+pub struct AmountValidator(Arc<dyn Fn(u64) -> Result<(), String> + Send + Sync>);
+
+impl fmt::Debug for AmountValidator {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("AmountValidator")
+    }
+}
+
+/// A user supplied decision whether a dispute of a deposit may be opened, see
+/// `StateBuilder::dispute_authorizer`.
+#[derive(Clone)]
+pub struct DisputeAuthorizer(Arc<dyn Fn(Client, Tx, u64) -> bool + Send + Sync>);
+
+impl fmt::Debug for DisputeAuthorizer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("DisputeAuthorizer")
+    }
+}
 
 /// The information associated to a deposit that we need to save in case it
 /// is disputed/resolved/charged back.
-#[derive(Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq)]
 struct DepositDetail {
     amount: u64,
     under_dispute: bool,
+    /// The account's `sequence` when this was last disputed.
+    disputed_at: u64,
+    /// The part of the disputed amount that still has to be moved into
+    /// `held` (only non-zero with `Config::partial_hold`).
+    owed: u64,
+    /// The part of the amount that hasn't been withdrawn (only less than
+    /// `amount` with `Config::fifo_disputes` or `Config::dispute_clamp`).
+    remaining: u64,
+}
+
+impl DepositDetail {
+    /// The part of the amount that is currently held.
+    fn held_amount(&self) -> u64 {
+        self.amount - self.owed
+    }
+}
+
+/// A step in the lifecycle of a dispute.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum DisputeEvent {
+    Opened,
+    Resolved,
+    /// Resolved because of `Config::dispute_timeout`.
+    Expired,
+    ChargedBack,
+    /// The chargeback was reversed, see `Action::ReverseChargeback`.
+    Reversed,
+}
+
+impl DisputeEvent {
+    fn name(self) -> &'static str {
+        match self {
+            DisputeEvent::Opened => "opened",
+            DisputeEvent::Resolved => "resolved",
+            DisputeEvent::Expired => "expired",
+            DisputeEvent::ChargedBack => "charged_back",
+            DisputeEvent::Reversed => "reversed",
+        }
+    }
 }
 
 /// The state of a single client account.
 ///
 /// # Invariant
 ///
-/// The total amount of all transactions under dispute should be equal to the
-/// the amount `held` _if_ the account isn't locked. If the accoun is locked,
-/// then there is no guarantee about `held` relating to the disputed
+/// The total amount of all transactions under dispute, less any amounts still
+/// owed on them, should be equal to the amount `held` _if_ the account isn't
+/// locked. Nothing is owed while there are funds `available`. If the account
+/// is locked, then there is no guarantee about `held` relating to the disputed
 /// transactions.
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 struct Account {
     client: Client,
     held: u64,
     available: u64,
     locked: bool,
+    /// The chargeback that locked the account.
+    locked_by: Option<Tx>,
+    /// The `sequence` of that chargeback and the amount it took, so that it
+    /// can be reversed.
+    locked_at: u64,
+    charged_back: u64,
+    /// Whether withdrawals are blocked by an `Action::Freeze`.
+    withdrawals_blocked: bool,
+    /// The largest `held` has been.
+    peak_held: u64,
     transactions: HashMap<Tx, DepositDetail>,
+    /// The number of transactions that have been submitted to this account.
+    sequence: u64,
+    /// The number of `transactions` that are under dispute.
+    open_disputes: usize,
+    /// The order in which the account was created, relative to the others in
+    /// the `State`.
+    first_seen: u64,
+    /// The dispute events of this account in order, if
+    /// `Config::record_dispute_events` is set.
+    dispute_events: Vec<(Tx, DisputeEvent)>,
+}
+
+/// A read-only view of the state of a single client account.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct AccountView {
+    pub client: Client,
+    /// Number of 1/10_000's available.
+    pub available: u64,
+    /// Number of 1/10_000's held.
+    pub held: u64,
+    pub locked: bool,
+    /// The chargeback that locked the account.
+    pub locked_by: Option<Tx>,
+    /// Whether withdrawals are blocked by an `Action::Freeze`.
+    pub withdrawals_blocked: bool,
+    /// The largest number of 1/10_000's that have been held at once. It
+    /// isn't lowered when disputes are resolved.
+    pub peak_held: u64,
+    /// `held` recomputed from the disputed deposits, which should always
+    /// equal `held`. `None` for locked accounts, where the invariant doesn't
+    /// hold.
+    pub held_check: Option<u64>,
+    /// The number of deposits recorded.
+    pub deposits: usize,
+}
+
+impl AccountView {
+    /// Number of 1/10_000's in total, held or available.
+    pub fn total(&self) -> u64 {
+        self.available + self.held
+    }
+
+    /// Whether nothing ever happened to the account, i.e. it only had
+    /// transactions that were rejected.
+    pub fn is_empty(&self) -> bool {
+        self.total() == 0 && !self.locked && self.deposits == 0
+    }
+
+    /// A summary of the account, the first of these that applies:
+    ///
+    /// | Status | When |
+    /// |---|---|
+    /// | `frozen` | the account is locked |
+    /// | `empty` | nothing is available or held |
+    /// | `fully-held` | nothing is available but some funds are held |
+    /// | `active` | otherwise |
+    pub fn status(&self) -> &'static str {
+        if self.locked {
+            "frozen"
+        } else if self.total() == 0 {
+            "empty"
+        } else if self.available == 0 {
+            "fully-held"
+        } else {
+            "active"
+        }
+    }
 }
 
 impl Account {
-    /// Create a new empty account.
-    pub(crate) fn new(client: Client) -> Self {
+    /// Create a new empty account, the `first_seen`th in its `State`.
+    pub(crate) fn new(client: Client, first_seen: u64) -> Self {
         // INVARIANT: No transactions are under dispute and `held` is 0.
         Account {
             client,
             held: 0,
             available: 0,
             locked: false,
+            locked_by: None,
+            locked_at: 0,
+            charged_back: 0,
+            withdrawals_blocked: false,
+            peak_held: 0,
             transactions: HashMap::new(),
+            sequence: 0,
+            open_disputes: 0,
+            first_seen,
+            dispute_events: Vec::new(),
+        }
+    }
+
+    fn view(&self) -> AccountView {
+        AccountView {
+            client: self.client,
+            available: self.available,
+            held: self.held,
+            locked: self.locked,
+            locked_by: self.locked_by,
+            withdrawals_blocked: self.withdrawals_blocked,
+            peak_held: self.peak_held,
+            held_check: if self.locked {
+                None
+            } else {
+                Some(
+                    self.transactions
+                        .values()
+                        .filter(|detail| detail.under_dispute)
+                        .map(DepositDetail::held_amount)
+                        .sum(),
+                )
+            },
+            deposits: self.transactions.len(),
         }
     }
 
@@ -44,288 +332,2453 @@ impl Account {
         &mut self,
         tx: Tx,
         expect_disputed: bool,
-    ) -> Result<&mut DepositDetail, String> {
+    ) -> Result<&mut DepositDetail, TransactionError> {
         let transaction = self
             .transactions
             .get_mut(&tx)
-            .ok_or_else(|| format!("Transaction was not found: {:?}", tx))?;
+            .ok_or(TransactionError::UnknownTx(tx))?;
         if expect_disputed && !transaction.under_dispute {
-            return Err(format!(
-                "Transaction is not under dispute: {:?}",
-                transaction
-            ));
+            return Err(TransactionError::NotDisputed(tx));
         } else if !expect_disputed && transaction.under_dispute {
-            return Err(format!(
-                "Transaction is already under dispute: {:?}",
-                transaction
-            ));
+            return Err(TransactionError::AlreadyDisputed(tx));
         }
         Ok(transaction)
     }
 
-    fn check_transaction_is_new(&self, tx: Tx) -> Result<(), String> {
+    /// Replace the amount of the recorded deposit `transaction.tx` with
+    /// `amount` (with `DuplicateTx::Update`). Withdrawals already paid from it
+    /// stay paid from it.
+    fn update_deposit(
+        &mut self,
+        transaction: Transaction,
+        amount: u64,
+    ) -> Result<(), TransactionError> {
+        let tx = transaction.tx;
+        let deposit = &self.transactions[&tx];
+        if deposit.under_dispute {
+            return Err(TransactionError::AlreadyDisputed(tx));
+        }
+        let withdrawn = deposit.amount - deposit.remaining;
+        // INVARIANT: The deposit isn't under dispute so `held` doesn't depend
+        // on it, and `available` only changes by the difference.
+        let available = if amount >= deposit.amount {
+            self.credited(amount - deposit.amount)
+                .ok_or(TransactionError::Overflow(transaction))?
+        } else {
+            self.available
+                .checked_sub(deposit.amount - amount)
+                .ok_or(TransactionError::InsufficientFunds(transaction))?
+        };
+        self.available = available;
+        let deposit = self.transactions.get_mut(&tx).unwrap();
+        deposit.amount = amount;
+        deposit.remaining = amount.saturating_sub(withdrawn);
+        Ok(())
+    }
+
+    /// `available` after adding `amount`, unless it or the total would no
+    /// longer fit in a `u64`.
+    fn credited(&self, amount: u64) -> Option<u64> {
+        self.available
+            .checked_add(amount)
+            .filter(|available| available.checked_add(self.held).is_some())
+    }
+
+    fn check_transaction_is_new(&self, tx: Tx) -> Result<(), TransactionError> {
         match self.transactions.get(&tx) {
             None => Ok(()),
-            Some(_) => Err(format!("Transaction already exists: {:?}", tx)),
+            Some(_) => Err(TransactionError::DuplicateTx(tx)),
         }
     }
 
     /// Assumes that the transaction is actually for this account and the
     /// account is not locked.
-    fn handle_valid_transaction(&mut self, transaction: Transaction) -> Result<(), String> {
+    fn handle_valid_transaction(
+        &mut self,
+        transaction: Transaction,
+        config: &Config,
+    ) -> Result<(), TransactionError> {
         let tx = transaction.tx;
         match transaction.detail {
             Action::Deposit(amount) => {
+                if config.duplicate_tx == DuplicateTx::Update && self.transactions.contains_key(&tx)
+                {
+                    return self.update_deposit(transaction, amount);
+                }
                 self.check_transaction_is_new(tx)?;
+                let new_available = self
+                    .credited(amount)
+                    .ok_or(TransactionError::Overflow(transaction))?;
                 // INVARIANT: The new transaction is not under dispute and
                 // `held` is not modified.
-                self.available += amount;
+                self.available = new_available;
                 self.transactions.insert(
                     tx,
                     DepositDetail {
                         amount,
                         under_dispute: false,
+                        disputed_at: 0,
+                        owed: 0,
+                        remaining: amount,
                     },
                 );
+                self.settle_owed();
                 Ok(())
             }
             Action::Withdrawal(amount) => {
+                if self.withdrawals_blocked {
+                    return Err(TransactionError::Frozen(transaction));
+                }
                 self.check_transaction_is_new(tx)?;
-                let new_available = self.available.checked_sub(amount).ok_or_else(|| {
-                    format!("Insufficient funds for withdrawal {:?}", transaction)
-                })?;
-                // INVARIANT: Transactions are not changed and `held` is not
+                let new_available = self
+                    .available
+                    .checked_sub(amount)
+                    .ok_or(TransactionError::InsufficientFunds(transaction))?;
+                // INVARIANT: Transactions are not disputed and `held` is not
                 // modified.
                 self.available = new_available;
+                if config.fifo_disputes || config.dispute_clamp {
+                    self.consume_remaining(amount);
+                }
                 Ok(())
             }
             Action::Dispute => {
-                let available = self.available;
+                if let Some(max) = config.max_open_disputes {
+                    if self.open_disputes >= max {
+                        return Err(TransactionError::TooManyOpenDisputes {
+                            open: self.open_disputes,
+                            transaction,
+                        });
+                    }
+                }
+                let (client, available, sequence) = (self.client, self.available, self.sequence);
                 let disputed_transaction = self.lookup_transaction(tx, false)?;
                 let amount = disputed_transaction.amount;
-                let new_available = available.checked_sub(amount).ok_or_else(|| {
-                    format!(
-                        "Insufficient funds to dispute transaction: {:?}",
-                        transaction
-                    )
-                })?;
+                if let Some(DisputeAuthorizer(authorize)) = &config.dispute_authorizer {
+                    if !authorize(client, tx, amount) {
+                        return Err(TransactionError::DisputeNotAuthorized(transaction));
+                    }
+                }
+                if config.fifo_disputes && disputed_transaction.remaining < amount {
+                    return Err(TransactionError::FundsWithdrawn {
+                        remaining: disputed_transaction.remaining,
+                        transaction,
+                    });
+                }
+                let withdrawn = amount - disputed_transaction.remaining;
+                let clamp = config.partial_hold
+                    || (config.dispute_clamp && amount.saturating_sub(available) <= withdrawn);
+                let hold = if clamp { amount.min(available) } else { amount };
+                let new_available = available
+                    .checked_sub(hold)
+                    .ok_or(TransactionError::InsufficientFunds(transaction))?;
                 // INVARIANT: The transaction is switched from not under dispute
                 // to under dispute and `held` is incremented by the ammount of
-                // the transaction.
+                // the transaction that isn't owed. If anything is owed then
+                // `available` is now 0.
                 disputed_transaction.under_dispute = true;
+                disputed_transaction.disputed_at = sequence;
+                disputed_transaction.owed = amount - hold;
+                self.available = new_available;
+                self.held += hold;
+                self.open_disputes += 1;
+                self.record_event(config, tx, DisputeEvent::Opened);
+                Ok(())
+            }
+            Action::Adjust(amount) => {
+                let magnitude = amount.unsigned_abs();
+                let new_available = if amount >= 0 {
+                    self.credited(magnitude)
+                        .ok_or(TransactionError::Overflow(transaction))?
+                } else {
+                    self.available
+                        .checked_sub(magnitude)
+                        .ok_or(TransactionError::InsufficientFunds(transaction))?
+                };
+                // INVARIANT: Transactions are not changed and `held` is not
+                // modified.
                 self.available = new_available;
-                self.held += amount;
                 Ok(())
             }
             Action::Resolve => {
-                let resolved_transaction = self.lookup_transaction(tx, true)?;
-                // INVARIANT: The transaction is switched from under dispute to
-                // not under dispute and `held` is decremented by the ammount of
-                // the transaction.
-                resolved_transaction.under_dispute = false;
-                let amount = resolved_transaction.amount;
-                self.held -= amount;
-                self.available += amount;
+                let undisputed =
+                    matches!(self.transactions.get(&tx), Some(detail) if !detail.under_dispute);
+                if config.lenient_resolve && undisputed {
+                    report!(
+                        Verbosity::Normal,
+                        "Ignoring resolve of {:?} for client {} as it isn't under dispute",
+                        tx,
+                        self.client
+                    );
+                    return Ok(());
+                }
+                self.resolve(tx)?;
+                self.record_event(config, tx, DisputeEvent::Resolved);
                 Ok(())
             }
             Action::ChargeBack => {
-                let charge_back_transaction = self.lookup_transaction(tx, true)?;
+                let held = self.held;
+                let available = self.available;
+                let direct = config.allow_direct_chargeback
+                    && matches!(self.transactions.get(&tx), Some(detail) if !detail.under_dispute);
+                let charge_back_transaction = self.lookup_transaction(tx, !direct)?;
+                let amount = charge_back_transaction.held_amount();
+                // Anything still owed on the dispute can no longer be
+                // collected once the account is locked.
+                charge_back_transaction.owed = 0;
+                let (new_available, new_held) = if direct {
+                    // Take the funds straight from `available` as if they had
+                    // first been disputed.
+                    let new_available = available
+                        .checked_sub(amount)
+                        .ok_or(TransactionError::InsufficientFunds(transaction))?;
+                    charge_back_transaction.under_dispute = true;
+                    self.open_disputes += 1;
+                    (new_available, held)
+                } else {
+                    let new_held = held
+                        .checked_sub(amount)
+                        .ok_or(TransactionError::NegativeHeld(tx))?;
+                    (available, new_held)
+                };
                 // INVARIANT: The account is now locked, so we don't need to
                 // keep `held` in line with the disputed transactions.
-                self.held -= charge_back_transaction.amount;
+                self.available = new_available;
+                self.held = new_held;
                 self.locked = true;
+                self.locked_by = Some(tx);
+                self.locked_at = self.sequence;
+                self.charged_back = amount;
+                self.record_event(config, tx, DisputeEvent::ChargedBack);
+                Ok(())
+            }
+            Action::Freeze => {
+                self.withdrawals_blocked = true;
+                Ok(())
+            }
+            Action::Unfreeze => {
+                self.withdrawals_blocked = false;
                 Ok(())
             }
+            // Only a locked account has a chargeback to reverse.
+            Action::ReverseChargeback => Err(TransactionError::NotChargedBack(tx)),
+        }
+    }
+
+    /// Undo the chargeback that locked the account, returning the funds it
+    /// took and unlocking the account.
+    fn reverse_chargeback(
+        &mut self,
+        transaction: Transaction,
+        config: &Config,
+    ) -> Result<(), TransactionError> {
+        let tx = transaction.tx;
+        if self.locked_by != Some(tx) {
+            return Err(TransactionError::NotChargedBack(tx));
+        }
+        match config.chargeback_grace {
+            Some(grace) if self.sequence - self.locked_at <= grace => (),
+            _ => return Err(TransactionError::ChargebackFinal(transaction)),
+        }
+        // Other disputes may have been resolved while the account was locked
+        // (with `Config::lenient_post_lock`), so `held` is recomputed rather
+        // than adjusted.
+        let new_held: u64 = self
+            .transactions
+            .iter()
+            .filter(|(other, detail)| **other != tx && detail.under_dispute)
+            .map(|(_, detail)| detail.held_amount())
+            .sum();
+        let new_available = (self.available + self.held + self.charged_back)
+            .checked_sub(new_held)
+            .ok_or(TransactionError::InsufficientFunds(transaction))?;
+        // INVARIANT: The charged back transaction is no longer under dispute
+        // and `held` is the total of the transactions that are.
+        if let Some(detail) = self.transactions.get_mut(&tx) {
+            detail.under_dispute = false;
+            self.open_disputes -= 1;
+        }
+        self.available = new_available;
+        self.held = new_held;
+        self.locked = false;
+        self.locked_by = None;
+        self.locked_at = 0;
+        self.charged_back = 0;
+        self.settle_owed();
+        self.record_event(config, tx, DisputeEvent::Reversed);
+        Ok(())
+    }
+
+    fn resolve(&mut self, tx: Tx) -> Result<(), TransactionError> {
+        let held = self.held;
+        let resolved_transaction = self.lookup_transaction(tx, true)?;
+        let amount = resolved_transaction.held_amount();
+        let new_held = held
+            .checked_sub(amount)
+            .ok_or(TransactionError::NegativeHeld(tx))?;
+        // INVARIANT: The transaction is switched from under dispute to
+        // not under dispute and `held` is decremented by the ammount of
+        // the transaction that was held.
+        resolved_transaction.under_dispute = false;
+        resolved_transaction.owed = 0;
+        self.held = new_held;
+        self.available += amount;
+        self.open_disputes -= 1;
+        self.settle_owed();
+        Ok(())
+    }
+
+    /// Move newly available funds into `held` for disputes that weren't
+    /// fully held when they were opened, oldest transaction first.
+    fn settle_owed(&mut self) {
+        if self.available == 0 {
+            return;
+        }
+        let mut owing: Vec<(&Tx, &mut DepositDetail)> = self
+            .transactions
+            .iter_mut()
+            .filter(|(_, detail)| detail.under_dispute && detail.owed > 0)
+            .collect();
+        owing.sort_by_key(|(tx, _)| **tx);
+        for (_, detail) in owing {
+            // INVARIANT: The amount moved into `held` is no longer owed.
+            let hold = detail.owed.min(self.available);
+            detail.owed -= hold;
+            self.available -= hold;
+            self.held += hold;
+            if self.available == 0 {
+                break;
+            }
+        }
+    }
+
+    /// Mark `amount` of the deposits that aren't disputed as withdrawn, oldest
+    /// transaction first.
+    fn consume_remaining(&mut self, mut amount: u64) {
+        let mut deposits: Vec<(&Tx, &mut DepositDetail)> = self
+            .transactions
+            .iter_mut()
+            .filter(|(_, detail)| !detail.under_dispute && detail.remaining > 0)
+            .collect();
+        deposits.sort_by_key(|(tx, _)| **tx);
+        for (_, detail) in deposits {
+            let consumed = detail.remaining.min(amount);
+            detail.remaining -= consumed;
+            amount -= consumed;
+            if amount == 0 {
+                break;
+            }
+        }
+    }
+
+    fn record_event(&mut self, config: &Config, tx: Tx, event: DisputeEvent) {
+        if config.record_dispute_events {
+            self.dispute_events.push((tx, event));
+        }
+    }
+
+    /// Resolve any disputes that have been open for more than `timeout`
+    /// transactions.
+    fn expire_disputes(&mut self, timeout: u64, config: &Config) {
+        let sequence = self.sequence;
+        let mut expired: Vec<Tx> = self
+            .transactions
+            .iter()
+            .filter(|(_, detail)| detail.under_dispute && sequence - detail.disputed_at > timeout)
+            .map(|(tx, _)| *tx)
+            .collect();
+        expired.sort();
+        for tx in expired {
+            match self.resolve(tx) {
+                Ok(()) => {
+                    report!(
+                        Verbosity::Normal,
+                        "Dispute of {:?} for client {} timed out and was resolved",
+                        tx,
+                        self.client
+                    );
+                    self.record_event(config, tx, DisputeEvent::Expired);
+                }
+                Err(err) => report!(
+                    Verbosity::Normal,
+                    "Failed to resolve timed out dispute of {:?} for client {}: {}",
+                    tx,
+                    self.client,
+                    err
+                ),
+            }
+        }
+    }
+
+    /// Resolve a dispute on a locked account. The invariant on `held` doesn't
+    /// apply to locked accounts, so instead of rejecting a resolve that would
+    /// make `held` negative we clamp it to zero. The charged back transaction
+    /// stays under dispute, but its funds are gone, so it can't be resolved.
+    fn resolve_after_lock(&mut self, transaction: Transaction) -> Result<(), TransactionError> {
+        if self.locked_by == Some(transaction.tx) {
+            return Err(TransactionError::Locked(transaction));
         }
+        let held = self.held;
+        let resolved_transaction = self.lookup_transaction(transaction.tx, true)?;
+        resolved_transaction.under_dispute = false;
+        let amount = resolved_transaction.held_amount();
+        resolved_transaction.owed = 0;
+        self.open_disputes -= 1;
+        self.held = held.checked_sub(amount).unwrap_or_else(|| {
+            report!(
+                Verbosity::Normal,
+                "Warning: resolve on locked account would make held negative, clamping to 0: {:?}",
+                transaction
+            );
+            0
+        });
+        self.available += amount;
+        Ok(())
     }
 
     /// Apply the effects of the given transaction.
-    pub(crate) fn handle_transaction(&mut self, transaction: Transaction) -> Result<(), String> {
+    pub(crate) fn handle_transaction(
+        &mut self,
+        transaction: Transaction,
+        config: &Config,
+    ) -> Result<(), TransactionError> {
+        let result = self.apply(transaction, config);
+        // Disputes aren't the only way `held` grows: amounts owed on them are
+        // held as funds arrive.
+        self.peak_held = self.peak_held.max(self.held);
+        result
+    }
+
+    fn apply(&mut self, transaction: Transaction, config: &Config) -> Result<(), TransactionError> {
         if self.client != transaction.client {
-            return Err(format!(
-                "Transaction cannot be applied to client {:?}: {:?}",
-                self.client, transaction
-            ));
+            return Err(TransactionError::WrongClient {
+                account: self.client,
+                transaction,
+            });
+        }
+        self.sequence += 1;
+        if let (Some(timeout), false) = (config.dispute_timeout, self.locked) {
+            self.expire_disputes(timeout, config);
         }
         if self.locked {
+            if transaction.detail == Action::ReverseChargeback {
+                return self.reverse_chargeback(transaction, config);
+            }
+            if config.lenient_post_lock && transaction.detail == Action::Resolve {
+                let tx = transaction.tx;
+                self.resolve_after_lock(transaction)?;
+                self.record_event(config, tx, DisputeEvent::Resolved);
+                return Ok(());
+            }
+            if config.allow_locked_adjust && matches!(transaction.detail, Action::Adjust(_)) {
+                return self.handle_valid_transaction(transaction, config);
+            }
+            return Err(TransactionError::Locked(transaction));
+        }
+        self.handle_valid_transaction(transaction, config)
+    }
+}
+
+/// Convert `amount` in 1/`from`'s to 1/`to`'s, failing if it can't be
+/// represented exactly.
+fn rescale_amount(amount: u64, from: u64, to: u64) -> Result<u64, String> {
+    let scaled = u128::from(amount) * u128::from(to);
+    if scaled % u128::from(from) != 0 {
+        return Err(format!(
+            "amount {} at scale {} can't be represented at scale {}",
+            amount, from, to
+        ));
+    }
+    u64::try_from(scaled / u128::from(from))
+        .map_err(|_| format!("amount {} overflows at scale {}", amount, to))
+}
+
+/// Parse field `index` of a snapshot row.
+fn snapshot_field<T>(record: &StringRecord, index: usize, name: &str) -> Result<T, String>
+where
+    T: FromStr,
+    T::Err: std::fmt::Display,
+{
+    let raw = record
+        .get(index)
+        .ok_or_else(|| format!("missing {}", name))?;
+    raw.parse()
+        .map_err(|e| format!("invalid {} {:?}: {}", name, raw, e))
+}
+
+impl Account {
+    /// Read an `account` row of a snapshot. Version 1 snapshots don't have
+    /// `first_seen`, so it is 0 for all of their accounts, versions before 4
+    /// don't have `locked_by`, versions before 5 don't have `locked_at` and
+    /// `charged_back`, so their chargebacks can't be reversed, versions
+    /// before 6 don't have `withdrawals_blocked` and versions before 8 don't
+    /// have `peak_held`, which is then the current `held`.
+    fn from_snapshot(record: &StringRecord) -> Result<Self, String> {
+        let first_seen = match record.get(6) {
+            Some(_) => snapshot_field(record, 6, "first_seen")?,
+            None => 0,
+        };
+        let locked_by = match record.get(7) {
+            None | Some("") => None,
+            Some(_) => Some(Tx::new(snapshot_field(record, 7, "locked_by")?)),
+        };
+        let sequence = snapshot_field(record, 5, "sequence")?;
+        let (locked_at, charged_back) = match record.get(8) {
+            Some(_) => (
+                snapshot_field(record, 8, "locked_at")?,
+                snapshot_field(record, 9, "charged_back")?,
+            ),
+            None => (0, 0),
+        };
+        let withdrawals_blocked = match record.get(10) {
+            Some(_) => snapshot_field(record, 10, "withdrawals_blocked")?,
+            None => false,
+        };
+        let held = snapshot_field(record, 3, "held")?;
+        let peak_held = match record.get(11) {
+            Some(_) => snapshot_field(record, 11, "peak_held")?,
+            None => held,
+        };
+        Ok(Account {
+            client: Client::new(snapshot_field(record, 1, "client")?),
+            available: snapshot_field(record, 2, "available")?,
+            held,
+            locked: snapshot_field(record, 4, "locked")?,
+            locked_by,
+            locked_at,
+            charged_back,
+            withdrawals_blocked,
+            peak_held,
+            transactions: HashMap::new(),
+            sequence,
+            open_disputes: 0,
+            first_seen,
+            dispute_events: Vec::new(),
+        })
+    }
+
+    /// Read a `deposit` row of a snapshot into this account.
+    fn deposit_from_snapshot(&mut self, record: &StringRecord) -> Result<(), String> {
+        let client = Client::new(snapshot_field(record, 1, "client")?);
+        if client != self.client {
             return Err(format!(
-                "Cannot apply transaction because client account {:?} is locked: {:?}",
-                self.client, transaction
+                "deposit for client {} follows account {}",
+                client, self.client
             ));
         }
-        self.handle_valid_transaction(transaction)
+        let tx = Tx::new(snapshot_field(record, 2, "tx")?);
+        let amount = snapshot_field(record, 3, "amount")?;
+        // Older snapshots don't have `remaining`, so nothing has been
+        // withdrawn from their deposits.
+        let remaining = match record.get(7) {
+            Some(_) => snapshot_field(record, 7, "remaining")?,
+            None => amount,
+        };
+        let detail = DepositDetail {
+            amount,
+            under_dispute: snapshot_field(record, 4, "under_dispute")?,
+            disputed_at: snapshot_field(record, 5, "disputed_at")?,
+            owed: snapshot_field(record, 6, "owed")?,
+            remaining,
+        };
+        let under_dispute = detail.under_dispute;
+        if self.transactions.insert(tx, detail).is_some() {
+            return Err(format!("transaction {} appears more than once", tx));
+        }
+        if under_dispute {
+            self.open_disputes += 1;
+        }
+        Ok(())
+    }
+
+    /// Convert all amounts of the account from 1/`from`'s to 1/`to`'s.
+    fn rescale(&mut self, from: u64, to: u64) -> Result<(), String> {
+        self.available = rescale_amount(self.available, from, to)?;
+        self.held = rescale_amount(self.held, from, to)?;
+        self.peak_held = rescale_amount(self.peak_held, from, to)?;
+        for detail in self.transactions.values_mut() {
+            detail.amount = rescale_amount(detail.amount, from, to)?;
+            detail.owed = rescale_amount(detail.owed, from, to)?;
+            detail.remaining = rescale_amount(detail.remaining, from, to)?;
+        }
+        Ok(())
+    }
+
+    /// Write the `account` row for this account and a `deposit` row for
+    /// each of its deposits, see `State::save_snapshot`.
+    fn write_snapshot<W: Write>(&self, writer: &mut Writer<W>) -> csv::Result<()> {
+        writer.write_record([
+            "account".to_string(),
+            self.client.to_string(),
+            self.available.to_string(),
+            self.held.to_string(),
+            self.locked.to_string(),
+            self.sequence.to_string(),
+            self.first_seen.to_string(),
+            self.locked_by.map_or(String::new(), |tx| tx.to_string()),
+            self.locked_at.to_string(),
+            self.charged_back.to_string(),
+            self.withdrawals_blocked.to_string(),
+            self.peak_held.to_string(),
+        ])?;
+        let mut transactions: Vec<_> = self.transactions.iter().collect();
+        transactions.sort_by_key(|(tx, _)| **tx);
+        for (tx, detail) in transactions {
+            writer.write_record([
+                "deposit".to_string(),
+                self.client.to_string(),
+                tx.to_string(),
+                detail.amount.to_string(),
+                detail.under_dispute.to_string(),
+                detail.disputed_at.to_string(),
+                detail.owed.to_string(),
+                detail.remaining.to_string(),
+            ])?;
+        }
+        Ok(())
     }
 }
 
 /// State of all known accounts.
-pub(crate) struct State {
+pub struct State {
     accounts: HashMap<Client, Account>,
+    config: Config,
+    /// Where accounts that don't fit in memory are kept, if enabled.
+    spill: Option<SpillStore>,
+    /// The idempotency keys of all accepted transactions, possibly shared
+    /// with other `State`s.
+    idempotency_keys: IdempotencyKeys,
+    /// The `first_seen` of the next account to be created.
+    next_first_seen: u64,
+    /// The number of deposits recorded by all accounts.
+    recorded_deposits: usize,
+    /// The number of transactions rejected with `DuplicateTx::Error`.
+    duplicates: usize,
 }
 
-impl State {
-    /// Create an empty `State`.
-    pub(crate) fn new() -> Self {
+/// Estimated bytes used by each account and each recorded deposit. These
+/// only count the map entries, not the maps' spare capacity.
+const ACCOUNT_BYTES: usize = mem::size_of::<(Client, Account)>();
+const DEPOSIT_BYTES: usize = mem::size_of::<(Tx, DepositDetail)>();
+
+/// A set of idempotency keys that can be shared between `State`s.
+pub(crate) type IdempotencyKeys = Arc<Mutex<HashSet<String>>>;
+
+/// Builder for a `State` with non-default policies.
+#[derive(Clone, Debug, Default)]
+pub struct StateBuilder {
+    config: Config,
+    spill: Option<(PathBuf, usize)>,
+    idempotency_keys: Option<IdempotencyKeys>,
+}
+
+impl StateBuilder {
+    pub fn new() -> Self {
+        StateBuilder::default()
+    }
+
+    /// See `Config::dispute_timeout`.
+    pub fn dispute_timeout(mut self, timeout: Option<u64>) -> Self {
+        self.config.dispute_timeout = timeout;
+        self
+    }
+
+    /// See `Config::lenient_resolve`.
+    pub fn lenient_resolve(mut self, lenient: bool) -> Self {
+        self.config.lenient_resolve = lenient;
+        self
+    }
+
+    /// See `Config::allow_direct_chargeback`.
+    pub fn allow_direct_chargeback(mut self, allow: bool) -> Self {
+        self.config.allow_direct_chargeback = allow;
+        self
+    }
+
+    /// See `Config::partial_hold`.
+    pub fn partial_hold(mut self, partial: bool) -> Self {
+        self.config.partial_hold = partial;
+        self
+    }
+
+    /// See `Config::max_open_disputes`.
+    pub fn max_open_disputes(mut self, max: Option<usize>) -> Self {
+        self.config.max_open_disputes = max;
+        self
+    }
+
+    /// See `Config::dispute_clamp`.
+    pub fn dispute_clamp(mut self, clamp: bool) -> Self {
+        self.config.dispute_clamp = clamp;
+        self
+    }
+
+    /// See `Config::duplicate_tx`.
+    pub fn duplicate_tx(mut self, policy: DuplicateTx) -> Self {
+        self.config.duplicate_tx = policy;
+        self
+    }
+
+    /// See `Config::require_deposit_first`.
+    pub fn require_deposit_first(mut self, require: bool) -> Self {
+        self.config.require_deposit_first = require;
+        self
+    }
+
+    /// See `Config::fifo_disputes`.
+    pub fn fifo_disputes(mut self, fifo: bool) -> Self {
+        self.config.fifo_disputes = fifo;
+        self
+    }
+
+    /// See `Config::record_dispute_events`.
+    pub fn record_dispute_events(mut self, record: bool) -> Self {
+        self.config.record_dispute_events = record;
+        self
+    }
+
+    /// Check the amount (in 1/10_000's) of every deposit and withdrawal
+    /// before it is applied, rejecting the transaction with the returned
+    /// reason if it fails.
+    pub fn amount_validator(
+        mut self,
+        validator: Box<dyn Fn(u64) -> Result<(), String> + Send + Sync>,
+    ) -> Self {
+        self.config.amount_validator = Some(AmountValidator(Arc::from(validator)));
+        self
+    }
+
+    /// Decide whether each dispute may be opened, given the client, the
+    /// disputed deposit and its amount (in 1/10_000's). A dispute it denies is
+    /// rejected before any funds are held.
+    pub fn dispute_authorizer(
+        mut self,
+        authorizer: Box<dyn Fn(Client, Tx, u64) -> bool + Send + Sync>,
+    ) -> Self {
+        self.config.dispute_authorizer = Some(DisputeAuthorizer(Arc::from(authorizer)));
+        self
+    }
+
+    /// See `Config::chargeback_grace`.
+    pub fn chargeback_grace(mut self, grace: Option<u64>) -> Self {
+        self.config.chargeback_grace = grace;
+        self
+    }
+
+    /// See `Config::rescale_snapshot`.
+    pub fn rescale_snapshot(mut self, enable: bool) -> Self {
+        self.config.rescale_snapshot = enable;
+        self
+    }
+
+    /// See `Config::max_memory`.
+    pub fn max_memory(mut self, bytes: Option<usize>) -> Self {
+        self.config.max_memory = bytes;
+        self
+    }
+
+    /// See `Config::disabled_types`.
+    pub fn disabled_types(mut self, types: Vec<String>) -> Self {
+        self.config.disabled_types = types;
+        self
+    }
+
+    /// See `Config::allow_locked_adjust`.
+    pub fn allow_locked_adjust(mut self, allow: bool) -> Self {
+        self.config.allow_locked_adjust = allow;
+        self
+    }
+
+    /// See `Config::lenient_post_lock`.
+    pub fn lenient_post_lock(mut self, lenient: bool) -> Self {
+        self.config.lenient_post_lock = lenient;
+        self
+    }
+
+    /// Keep at most `capacity` accounts in memory, writing the least
+    /// recently used ones to files in `dir` and reading them back when they
+    /// are next needed. Not supported with `State::merge`.
+    pub fn spill(mut self, dir: PathBuf, capacity: usize) -> Self {
+        self.spill = Some((dir, capacity));
+        self
+    }
+
+    /// Check and record idempotency keys in `keys` rather than a set of the
+    /// `State`'s own, so that a key used with one `State` is skipped by the
+    /// others.
+    pub(crate) fn idempotency_keys(mut self, keys: IdempotencyKeys) -> Self {
+        self.idempotency_keys = Some(keys);
+        self
+    }
+
+    /// Create an empty `State` using the configured policies.
+    pub fn build(self) -> State {
         State {
             accounts: HashMap::new(),
+            config: self.config,
+            spill: self
+                .spill
+                .map(|(dir, capacity)| SpillStore::new(dir, capacity)),
+            idempotency_keys: self.idempotency_keys.unwrap_or_default(),
+            next_first_seen: 0,
+            recorded_deposits: 0,
+            duplicates: 0,
+        }
+    }
+
+    /// Create a `State` using the configured policies from a snapshot written
+    /// by `State::save_snapshot`. This only checks that the snapshot is well
+    /// formed; use `State::check_invariants` to check its consistency.
+    ///
+    /// A snapshot written with a different `SCALE` is rejected unless
+    /// `Config::rescale_snapshot` is set. Snapshots before version 7 don't
+    /// record their scale and are assumed to use `SCALE`.
+    pub fn load_snapshot<R: Read>(self, reader: R) -> Result<State, String> {
+        let mut state = self.build();
+        let mut reader = ReaderBuilder::new()
+            .has_headers(false)
+            .flexible(true)
+            .from_reader(reader);
+        let mut records = reader.records().enumerate();
+        let with_line = |line: usize| move |e: String| format!("Line {}: {}", line + 1, e);
+
+        let (line, record) = records.next().ok_or("Snapshot is empty")?;
+        let record = record.map_err(|e| with_line(line)(e.to_string()))?;
+        if record.get(0) != Some("version") {
+            return Err(with_line(line)(
+                "snapshot must start with a version row".to_string(),
+            ));
+        }
+        let version: u32 = snapshot_field(&record, 1, "version").map_err(with_line(line))?;
+        if version == 0 || version > SNAPSHOT_VERSION {
+            return Err(format!("Unsupported snapshot version {}", version));
+        }
+        let scale: u64 = match record.get(2) {
+            Some(_) => snapshot_field(&record, 2, "scale").map_err(with_line(line))?,
+            None => SCALE,
+        };
+        if scale == 0 {
+            return Err(with_line(line)("invalid scale 0".to_string()));
+        }
+        if scale != SCALE && !state.config.rescale_snapshot {
+            return Err(format!(
+                "Snapshot has scale {} but amounts use scale {}",
+                scale, SCALE
+            ));
+        }
+
+        let mut current: Option<Account> = None;
+        for (line, record) in records {
+            let record = record.map_err(|e| with_line(line)(e.to_string()))?;
+            match record.get(0) {
+                Some("account") => {
+                    let account = Account::from_snapshot(&record).map_err(with_line(line))?;
+                    if let Some(mut previous) = current.take() {
+                        previous.rescale(scale, SCALE).map_err(with_line(line))?;
+                        state.accounts.insert(previous.client, previous);
+                    }
+                    if state.accounts.contains_key(&account.client) {
+                        return Err(with_line(line)(format!(
+                            "client {} appears more than once",
+                            account.client
+                        )));
+                    }
+                    current = Some(account);
+                }
+                Some("deposit") => {
+                    let account = current
+                        .as_mut()
+                        .ok_or_else(|| with_line(line)("deposit before any account".to_string()))?;
+                    account
+                        .deposit_from_snapshot(&record)
+                        .map_err(with_line(line))?;
+                }
+                kind => {
+                    return Err(with_line(line)(format!(
+                        "unknown row type {:?}",
+                        kind.unwrap_or("")
+                    )))
+                }
+            }
+        }
+        if let Some(mut account) = current {
+            account.rescale(scale, SCALE)?;
+            state.accounts.insert(account.client, account);
+        }
+        state.next_first_seen = state
+            .accounts
+            .values()
+            .map(|account| account.first_seen + 1)
+            .max()
+            .unwrap_or(0);
+        Ok(state)
+    }
+}
+
+impl Default for State {
+    fn default() -> Self {
+        StateBuilder::new().build()
+    }
+}
+
+impl State {
+    /// Create an empty `State`.
+    pub fn new() -> Self {
+        State::default()
+    }
+
+    /// Update `State` based on a `Transaction`.
+    ///
+    /// # Panics
+    ///
+    /// If spilling accounts to disk is enabled and reading or writing a spilled
+    /// account fails.
+    pub fn handle_transaction(
+        &mut self,
+        mut transaction: Transaction,
+    ) -> Result<(), TransactionError> {
+        let client = transaction.client;
+        self.check_allowed(&transaction)?;
+        if let (Some(budget), Action::Deposit(_)) = (self.config.max_memory, transaction.detail) {
+            let new_account = if self.accounts.contains_key(&client) {
+                0
+            } else {
+                ACCOUNT_BYTES
+            };
+            if self.estimated_memory() + new_account + DEPOSIT_BYTES > budget {
+                return Err(TransactionError::MemoryBudgetExceeded {
+                    budget,
+                    transaction,
+                });
+            }
+        }
+        if let Some(key) = &transaction.idempotency_key {
+            if self.idempotency_keys.lock().unwrap().contains(key) {
+                report!(
+                    Verbosity::Normal,
+                    "Skipping retry of transaction: {:?}",
+                    transaction
+                );
+                return Err(TransactionError::DuplicateKey(key.clone()));
+            }
         }
+        self.check_client_known(&transaction)?;
+        let key = transaction.idempotency_key.take();
+        let spill = &mut self.spill;
+        let next_first_seen = &mut self.next_first_seen;
+        let account = self.accounts.entry(client).or_insert_with(|| {
+            spill
+                .as_mut()
+                .and_then(|spill| spill.take(client).expect("failed to read spilled account"))
+                .unwrap_or_else(|| {
+                    *next_first_seen += 1;
+                    Account::new(client, *next_first_seen - 1)
+                })
+        });
+        let deposits = account.transactions.len();
+        let result = account.handle_transaction(transaction, &self.config);
+        self.recorded_deposits += account.transactions.len() - deposits;
+        if let Some(spill) = &mut self.spill {
+            spill.touch(client);
+            spill
+                .evict(&mut self.accounts)
+                .expect("failed to spill account");
+        }
+        if let (Ok(()), Some(key)) = (&result, key) {
+            self.idempotency_keys.lock().unwrap().insert(key);
+        }
+        if let (Err(TransactionError::DuplicateTx(tx)), DuplicateTx::Error) =
+            (&result, self.config.duplicate_tx)
+        {
+            report!(
+                Verbosity::Normal,
+                "Rejected duplicate transaction {} for client {}",
+                tx,
+                client
+            );
+            self.duplicates += 1;
+        }
+        result
+    }
+
+    /// The number of transactions rejected as duplicates with
+    /// `DuplicateTx::Error`.
+    pub fn duplicates(&self) -> usize {
+        self.duplicates
+    }
+
+    /// Check the policies that reject a transaction by its type or amount
+    /// alone.
+    fn check_allowed(&self, transaction: &Transaction) -> Result<(), TransactionError> {
+        let type_name = transaction.detail.type_name();
+        if self.config.disabled_types.iter().any(|t| t == type_name) {
+            return Err(TransactionError::Disabled(transaction.clone()));
+        }
+        if let (
+            Some(AmountValidator(validate)),
+            Action::Deposit(amount) | Action::Withdrawal(amount),
+        ) = (&self.config.amount_validator, transaction.detail)
+        {
+            if let Err(reason) = validate(amount) {
+                return Err(TransactionError::InvalidAmount {
+                    reason,
+                    transaction: transaction.clone(),
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// Reject transactions that need the client to have an account when it
+    /// doesn't.
+    fn check_client_known(&self, transaction: &Transaction) -> Result<(), TransactionError> {
+        let client = transaction.client;
+        let is_deposit = matches!(transaction.detail, Action::Deposit(_));
+        if self.config.require_deposit_first && !is_deposit && !self.exists(client) {
+            return Err(TransactionError::NoPriorDeposit(transaction.clone()));
+        }
+        // These can only refer to an earlier deposit, so a client without an
+        // account can't have it. Fail without creating an empty account that
+        // would then show up in the output.
+        let refers_to_deposit = matches!(
+            transaction.detail,
+            Action::Dispute | Action::Resolve | Action::ChargeBack | Action::ReverseChargeback
+        );
+        if refers_to_deposit && !self.exists(client) {
+            return Err(TransactionError::UnknownTx(transaction.tx));
+        }
+        Ok(())
+    }
+
+    /// Preview the effect of `transaction`: apply it to a copy of the
+    /// client's account and return the resulting balances, or the error it
+    /// would be rejected with, leaving the state unchanged. Idempotency keys
+    /// and `Config::max_memory` aren't checked.
+    pub fn simulate(&self, transaction: &Transaction) -> Result<AccountView, TransactionError> {
+        self.check_allowed(transaction)?;
+        self.check_client_known(transaction)?;
+        let client = transaction.client;
+        let mut account = match self.get(client) {
+            Some(account) => account.into_owned(),
+            None => Account::new(client, self.next_first_seen),
+        };
+        account.handle_transaction(transaction.clone(), &self.config)?;
+        Ok(account.view())
+    }
+
+    /// Append `transaction` to the write-ahead log `wal` and sync it before
+    /// applying it with `State::handle_transaction`, so that after a crash
+    /// `wal::replay` can rebuild the state from every logged transaction.
+    /// The transaction isn't applied if it can't be logged.
+    pub fn apply_with_wal<W: SyncWrite>(
+        &mut self,
+        transaction: Transaction,
+        wal: &mut W,
+    ) -> io::Result<Result<(), TransactionError>> {
+        wal::append(wal, &transaction)?;
+        Ok(self.handle_transaction(transaction))
+    }
+
+    /// Apply `transactions` in order with `State::handle_transaction`,
+    /// returning the result of each at the same index, so that callers can
+    /// respond to every transaction of a batch.
+    pub fn handle_batch(
+        &mut self,
+        transactions: Vec<Transaction>,
+    ) -> Vec<Result<(), TransactionError>> {
+        transactions
+            .into_iter()
+            .map(|transaction| self.handle_transaction(transaction))
+            .collect()
+    }
+
+    /// A coarse estimate of the bytes used by the accounts and their recorded
+    /// deposits, ignoring spilled accounts.
+    pub fn estimated_memory(&self) -> usize {
+        self.accounts.len() * ACCOUNT_BYTES + self.recorded_deposits * DEPOSIT_BYTES
+    }
+
+    /// Whether the client has an account, in memory or spilled to disk.
+    fn exists(&self, client: Client) -> bool {
+        self.accounts.contains_key(&client)
+            || self
+                .spill
+                .as_ref()
+                .is_some_and(|spill| spill.is_spilled(client))
+    }
+
+    /// Get an account whether it is in memory or spilled to disk.
+    fn get(&self, client: Client) -> Option<Cow<'_, Account>> {
+        match (self.accounts.get(&client), &self.spill) {
+            (Some(account), _) => Some(Cow::Borrowed(account)),
+            (None, Some(spill)) if spill.is_spilled(client) => Some(Cow::Owned(
+                spill.load(client).expect("failed to read spilled account"),
+            )),
+            (None, _) => None,
+        }
+    }
+
+    /// The current state of a client's account, if it exists.
+    pub fn account(&self, client: Client) -> Option<AccountView> {
+        self.get(client).map(|account| account.view())
+    }
+
+    /// Combine with a `State` holding a disjoint set of clients. The accounts
+    /// of `other` are ordered after those of `self` by `first_seen`, so it
+    /// stays unique, but it doesn't interleave them as applying all the
+    /// transactions to one `State` would.
+    pub fn merge(&mut self, other: State) {
+        let offset = self.next_first_seen;
+        self.accounts
+            .extend(other.accounts.into_iter().map(|(client, mut account)| {
+                account.first_seen += offset;
+                (client, account)
+            }));
+        self.next_first_seen += other.next_first_seen;
+        self.recorded_deposits += other.recorded_deposits;
+        if !Arc::ptr_eq(&self.idempotency_keys, &other.idempotency_keys) {
+            let keys = other.idempotency_keys.lock().unwrap();
+            self.idempotency_keys
+                .lock()
+                .unwrap()
+                .extend(keys.iter().cloned());
+        }
+        self.duplicates += other.duplicates;
+    }
+
+    fn sorted_accounts(&self) -> impl Iterator<Item = Cow<'_, Account>> + '_ {
+        let spilled = self.spill.iter().flat_map(SpillStore::spilled);
+        let mut clients: Vec<Client> = self.accounts.keys().copied().chain(spilled).collect();
+        // Sort by client so the output doesn't depend on the order of iterating through
+        // the map (which isn't stable).
+        clients.sort();
+        // Spilled accounts are only read back one at a time.
+        clients
+            .into_iter()
+            .filter_map(move |client| self.get(client))
+    }
+
+    /// Iterate over views of all accounts, sorted by client. This can be
+    /// used to serialize the accounts in formats other than CSV.
+    pub fn iter_accounts(&self) -> impl Iterator<Item = AccountView> + '_ {
+        self.sorted_accounts().map(|account| account.view())
+    }
+
+    /// Iterate over views of all accounts in the order they're stored, which
+    /// isn't deterministic.
+    pub fn iter_accounts_unsorted(&self) -> impl Iterator<Item = AccountView> + '_ {
+        let spilled = self
+            .spill
+            .iter()
+            .flat_map(SpillStore::spilled)
+            .filter_map(move |client| self.get(client));
+        self.accounts
+            .values()
+            .map(Cow::Borrowed)
+            .chain(spilled)
+            .map(|account| account.view())
+    }
+
+    /// Views of all accounts in the given order.
+    pub fn accounts_sorted_by(&self, sort_by: SortBy) -> Vec<AccountView> {
+        match sort_by {
+            SortBy::Unsorted => self.iter_accounts_unsorted().collect(),
+            SortBy::Client => self.accounts_snapshot(),
+            SortBy::FirstSeen => {
+                let mut accounts: Vec<_> = self
+                    .sorted_accounts()
+                    .map(|account| (account.first_seen, account.view()))
+                    .collect();
+                // Accounts from older snapshots may share a `first_seen`, in
+                // which case they stay in client order.
+                accounts.sort_by_key(|(first_seen, _)| *first_seen);
+                accounts.into_iter().map(|(_, view)| view).collect()
+            }
+            // The client is part of the key so that ties don't depend on the
+            // order of the accounts before sorting.
+            SortBy::Total => {
+                let mut accounts = self.accounts_snapshot();
+                accounts.sort_unstable_by_key(|view| (Reverse(view.total()), view.client));
+                accounts
+            }
+            SortBy::Held => {
+                let mut accounts = self.accounts_snapshot();
+                accounts.sort_unstable_by_key(|view| (Reverse(view.held), view.client));
+                accounts
+            }
+        }
+    }
+
+    /// Views of all accounts, sorted by client.
+    pub fn accounts_snapshot(&self) -> Vec<AccountView> {
+        self.iter_accounts().collect()
+    }
+
+    /// Display the state of all accounts as a CSV, formatted according to
+    /// `options`.
+    pub fn write_csv<W: Write>(
+        &self,
+        writer: &mut Writer<W>,
+        options: &OutputOptions,
+    ) -> csv::Result<()> {
+        match options.sort_by {
+            SortBy::Client => write_accounts_csv(writer, self.iter_accounts(), options),
+            SortBy::Unsorted => write_accounts_csv(writer, self.iter_accounts_unsorted(), options),
+            sort_by => write_accounts_csv(writer, self.accounts_sorted_by(sort_by), options),
+        }
+    }
+
+    /// Write the full state of every account, including the recorded
+    /// deposits, so that it can be loaded again with
+    /// `StateBuilder::load_snapshot`. The policies in `Config` aren't saved.
+    ///
+    /// The first row is `version,<SNAPSHOT_VERSION>,<SCALE>`. Each account is then
+    /// written as a row
+    /// `account,<client>,<available>,<held>,<locked>,<sequence>,<first_seen>,<locked_by>,<locked_at>,<charged_back>,<withdrawals_blocked>,<peak_held>`
+    /// followed by a row
+    /// `deposit,<client>,<tx>,<amount>,<under_dispute>,<disputed_at>,<owed>,<remaining>`
+    /// for each deposit. Amounts are the raw internal values.
+    pub fn save_snapshot<W: Write>(&self, writer: W) -> csv::Result<()> {
+        let mut writer = WriterBuilder::new().flexible(true).from_writer(writer);
+        writer.write_record([
+            "version".to_string(),
+            SNAPSHOT_VERSION.to_string(),
+            SCALE.to_string(),
+        ])?;
+        for account in self.sorted_accounts() {
+            account.write_snapshot(&mut writer)?;
+        }
+        writer.flush()?;
+        Ok(())
+    }
+
+    /// Check the invariant of every account (see `Account`), returning a
+    /// description of each violation.
+    pub fn check_invariants(&self) -> Vec<String> {
+        let mut violations = Vec::new();
+        for account in self.sorted_accounts() {
+            for (tx, detail) in &account.transactions {
+                if detail.owed > detail.amount {
+                    violations.push(format!(
+                        "Client {}: transaction {} owes {} but its amount is {}",
+                        account.client, tx, detail.owed, detail.amount
+                    ));
+                }
+                if detail.remaining > detail.amount {
+                    violations.push(format!(
+                        "Client {}: transaction {} has {} remaining but its amount is {}",
+                        account.client, tx, detail.remaining, detail.amount
+                    ));
+                }
+            }
+            if account.locked {
+                continue;
+            }
+            let disputed: u64 = account
+                .transactions
+                .values()
+                .filter(|detail| detail.under_dispute)
+                .map(|detail| detail.amount.saturating_sub(detail.owed))
+                .sum();
+            if disputed != account.held {
+                violations.push(format!(
+                    "Client {}: held is {} but disputed transactions total {}",
+                    account.client, account.held, disputed
+                ));
+            }
+            let owed = account.transactions.values().any(|detail| detail.owed > 0);
+            if owed && account.available > 0 {
+                violations.push(format!(
+                    "Client {}: funds are available while disputes are still owed",
+                    account.client
+                ));
+            }
+        }
+        violations
+    }
+
+    /// Write the dispute events recorded with `Config::record_dispute_events`
+    /// as CSV rows `client,tx,event`, grouped by client and in the order they
+    /// happened for each client. The events are `opened`, `resolved`,
+    /// `expired` (resolved by `Config::dispute_timeout`), `charged_back` and
+    /// `reversed` (see `Action::ReverseChargeback`).
+    pub fn write_dispute_log<W: Write>(&self, writer: W) -> csv::Result<()> {
+        let mut writer = Writer::from_writer(writer);
+        writer.write_record(["client", "tx", "event"])?;
+        for account in self.sorted_accounts() {
+            for (tx, event) in &account.dispute_events {
+                writer.write_record(&[
+                    account.client.to_string(),
+                    tx.to_string(),
+                    event.name().to_string(),
+                ])?;
+            }
+        }
+        writer.flush()?;
+        Ok(())
+    }
+
+    /// Debugging aid: dump the internal state of every account, including
+    /// each recorded deposit and whether it is under dispute. Amounts are the
+    /// raw internal values (number of 1/10_000's). The format is sorted by
+    /// client and then by transaction so that dumps can be diffed, but it is
+    /// not a stable interface.
+    ///
+    /// Each account is written as a row
+    /// `account,<client>,<available>,<held>,<locked>` followed by a row
+    /// `deposit,<client>,<tx>,<amount>,<under_dispute>` for each deposit.
+    pub fn write_internal_dump<W: Write>(&self, writer: W) -> csv::Result<()> {
+        let mut writer = WriterBuilder::new().flexible(true).from_writer(writer);
+        for account in self.sorted_accounts() {
+            writer.write_record(&[
+                "account".to_string(),
+                account.client.to_string(),
+                account.available.to_string(),
+                account.held.to_string(),
+                account.locked.to_string(),
+            ])?;
+            let mut transactions: Vec<_> = account.transactions.iter().collect();
+            transactions.sort_by_key(|(tx, _)| **tx);
+            for (tx, detail) in transactions {
+                writer.write_record(&[
+                    "deposit".to_string(),
+                    account.client.to_string(),
+                    tx.to_string(),
+                    detail.amount.to_string(),
+                    detail.under_dispute.to_string(),
+                ])?;
+            }
+        }
+        writer.flush()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::output::format_amount;
+    use crate::transaction::TransactionRow;
+    use csv::{ReaderBuilder, Trim, Writer};
+    use std::convert::TryFrom;
+
+    fn read_transactions(s: &str) -> Vec<Transaction> {
+        let mut rdr = ReaderBuilder::new()
+            .trim(Trim::All)
+            .has_headers(false)
+            .from_reader(s.as_bytes());
+        rdr.deserialize()
+            .map(|row| {
+                let row: TransactionRow = row.unwrap();
+                Transaction::try_from(row).unwrap()
+            })
+            .collect()
+    }
+
+    fn apply_transactions(account: &mut Account, transaction_data: &str) {
+        for transaction in read_transactions(transaction_data) {
+            account
+                .handle_transaction(transaction, &Config::default())
+                .unwrap()
+        }
+    }
+
+    fn apply_transactions_to_empty_state(transaction_data: &str) -> Result<String, String> {
+        let mut state = State::new();
+        for transaction in read_transactions(transaction_data) {
+            match state.handle_transaction(transaction) {
+                Ok(()) => (),
+                Err(err) => println!("Error: {}", err),
+            }
+        }
+        let mut vec = Vec::new();
+        {
+            let mut writer = Writer::from_writer(&mut vec);
+            state
+                .write_csv(&mut writer, &OutputOptions::default())
+                .map_err(|e| e.to_string())?;
+        }
+        String::from_utf8(vec).map_err(|e| e.to_string())
+    }
+
+    #[test]
+    fn dispute_for_unknown_client_creates_no_account() {
+        let data = r#"deposit, 1, 1, 5.0
+            dispute, 2, 1,
+            resolve, 3, 1,
+            chargeback, 4, 1,"#;
+        assert_eq!(
+            apply_transactions_to_empty_state(data),
+            Ok(r#"client,available,held,total,locked
+1,5,0,5,false
+"#
+            .to_string())
+        );
+    }
+
+    #[test]
+    fn internal_dump_shows_dispute_flags() {
+        let mut state = State::new();
+        let data = r#"deposit,1,3,5
+        deposit,1,1,2
+        deposit,2,2,1.5
+        dispute,1,3,"#;
+        for transaction in read_transactions(data) {
+            state.handle_transaction(transaction).unwrap();
+        }
+        let mut vec = Vec::new();
+        state.write_internal_dump(&mut vec).unwrap();
+        assert_eq!(
+            String::from_utf8(vec).unwrap(),
+            r#"account,1,20000,50000,false
+deposit,1,1,20000,false
+deposit,1,3,50000,true
+account,2,15000,0,false
+deposit,2,2,15000,false
+"#
+        );
+    }
+
+    fn snapshot_state() -> State {
+        let mut state = StateBuilder::new().partial_hold(true).build();
+        let data = r#"deposit,1,1,5
+        deposit,1,2,2
+        deposit,2,3,1.5
+        withdrawal,2,4,1
+        dispute,1,1,
+        dispute,2,3,"#;
+        for transaction in read_transactions(data) {
+            state.handle_transaction(transaction).unwrap();
+        }
+        state
+    }
+
+    #[test]
+    fn snapshot_round_trip() {
+        let state = snapshot_state();
+        let mut saved = Vec::new();
+        state.save_snapshot(&mut saved).unwrap();
+        assert_eq!(
+            String::from_utf8(saved.clone()).unwrap(),
+            r#"version,8,10000
+account,1,20000,50000,false,3,0,,0,0,false,50000
+deposit,1,1,50000,true,3,0,50000
+deposit,1,2,20000,false,0,0,20000
+account,2,0,5000,false,3,1,,0,0,false,5000
+deposit,2,3,15000,true,3,10000,15000
+"#
+        );
+        let loaded = StateBuilder::new().load_snapshot(&saved[..]).unwrap();
+        assert_eq!(loaded.accounts_snapshot(), state.accounts_snapshot());
+        assert!(loaded.check_invariants().is_empty());
+        let mut resaved = Vec::new();
+        loaded.save_snapshot(&mut resaved).unwrap();
+        assert_eq!(saved, resaved);
+    }
+
+    #[test]
+    fn snapshot_invariant_violations() {
+        let data = r#"version,1
+account,1,20000,40000,false,5
+deposit,1,1,50000,true,3,0
+account,2,10,5000,false,3
+deposit,2,3,15000,true,3,10000
+account,3,0,7,true,1
+"#;
+        let state = StateBuilder::new().load_snapshot(data.as_bytes()).unwrap();
+        assert_eq!(
+            state.check_invariants(),
+            vec![
+                "Client 1: held is 40000 but disputed transactions total 50000".to_string(),
+                "Client 2: funds are available while disputes are still owed".to_string(),
+            ]
+        );
+    }
+
+    #[test]
+    fn malformed_snapshots() {
+        for (data, error) in &[
+            ("", "Snapshot is empty"),
+            ("account,1,0,0,false,0\n", "Line 1: snapshot must start"),
+            ("version,9\n", "Unsupported snapshot version 9"),
+            ("version,8,0\n", "Line 1: invalid scale 0"),
+            (
+                "version,1\naccount,1,-5,0,false,0\n",
+                "Line 2: invalid available",
+            ),
+            (
+                "version,1\ndeposit,1,1,5,false,0,0\n",
+                "Line 2: deposit before",
+            ),
+            (
+                "version,1\naccount,1,0,0,false,0\naccount,1,0,0,false,0\n",
+                "Line 3: client 1 appears more than once",
+            ),
+        ] {
+            let err = StateBuilder::new()
+                .load_snapshot(data.as_bytes())
+                .err()
+                .unwrap();
+            assert!(err.starts_with(error), "{:?} gave {:?}", data, err);
+        }
+    }
+
+    #[test]
+    fn snapshot_at_other_scale() {
+        // Written with two decimals, so 5.25 is 525.
+        let data = r#"version,7,100
+account,1,200,525,false,2,0,,0,0,false
+deposit,1,1,525,true,2,0,525
+deposit,1,2,200,false,0,0,200
+"#;
+        let err = StateBuilder::new()
+            .load_snapshot(data.as_bytes())
+            .err()
+            .unwrap();
+        assert_eq!(err, "Snapshot has scale 100 but amounts use scale 10000");
+
+        let state = StateBuilder::new()
+            .rescale_snapshot(true)
+            .load_snapshot(data.as_bytes())
+            .unwrap();
+        let account = state.account(Client::new(1)).unwrap();
+        assert_eq!(account.available, 20_000);
+        assert_eq!(account.held, 52_500);
+        assert!(state.check_invariants().is_empty());
+
+        let err = StateBuilder::new()
+            .rescale_snapshot(true)
+            .load_snapshot("version,7,100000\naccount,1,5,0,false,0\n".as_bytes())
+            .err()
+            .unwrap();
+        assert_eq!(
+            err,
+            "amount 5 at scale 100000 can't be represented at scale 10000"
+        );
+    }
+
+    #[test]
+    fn spilled_accounts_give_same_output() {
+        let data = r#"deposit,1,1,5
+        deposit,2,2,3
+        deposit,3,3,1
+        deposit,4,4,2
+        dispute,1,1,
+        withdrawal,2,5,1
+        deposit,5,6,7
+        resolve,1,1,
+        dispute,3,3,
+        chargeback,3,3,
+        deposit,3,7,1
+        dispute,4,4,
+        withdrawal,1,8,0.5"#;
+        let dir = std::env::temp_dir().join(format!("atm-spill-test-{}", std::process::id()));
+        let mut in_memory = State::new();
+        let mut spilled = StateBuilder::new().spill(dir.clone(), 2).build();
+        for transaction in read_transactions(data) {
+            assert_eq!(
+                spilled.handle_transaction(transaction.clone()).is_ok(),
+                in_memory.handle_transaction(transaction).is_ok()
+            );
+            assert!(spilled.accounts.len() <= 2);
+        }
+        assert!(spilled.spill.as_ref().unwrap().spilled().count() > 0);
+        assert_eq!(spilled.accounts_snapshot(), in_memory.accounts_snapshot());
+        assert_eq!(
+            spilled.accounts_sorted_by(SortBy::FirstSeen),
+            in_memory.accounts_sorted_by(SortBy::FirstSeen)
+        );
+        assert_eq!(
+            spilled.account(Client::new(1)),
+            in_memory.account(Client::new(1))
+        );
+        let (mut a, mut b) = (Vec::new(), Vec::new());
+        spilled.save_snapshot(&mut a).unwrap();
+        in_memory.save_snapshot(&mut b).unwrap();
+        assert_eq!(String::from_utf8(a), String::from_utf8(b));
+        drop(spilled);
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 0);
+        std::fs::remove_dir(dir).unwrap();
+    }
+
+    #[test]
+    fn sort_by_amount_breaks_ties_by_client() {
+        let data = r#"deposit,4,1,2
+        deposit,2,2,5
+        deposit,3,3,2
+        deposit,1,4,2
+        dispute,3,3,
+        dispute,1,4,"#;
+        let mut state = State::new();
+        for transaction in read_transactions(data) {
+            state.handle_transaction(transaction).unwrap();
+        }
+        let clients = |sort_by| -> Vec<u32> {
+            state
+                .accounts_sorted_by(sort_by)
+                .into_iter()
+                .map(|account| account.client.id())
+                .collect()
+        };
+        assert_eq!(clients(SortBy::Total), vec![2, 1, 3, 4]);
+        assert_eq!(clients(SortBy::Held), vec![1, 3, 2, 4]);
+    }
+
+    #[test]
+    fn sort_by_first_seen() {
+        let data = r#"deposit,3,1,1
+        deposit,1,2,1
+        withdrawal,3,3,1
+        deposit,2,4,1
+        deposit,1,5,1"#;
+        let mut state = State::new();
+        for transaction in read_transactions(data) {
+            state.handle_transaction(transaction).unwrap();
+        }
+        let clients = |sort_by| -> Vec<u32> {
+            state
+                .accounts_sorted_by(sort_by)
+                .into_iter()
+                .map(|account| account.client.id())
+                .collect()
+        };
+        assert_eq!(clients(SortBy::Client), vec![1, 2, 3]);
+        assert_eq!(clients(SortBy::FirstSeen), vec![3, 1, 2]);
+
+        // The order survives a snapshot, and new accounts come after.
+        let mut snapshot = Vec::new();
+        state.save_snapshot(&mut snapshot).unwrap();
+        let mut state = StateBuilder::new().load_snapshot(&snapshot[..]).unwrap();
+        for transaction in read_transactions("deposit,0,6,1") {
+            state.handle_transaction(transaction).unwrap();
+        }
+        let clients: Vec<u32> = state
+            .accounts_sorted_by(SortBy::FirstSeen)
+            .into_iter()
+            .map(|account| account.client.id())
+            .collect();
+        assert_eq!(clients, vec![3, 1, 2, 0]);
+    }
+
+    fn write_with_columns(transaction_data: &str, columns: &str) -> String {
+        let mut state = State::new();
+        for transaction in read_transactions(transaction_data) {
+            let _ = state.handle_transaction(transaction);
+        }
+        let options = OutputOptions {
+            columns: crate::output::parse_columns(columns).unwrap(),
+            ..OutputOptions::default()
+        };
+        let mut vec = Vec::new();
+        state
+            .write_csv(&mut Writer::from_writer(&mut vec), &options)
+            .unwrap();
+        String::from_utf8(vec).unwrap()
+    }
+
+    #[test]
+    fn reduced_columns() {
+        let data = r#"deposit, 2, 1, 1.0
+            deposit, 1, 2, 2.5"#;
+        assert_eq!(
+            write_with_columns(data, "client,total"),
+            r#"client,total
+1,2.5
+2,1
+"#
+        );
+    }
+
+    #[test]
+    fn reordered_columns() {
+        let data = r#"deposit, 1, 1, 3.0
+            dispute, 1, 1,"#;
+        assert_eq!(
+            write_with_columns(data, "client,locked,held,available"),
+            r#"client,locked,held,available
+1,false,3,0
+"#
+        );
+    }
+
+    #[test]
+    fn iter_accounts_matches_csv() {
+        let data = r#"deposit, 2, 1, 1.0
+            deposit, 1, 2, 2.5
+            deposit, 3, 3, 4.0
+            dispute, 3, 3,
+            chargeback, 3, 3,
+            dispute, 1, 2,"#;
+        let mut state = State::new();
+        for transaction in read_transactions(data) {
+            let _ = state.handle_transaction(transaction);
+        }
+        let mut from_iter = String::from("client,available,held,total,locked\n");
+        for view in state.iter_accounts() {
+            from_iter.push_str(&format!(
+                "{},{},{},{},{}\n",
+                view.client,
+                format_amount(view.available),
+                format_amount(view.held),
+                format_amount(view.total()),
+                view.locked
+            ));
+        }
+        assert_eq!(Ok(from_iter), apply_transactions_to_empty_state(data));
+        let clients: Vec<_> = state.iter_accounts().map(|view| view.client).collect();
+        assert_eq!(
+            clients,
+            vec![Client::new(1), Client::new(2), Client::new(3)]
+        );
+    }
+
+    #[test]
+    fn simple_deposit() {
+        let mut account = Account::new(Client::new(1), 0);
+        let data = "deposit,1,3,5";
+        apply_transactions(&mut account, data);
+        assert_eq!(account.held, 0);
+        assert_eq!(account.available, 50_000);
+        assert!(!account.locked);
+        assert_eq!(account.transactions.len(), 1);
+        assert_eq!(
+            account.transactions.get(&Tx::new(3)).unwrap(),
+            &DepositDetail {
+                amount: 50_000,
+                under_dispute: false,
+                disputed_at: 0,
+                owed: 0,
+                remaining: 50_000
+            }
+        );
+    }
+
+    #[test]
+    fn simple_withrawal() {
+        let mut account = Account::new(Client::new(1), 0);
+        let data = r#"deposit,1,3,5
+        withdrawal,1,35,2"#;
+        apply_transactions(&mut account, data);
+        assert_eq!(account.available, 30_000);
+    }
+
+    #[test]
+    fn simple_dispute() {
+        let mut account = Account::new(Client::new(1), 0);
+        let data = r#"deposit,1,3,5
+        dispute,1,3,"#;
+        apply_transactions(&mut account, data);
+        assert_eq!(account.held, 50_000);
+        assert_eq!(account.available, 0);
+        assert!(!account.locked);
+        assert_eq!(
+            account.transactions.get(&Tx::new(3)).unwrap(),
+            &DepositDetail {
+                amount: 50_000,
+                under_dispute: true,
+                disputed_at: 2,
+                owed: 0,
+                remaining: 50_000
+            }
+        );
+    }
+
+    #[test]
+    fn simple_resolve() {
+        let mut account = Account::new(Client::new(1), 0);
+        let data = r#"deposit,1,3,5
+        dispute,1,3,
+        resolve,1,3,"#;
+        apply_transactions(&mut account, data);
+        assert_eq!(account.held, 0);
+        assert_eq!(account.available, 50_000);
+        assert!(!account.locked);
+        assert_eq!(
+            account.transactions.get(&Tx::new(3)).unwrap(),
+            &DepositDetail {
+                amount: 50_000,
+                under_dispute: false,
+                disputed_at: 2,
+                owed: 0,
+                remaining: 50_000
+            }
+        );
+    }
+
+    #[test]
+    fn peak_held_survives_resolve() {
+        let mut account = Account::new(Client::new(1), 0);
+        apply_transactions(
+            &mut account,
+            r#"deposit,1,1,5
+        deposit,1,2,3
+        dispute,1,1,
+        resolve,1,1,
+        dispute,1,2,"#,
+        );
+        assert_eq!(account.held, 30_000);
+        assert_eq!(account.view().peak_held, 50_000);
+        apply_transactions(&mut account, "resolve,1,2,");
+        assert_eq!(account.held, 0);
+        assert_eq!(account.view().peak_held, 50_000);
+    }
+
+    #[test]
+    fn resolve_moves_exactly_the_disputed_amount_back() {
+        for &(amount, other) in &[("0.0001", "0"), ("5", "3.25"), ("123456.789", "0.5")] {
+            let mut account = Account::new(Client::new(1), 0);
+            let data = format!(
+                "deposit,1,1,{}\ndeposit,1,2,{}\ndispute,1,1,",
+                amount, other
+            );
+            apply_transactions(&mut account, &data);
+            let disputed = account.transactions[&Tx::new(1)].amount;
+            let (available, held, total) =
+                (account.available, account.held, account.view().total());
+            apply_transactions(&mut account, "resolve,1,1,");
+            assert_eq!(account.available, available + disputed, "{}", amount);
+            assert_eq!(account.held, held - disputed, "{}", amount);
+            assert_eq!(account.view().total(), total, "{}", amount);
+            assert!(
+                !account.transactions[&Tx::new(1)].under_dispute,
+                "{}",
+                amount
+            );
+        }
+    }
+
+    #[test]
+    fn simple_chargeback() {
+        let mut account = Account::new(Client::new(1), 0);
+        let data = r#"deposit,1,3,5
+        dispute,1,3,
+        chargeback,1,3,"#;
+        apply_transactions(&mut account, data);
+        assert_eq!(account.held, 0);
+        assert_eq!(account.available, 0);
+        assert!(account.locked);
+        assert_eq!(
+            account.transactions.get(&Tx::new(3)).unwrap(),
+            &DepositDetail {
+                amount: 50_000,
+                under_dispute: true,
+                disputed_at: 2,
+                owed: 0,
+                remaining: 50_000
+            }
+        );
+    }
+
+    #[test]
+    fn dispute_times_out() {
+        let mut account = Account::new(Client::new(1), 0);
+        let config = Config {
+            dispute_timeout: Some(2),
+            ..Config::default()
+        };
+        let data = r#"deposit,1,1,5
+        dispute,1,1,
+        deposit,1,2,1
+        deposit,1,3,1"#;
+        for transaction in read_transactions(data) {
+            account.handle_transaction(transaction, &config).unwrap();
+        }
+        // Two transactions since the dispute, so it is still open.
+        assert_eq!(account.held, 50_000);
+        assert_eq!(account.available, 20_000);
+        for transaction in read_transactions("deposit,1,4,1") {
+            account.handle_transaction(transaction, &config).unwrap();
+        }
+        assert_eq!(account.held, 0);
+        assert_eq!(account.available, 80_000);
+        assert!(!account.transactions[&Tx::new(1)].under_dispute);
+    }
+
+    #[test]
+    fn charged_back_dispute_does_not_time_out() {
+        let mut account = Account::new(Client::new(1), 0);
+        let config = Config {
+            dispute_timeout: Some(1),
+            ..Config::default()
+        };
+        let data = r#"deposit,1,1,5
+        dispute,1,1,
+        chargeback,1,1,
+        deposit,1,2,1"#;
+        for transaction in read_transactions(data) {
+            let _ = account.handle_transaction(transaction, &config);
+        }
+        assert!(account.locked);
+        assert_eq!(account.held, 0);
+        assert_eq!(account.available, 0);
+    }
+
+    #[test]
+    fn direct_chargeback_rejected_by_default() {
+        let mut account = Account::new(Client::new(1), 0);
+        apply_transactions(&mut account, "deposit,1,1,5");
+        let chargeback = read_transactions("chargeback,1,1,").pop().unwrap();
+        assert!(account
+            .handle_transaction(chargeback, &Config::default())
+            .is_err());
+        assert_eq!(account.available, 50_000);
+        assert!(!account.locked);
+    }
+
+    #[test]
+    fn direct_chargeback_when_allowed() {
+        let mut account = Account::new(Client::new(1), 0);
+        let config = Config {
+            allow_direct_chargeback: true,
+            ..Config::default()
+        };
+        let data = r#"deposit,1,1,5
+        deposit,1,2,3
+        chargeback,1,1,"#;
+        for transaction in read_transactions(data) {
+            account.handle_transaction(transaction, &config).unwrap();
+        }
+        assert_eq!(account.available, 30_000);
+        assert_eq!(account.held, 0);
+        assert!(account.locked);
+    }
+
+    #[test]
+    fn direct_chargeback_still_works_after_dispute() {
+        let mut account = Account::new(Client::new(1), 0);
+        let config = Config {
+            allow_direct_chargeback: true,
+            ..Config::default()
+        };
+        let data = r#"deposit,1,1,5
+        deposit,1,2,3
+        dispute,1,1,
+        chargeback,1,1,"#;
+        for transaction in read_transactions(data) {
+            account.handle_transaction(transaction, &config).unwrap();
+        }
+        assert_eq!(account.available, 30_000);
+        assert_eq!(account.held, 0);
+        assert!(account.locked);
+    }
+
+    fn partial_hold_account(data: &str) -> Account {
+        let mut account = Account::new(Client::new(1), 0);
+        let config = Config {
+            partial_hold: true,
+            ..Config::default()
+        };
+        for transaction in read_transactions(data) {
+            account.handle_transaction(transaction, &config).unwrap();
+        }
+        account
+    }
+
+    #[test]
+    fn partial_hold_dispute_exceeding_available() {
+        let account = partial_hold_account(
+            r#"deposit,1,1,5
+        withdrawal,1,2,3
+        dispute,1,1,"#,
+        );
+        assert_eq!(account.available, 0);
+        assert_eq!(account.held, 20_000);
+        assert_eq!(account.transactions[&Tx::new(1)].owed, 30_000);
+    }
+
+    #[test]
+    fn partial_hold_later_deposits_settle_owed() {
+        let account = partial_hold_account(
+            r#"deposit,1,1,5
+        withdrawal,1,2,3
+        dispute,1,1,
+        deposit,1,3,1
+        deposit,1,4,4"#,
+        );
+        assert_eq!(account.available, 20_000);
+        assert_eq!(account.held, 50_000);
+        assert_eq!(account.transactions[&Tx::new(1)].owed, 0);
+    }
+
+    fn clamp_account(data: &str) -> (Account, Vec<Result<(), &'static str>>) {
+        let mut account = Account::new(Client::new(1), 0);
+        let config = Config {
+            dispute_clamp: true,
+            ..Config::default()
+        };
+        let results = read_transactions(data)
+            .into_iter()
+            .map(|transaction| {
+                account
+                    .handle_transaction(transaction, &config)
+                    .map_err(|e| e.code())
+            })
+            .collect();
+        (account, results)
+    }
+
+    #[test]
+    fn dispute_clamp_holds_what_is_left() {
+        let (account, results) = clamp_account(
+            r#"deposit,1,1,5
+        withdrawal,1,2,3
+        dispute,1,1,"#,
+        );
+        assert!(results.iter().all(Result::is_ok));
+        assert_eq!((account.available, account.held), (0, 20_000));
+        assert_eq!(account.transactions[&Tx::new(1)].owed, 30_000);
+    }
+
+    #[test]
+    fn dispute_clamp_recovers_shortfall_from_deposits() {
+        let (account, results) = clamp_account(
+            r#"deposit,1,1,5
+        withdrawal,1,2,3
+        dispute,1,1,
+        deposit,1,3,2
+        deposit,1,4,4"#,
+        );
+        assert!(results.iter().all(Result::is_ok));
+        assert_eq!((account.available, account.held), (30_000, 50_000));
+        assert_eq!(account.transactions[&Tx::new(1)].owed, 0);
+    }
+
+    #[test]
+    fn dispute_clamp_only_covers_withdrawals() {
+        // The adjustment isn't a withdrawal, so none of the deposit counts as
+        // withdrawn.
+        let (account, results) = clamp_account(
+            r#"deposit,1,1,5
+        adjust,1,2,-3
+        dispute,1,1,"#,
+        );
+        assert_eq!(results[2], Err("E_INSUFFICIENT_FUNDS"));
+        assert_eq!((account.available, account.held), (20_000, 0));
+
+        // Only 1 of the 3 withdrawn came from the disputed deposit, which
+        // can be clamped but not the 1 taken by the adjustment as well.
+        let (_, results) = clamp_account(
+            r#"deposit,1,1,2
+        deposit,1,2,5
+        withdrawal,1,3,3
+        dispute,1,2,
+        resolve,1,2,
+        adjust,1,4,-1
+        dispute,1,2,"#,
+        );
+        assert_eq!(&results[3..5], &[Ok(()), Ok(())]);
+        assert_eq!(results[6], Err("E_INSUFFICIENT_FUNDS"));
+    }
+
+    #[test]
+    fn partial_hold_resolve_releases_only_held_part() {
+        let account = partial_hold_account(
+            r#"deposit,1,1,5
+        withdrawal,1,2,3
+        dispute,1,1,
+        deposit,1,3,1
+        resolve,1,1,"#,
+        );
+        assert_eq!(account.available, 30_000);
+        assert_eq!(account.held, 0);
+        assert_eq!(account.transactions[&Tx::new(1)].owed, 0);
+    }
+
+    #[test]
+    fn partial_hold_chargeback_takes_held_part() {
+        let account = partial_hold_account(
+            r#"deposit,1,1,5
+        withdrawal,1,2,3
+        dispute,1,1,
+        chargeback,1,1,"#,
+        );
+        assert_eq!(account.available, 0);
+        assert_eq!(account.held, 0);
+        assert!(account.locked);
+    }
+
+    #[test]
+    fn dispute_exceeding_available_rejected_by_default() {
+        let mut account = Account::new(Client::new(1), 0);
+        apply_transactions(&mut account, "deposit,1,1,5\nwithdrawal,1,2,3");
+        let dispute = read_transactions("dispute,1,1,").pop().unwrap();
+        assert!(account
+            .handle_transaction(dispute, &Config::default())
+            .is_err());
+        assert_eq!(account.available, 20_000);
+        assert_eq!(account.held, 0);
+    }
+
+    fn fifo_account(data: &str) -> (Account, Vec<Result<(), TransactionError>>) {
+        let mut account = Account::new(Client::new(1), 0);
+        let config = Config {
+            fifo_disputes: true,
+            ..Config::default()
+        };
+        let results = read_transactions(data)
+            .into_iter()
+            .map(|transaction| account.handle_transaction(transaction, &config))
+            .collect();
+        (account, results)
+    }
+
+    #[test]
+    fn fifo_disputes_fully_withdrawn_deposit() {
+        let (account, results) = fifo_account(
+            r#"deposit,1,1,5
+        deposit,1,2,5
+        withdrawal,1,3,5
+        dispute,1,1,
+        dispute,1,2,"#,
+        );
+        assert_eq!(
+            results[3],
+            Err(TransactionError::FundsWithdrawn {
+                remaining: 0,
+                transaction: read_transactions("dispute,1,1,").pop().unwrap(),
+            })
+        );
+        assert_eq!(results[4], Ok(()));
+        assert_eq!(account.transactions[&Tx::new(1)].remaining, 0);
+        assert_eq!(account.available, 0);
+        assert_eq!(account.held, 50_000);
+    }
+
+    #[test]
+    fn fifo_disputes_partly_withdrawn_deposit() {
+        let (account, results) = fifo_account(
+            r#"deposit,1,1,5
+        deposit,1,2,5
+        withdrawal,1,3,7
+        dispute,1,2,"#,
+        );
+        assert_eq!(results[3].as_ref().unwrap_err().code(), "E_FUNDS_WITHDRAWN");
+        assert_eq!(account.transactions[&Tx::new(1)].remaining, 0);
+        assert_eq!(account.transactions[&Tx::new(2)].remaining, 30_000);
+        assert_eq!(account.available, 30_000);
+    }
+
+    #[test]
+    fn fifo_disputes_skip_disputed_deposits() {
+        let (account, results) = fifo_account(
+            r#"deposit,1,1,5
+        deposit,1,2,5
+        dispute,1,1,
+        withdrawal,1,3,5
+        resolve,1,1,
+        withdrawal,1,4,1
+        dispute,1,1,"#,
+        );
+        assert!(results[..6].iter().all(Result::is_ok));
+        assert_eq!(results[6].as_ref().unwrap_err().code(), "E_FUNDS_WITHDRAWN");
+        assert_eq!(account.transactions[&Tx::new(1)].remaining, 40_000);
+        assert_eq!(account.transactions[&Tx::new(2)].remaining, 0);
+    }
+
+    #[test]
+    fn fully_withdrawn_deposit_disputed_by_default() {
+        let mut account = Account::new(Client::new(1), 0);
+        apply_transactions(
+            &mut account,
+            "deposit,1,1,5\ndeposit,1,2,5\nwithdrawal,1,3,5\ndispute,1,1,",
+        );
+        assert_eq!(account.held, 50_000);
+    }
+
+    #[test]
+    fn disabled_types_are_rejected() {
+        let mut state = StateBuilder::new()
+            .disabled_types(vec!["withdrawal".to_string()])
+            .build();
+        let results: Vec<_> = read_transactions(
+            r#"deposit,1,1,5
+        withdrawal,1,2,1
+        deposit,1,3,1
+        withdrawal,2,4,1"#,
+        )
+        .into_iter()
+        .map(|transaction| state.handle_transaction(transaction).map_err(|e| e.code()))
+        .collect();
+        assert_eq!(
+            results,
+            vec![
+                Ok(()),
+                Err("E_TYPE_DISABLED"),
+                Ok(()),
+                Err("E_TYPE_DISABLED")
+            ]
+        );
+        assert_eq!(state.account(Client::new(1)).unwrap().available, 60_000);
+        // The rejected withdrawal doesn't create an account.
+        assert_eq!(state.account(Client::new(2)), None);
+    }
+
+    #[test]
+    fn lenient_resolve_without_dispute_is_no_op() {
+        let mut account = Account::new(Client::new(1), 0);
+        let config = Config {
+            lenient_resolve: true,
+            ..Config::default()
+        };
+        let mut results = read_transactions("deposit,1,1,5\nresolve,1,1,\nresolve,1,2,")
+            .into_iter()
+            .map(|transaction| account.handle_transaction(transaction, &config));
+        assert_eq!(results.next(), Some(Ok(())));
+        assert_eq!(results.next(), Some(Ok(())));
+        // Resolving a transaction that doesn't exist is still an error.
+        assert_eq!(
+            results.next(),
+            Some(Err(TransactionError::UnknownTx(Tx::new(2))))
+        );
+        drop(results);
+        assert_eq!(account.available, 50_000);
+        assert_eq!(account.held, 0);
     }
 
-    /// Update `State` based on a `Transaction`.
-    pub(crate) fn handle_transaction(&mut self, transaction: Transaction) -> Result<(), String> {
-        let client = transaction.client;
-        let account = self
-            .accounts
-            .entry(client)
-            .or_insert_with(|| Account::new(client));
-        account.handle_transaction(transaction)
+    #[test]
+    fn strict_resolve_without_dispute_is_error() {
+        let mut account = Account::new(Client::new(1), 0);
+        apply_transactions(&mut account, "deposit,1,1,5");
+        let resolve = read_transactions("resolve,1,1,").pop().unwrap();
+        assert_eq!(
+            account.handle_transaction(resolve, &Config::default()),
+            Err(TransactionError::NotDisputed(Tx::new(1)))
+        );
     }
 
-    /// Display the state of all accounts as a CSV.
-    pub(crate) fn write_csv<W: Write>(&self, writer: &mut Writer<W>) -> csv::Result<()> {
-        fn convert_from_thousandths(amount: u64) -> String {
-            format!("{}", (amount as f64) / 10_000.0)
-        }
-        writer.write_record(&["client", "available", "held", "total", "locked"])?;
-        let mut accounts: Vec<_> = self.accounts.values().collect();
-        // Sort by client so the output doesn't depend on the order of iterating through
-        // the map (which isn't stable).
-        accounts.sort_by_key(|a| a.client);
-        for account in accounts {
-            writer.write_record(&[
-                account.client.to_string(),
-                convert_from_thousandths(account.available),
-                convert_from_thousandths(account.held),
-                convert_from_thousandths(account.available + account.held),
-                account.locked.to_string(),
-            ])?;
-        }
-        Ok(())
+    #[test]
+    fn adjustments() {
+        let mut account = Account::new(Client::new(1), 0);
+        apply_transactions(
+            &mut account,
+            "deposit,1,1,5\nadjust,1,2,+1.5\nadjust,1,3,-2",
+        );
+        assert_eq!(account.available, 45_000);
+        // Adjustments aren't recorded, so can't be disputed.
+        assert_eq!(account.transactions.len(), 1);
+        let overdraw = read_transactions("adjust,1,4,-5").pop().unwrap();
+        assert_eq!(
+            account.handle_transaction(overdraw.clone(), &Config::default()),
+            Err(TransactionError::InsufficientFunds(overdraw))
+        );
+        assert_eq!(account.available, 45_000);
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use crate::transaction::TransactionRow;
-    use csv::{ReaderBuilder, Trim, Writer};
-    use std::convert::TryFrom;
-
-    fn read_transactions(s: &str) -> Vec<Transaction> {
-        let mut rdr = ReaderBuilder::new()
-            .trim(Trim::All)
-            .has_headers(false)
-            .from_reader(s.as_bytes());
-        rdr.deserialize()
-            .map(|row| {
-                let row: TransactionRow = row.unwrap();
-                Transaction::try_from(row).unwrap()
-            })
-            .collect()
+    #[test]
+    fn adjustment_of_locked_account() {
+        let mut account = Account::new(Client::new(1), 0);
+        apply_transactions(
+            &mut account,
+            "deposit,1,1,5\ndeposit,1,2,1\ndispute,1,2,\nchargeback,1,2,",
+        );
+        let adjust = read_transactions("adjust,1,3,+1").pop().unwrap();
+        assert_eq!(
+            account.handle_transaction(adjust.clone(), &Config::default()),
+            Err(TransactionError::Locked(adjust.clone()))
+        );
+        let config = Config {
+            allow_locked_adjust: true,
+            ..Config::default()
+        };
+        assert_eq!(account.handle_transaction(adjust, &config), Ok(()));
+        assert_eq!(account.available, 60_000);
+        assert!(account.locked);
     }
 
-    fn apply_transactions(account: &mut Account, transaction_data: &str) {
-        for transaction in read_transactions(transaction_data) {
-            account.handle_transaction(transaction).unwrap()
+    #[test]
+    fn dispute_log_order() {
+        let mut state = StateBuilder::new().record_dispute_events(true).build();
+        let data = r#"deposit,2,1,5
+        deposit,1,2,5
+        dispute,1,2,
+        dispute,2,1,
+        resolve,1,2,
+        resolve,1,2,
+        dispute,1,2,
+        chargeback,1,2,"#;
+        for transaction in read_transactions(data) {
+            let _possible_client_error = state.handle_transaction(transaction);
         }
+        let mut log = Vec::new();
+        state.write_dispute_log(&mut log).unwrap();
+        assert_eq!(
+            String::from_utf8(log).unwrap(),
+            r#"client,tx,event
+1,2,opened
+1,2,resolved
+1,2,opened
+1,2,charged_back
+2,1,opened
+"#
+        );
     }
 
-    fn apply_transactions_to_empty_state(transaction_data: &str) -> Result<String, String> {
+    #[test]
+    fn dispute_log_is_empty_by_default() {
         let mut state = State::new();
-        for transaction in read_transactions(transaction_data) {
-            match state.handle_transaction(transaction) {
-                Ok(()) => (),
-                Err(err) => println!("Error: {}", err),
-            }
-        }
-        let mut vec = Vec::new();
-        {
-            let mut writer = Writer::from_writer(&mut vec);
-            state.write_csv(&mut writer).map_err(|e| e.to_string())?;
+        for transaction in read_transactions("deposit,1,1,5\ndispute,1,1,") {
+            state.handle_transaction(transaction).unwrap();
         }
-        String::from_utf8(vec).map_err(|e| e.to_string())
+        let mut log = Vec::new();
+        state.write_dispute_log(&mut log).unwrap();
+        assert_eq!(String::from_utf8(log).unwrap(), "client,tx,event\n");
     }
 
     #[test]
-    fn simple_deposit() {
-        let mut account = Account::new(Client::new(1));
-        let data = "deposit,1,3,5";
-        apply_transactions(&mut account, data);
-        assert_eq!(account.held, 0);
-        assert_eq!(account.available, 50_000);
-        assert!(!account.locked);
-        assert_eq!(account.transactions.len(), 1);
+    fn amount_validator_rejects_transactions() {
+        let mut state = StateBuilder::new()
+            .amount_validator(Box::new(|amount| {
+                if amount.is_multiple_of(100) {
+                    Ok(())
+                } else {
+                    Err("not a whole number of cents".to_string())
+                }
+            }))
+            .build();
+        let results: Vec<_> = read_transactions(
+            r#"deposit,1,1,5.01
+        deposit,1,2,5.015
+        withdrawal,1,3,0.0001
+        withdrawal,1,4,1"#,
+        )
+        .into_iter()
+        .map(|transaction| state.handle_transaction(transaction).map_err(|e| e.code()))
+        .collect();
         assert_eq!(
-            account.transactions.get(&Tx::new(3)).unwrap(),
-            &DepositDetail {
-                amount: 50_000,
-                under_dispute: false
-            }
+            results,
+            vec![
+                Ok(()),
+                Err("E_INVALID_AMOUNT"),
+                Err("E_INVALID_AMOUNT"),
+                Ok(())
+            ]
         );
+        assert_eq!(state.account(Client::new(1)).unwrap().available, 4_0100);
     }
 
     #[test]
-    fn simple_withrawal() {
-        let mut account = Account::new(Client::new(1));
-        let data = r#"deposit,1,3,5
-        withdrawal,1,35,2"#;
-        apply_transactions(&mut account, data);
-        assert_eq!(account.available, 30_000);
+    fn dispute_authorizer_denies_large_disputes() {
+        let mut state = StateBuilder::new()
+            .dispute_authorizer(Box::new(|_, _, amount| amount <= 10_0000))
+            .build();
+        let results: Vec<_> = read_transactions(
+            r#"deposit,1,1,5
+        deposit,1,2,20
+        deposit,2,3,10
+        dispute,1,1,
+        dispute,1,2,
+        dispute,2,3,"#,
+        )
+        .into_iter()
+        .map(|transaction| state.handle_transaction(transaction).map_err(|e| e.code()))
+        .collect();
+        assert_eq!(
+            results,
+            vec![
+                Ok(()),
+                Ok(()),
+                Ok(()),
+                Ok(()),
+                Err("E_DISPUTE_NOT_AUTHORIZED"),
+                Ok(())
+            ]
+        );
+        let account = state.account(Client::new(1)).unwrap();
+        assert_eq!((account.available, account.held), (20_0000, 5_0000));
+        assert_eq!(state.account(Client::new(2)).unwrap().held, 10_0000);
     }
 
     #[test]
-    fn simple_dispute() {
-        let mut account = Account::new(Client::new(1));
-        let data = r#"deposit,1,3,5
-        dispute,1,3,"#;
-        apply_transactions(&mut account, data);
-        assert_eq!(account.held, 50_000);
-        assert_eq!(account.available, 0);
-        assert!(!account.locked);
+    fn memory_budget_rejects_deposits() {
+        let budget = ACCOUNT_BYTES + 2 * DEPOSIT_BYTES;
+        let mut state = StateBuilder::new().max_memory(Some(budget)).build();
+        let results: Vec<_> = read_transactions(
+            r#"deposit,1,1,5
+        deposit,1,2,5
+        deposit,1,3,5
+        withdrawal,1,4,1
+        deposit,2,5,5"#,
+        )
+        .into_iter()
+        .map(|transaction| state.handle_transaction(transaction).map_err(|e| e.code()))
+        .collect();
         assert_eq!(
-            account.transactions.get(&Tx::new(3)).unwrap(),
-            &DepositDetail {
-                amount: 50_000,
-                under_dispute: true
-            }
+            results,
+            vec![
+                Ok(()),
+                Ok(()),
+                Err("E_MEMORY_BUDGET"),
+                Ok(()),
+                Err("E_MEMORY_BUDGET")
+            ]
         );
+        assert_eq!(state.estimated_memory(), budget);
+        assert_eq!(state.account(Client::new(1)).unwrap().available, 90_000);
     }
 
     #[test]
-    fn simple_resolve() {
-        let mut account = Account::new(Client::new(1));
-        let data = r#"deposit,1,3,5
-        dispute,1,3,
-        resolve,1,3,"#;
-        apply_transactions(&mut account, data);
-        assert_eq!(account.held, 0);
-        assert_eq!(account.available, 50_000);
-        assert!(!account.locked);
-        assert_eq!(
-            account.transactions.get(&Tx::new(3)).unwrap(),
-            &DepositDetail {
-                amount: 50_000,
-                under_dispute: false
-            }
-        );
+    fn max_open_disputes() {
+        let mut account = Account::new(Client::new(1), 0);
+        let config = Config {
+            max_open_disputes: Some(2),
+            ..Config::default()
+        };
+        let data = r#"deposit,1,1,1
+        deposit,1,2,2
+        deposit,1,3,3
+        dispute,1,1,
+        dispute,1,2,"#;
+        for transaction in read_transactions(data) {
+            account.handle_transaction(transaction, &config).unwrap();
+        }
+        assert_eq!(account.open_disputes, 2);
+        assert_eq!(account.held, 30_000);
+
+        let dispute = read_transactions("dispute,1,3,").pop().unwrap();
+        let err = account.handle_transaction(dispute, &config).unwrap_err();
+        assert_eq!(err.code(), "E_TOO_MANY_DISPUTES");
+        assert_eq!(account.held, 30_000);
+        assert_eq!(account.available, 30_000);
+        assert_eq!(account.open_disputes, 2);
+        assert!(!account.transactions[&Tx::new(3)].under_dispute);
+
+        // Resolving one of the disputes makes room for another.
+        for transaction in read_transactions("resolve,1,1,\ndispute,1,3,") {
+            account.handle_transaction(transaction, &config).unwrap();
+        }
+        assert_eq!(account.open_disputes, 2);
+        assert_eq!(account.held, 50_000);
     }
 
     #[test]
-    fn simple_chargeback() {
-        let mut account = Account::new(Client::new(1));
-        let data = r#"deposit,1,3,5
-        dispute,1,3,
-        chargeback,1,3,"#;
-        apply_transactions(&mut account, data);
-        assert_eq!(account.held, 0);
-        assert_eq!(account.available, 0);
-        assert!(account.locked);
+    fn repeated_idempotency_key_is_skipped() {
+        let mut state = State::new();
+        let keyed = |tx, detail, key: &str| Transaction {
+            client: Client::new(1),
+            tx: Tx::new(tx),
+            detail,
+            idempotency_key: Some(key.to_string()),
+        };
+        state
+            .handle_transaction(keyed(1, Action::Deposit(5_0000), "a"))
+            .unwrap();
+        // A retry of "a" with a new tx id.
         assert_eq!(
-            account.transactions.get(&Tx::new(3)).unwrap(),
-            &DepositDetail {
-                amount: 50_000,
-                under_dispute: true
-            }
+            state.handle_transaction(keyed(2, Action::Deposit(5_0000), "a")),
+            Err(TransactionError::DuplicateKey("a".to_string()))
+        );
+        // A rejected transaction doesn't use up its key.
+        assert!(state
+            .handle_transaction(keyed(3, Action::Withdrawal(9_0000), "b"))
+            .is_err());
+        state
+            .handle_transaction(keyed(4, Action::Withdrawal(2_0000), "b"))
+            .unwrap();
+        assert_eq!(
+            state.handle_transaction(keyed(5, Action::Withdrawal(1_0000), "b")),
+            Err(TransactionError::DuplicateKey("b".to_string()))
         );
+        assert_eq!(state.account(Client::new(1)).unwrap().available, 3_0000);
     }
 
     #[test]
@@ -459,6 +2912,292 @@ mod tests {
         );
     }
 
+    #[test]
+    fn reverse_chargeback_within_grace() {
+        let mut state = StateBuilder::new().chargeback_grace(Some(3)).build();
+        let results: Vec<_> = read_transactions(
+            r#"deposit,1,1,5
+        deposit,1,2,3
+        dispute,1,2,
+        dispute,1,1,
+        chargeback,1,1,
+        deposit,1,3,1
+        reverse-chargeback,1,2,
+        reverse-chargeback,1,1,
+        deposit,1,4,1"#,
+        )
+        .into_iter()
+        .map(|transaction| state.handle_transaction(transaction).map_err(|e| e.code()))
+        .collect();
+        assert_eq!(
+            results,
+            vec![
+                Ok(()),
+                Ok(()),
+                Ok(()),
+                Ok(()),
+                Ok(()),
+                Err("E_LOCKED"),
+                Err("E_NOT_CHARGED_BACK"),
+                Ok(()),
+                Ok(())
+            ]
+        );
+        let account = state.account(Client::new(1)).unwrap();
+        assert!(!account.locked);
+        assert_eq!(account.locked_by, None);
+        assert_eq!((account.available, account.held), (60_000, 30_000));
+        assert!(state.check_invariants().is_empty());
+    }
+
+    #[test]
+    fn reverse_chargeback_outside_grace() {
+        for (grace, transactions) in [
+            (
+                Some(1),
+                "deposit,1,2,1\ndeposit,1,3,1\nreverse-chargeback,1,1,",
+            ),
+            (None, "reverse-chargeback,1,1,"),
+        ] {
+            let mut state = StateBuilder::new().chargeback_grace(grace).build();
+            for transaction in read_transactions("deposit,1,1,5\ndispute,1,1,\nchargeback,1,1,") {
+                state.handle_transaction(transaction).unwrap();
+            }
+            let result = read_transactions(transactions)
+                .into_iter()
+                .map(|transaction| state.handle_transaction(transaction))
+                .last()
+                .unwrap();
+            assert_eq!(result.map_err(|e| e.code()), Err("E_CHARGEBACK_FINAL"));
+            assert!(state.account(Client::new(1)).unwrap().locked);
+        }
+    }
+
+    #[test]
+    fn reverse_chargeback_survives_snapshot() {
+        let mut state = StateBuilder::new().chargeback_grace(Some(1)).build();
+        for transaction in read_transactions("deposit,1,1,5\ndispute,1,1,\nchargeback,1,1,") {
+            state.handle_transaction(transaction).unwrap();
+        }
+        let mut snapshot = Vec::new();
+        state.save_snapshot(&mut snapshot).unwrap();
+        let mut state = StateBuilder::new()
+            .chargeback_grace(Some(1))
+            .load_snapshot(&snapshot[..])
+            .unwrap();
+        for transaction in read_transactions("reverse-chargeback,1,1,") {
+            state.handle_transaction(transaction).unwrap();
+        }
+        let account = state.account(Client::new(1)).unwrap();
+        assert_eq!((account.available, account.locked), (50_000, false));
+    }
+
+    #[test]
+    fn batch_results_are_aligned_with_transactions() {
+        let mut state = State::new();
+        let results = state.handle_batch(read_transactions(
+            r#"deposit,1,1,5
+        withdrawal,1,2,10
+        dispute,2,3,
+        deposit,2,4,1
+        dispute,1,1,
+        dispute,1,1,"#,
+        ));
+        assert_eq!(
+            results
+                .iter()
+                .map(|result| result.clone().map_err(|e| e.code()))
+                .collect::<Vec<_>>(),
+            vec![
+                Ok(()),
+                Err("E_INSUFFICIENT_FUNDS"),
+                Err("E_UNKNOWN_TX"),
+                Ok(()),
+                Ok(()),
+                Err("E_ALREADY_DISPUTED"),
+            ]
+        );
+        assert_eq!(state.account(Client::new(1)).unwrap().held, 50_000);
+    }
+
+    #[test]
+    fn simulate_leaves_state_unchanged() {
+        let mut state = State::new();
+        for transaction in read_transactions("deposit,1,1,5") {
+            state.handle_transaction(transaction).unwrap();
+        }
+        let before = state.account(Client::new(1)).unwrap();
+
+        let withdrawal = &read_transactions("withdrawal,1,2,3")[0];
+        let after = state.simulate(withdrawal).unwrap();
+        assert_eq!((after.available, after.held), (20_000, 0));
+
+        let withdrawal = &read_transactions("withdrawal,1,2,6")[0];
+        assert_eq!(
+            state.simulate(withdrawal).map_err(|e| e.code()),
+            Err("E_INSUFFICIENT_FUNDS")
+        );
+        let dispute = &read_transactions("dispute,2,1,")[0];
+        assert_eq!(
+            state.simulate(dispute).map_err(|e| e.code()),
+            Err("E_UNKNOWN_TX")
+        );
+
+        assert_eq!(state.account(Client::new(1)).unwrap(), before);
+        assert!(state.account(Client::new(2)).is_none());
+        // The simulated withdrawal's id is still free.
+        for transaction in read_transactions("withdrawal,1,2,3") {
+            state.handle_transaction(transaction).unwrap();
+        }
+        assert_eq!(state.account(Client::new(1)).unwrap().available, 20_000);
+    }
+
+    #[test]
+    fn overflowing_balances_are_rejected() {
+        let mut state = State::new();
+        let results: Vec<_> = read_transactions(
+            r#"deposit,1,1,1844674407370955.0
+        deposit,1,2,1844674407370955.0
+        dispute,1,1,
+        deposit,1,3,0.1615
+        deposit,1,4,0.0001
+        adjust,1,5,0.0001"#,
+        )
+        .into_iter()
+        .map(|transaction| state.handle_transaction(transaction).map_err(|e| e.code()))
+        .collect();
+        assert_eq!(
+            results,
+            vec![
+                Ok(()),
+                Err("E_OVERFLOW"),
+                Ok(()),
+                Ok(()),
+                Err("E_OVERFLOW"),
+                Err("E_OVERFLOW"),
+            ]
+        );
+        assert_eq!(state.account(Client::new(1)).unwrap().total(), u64::MAX);
+    }
+
+    #[test]
+    fn require_deposit_first() {
+        let mut state = StateBuilder::new().require_deposit_first(true).build();
+        let results: Vec<_> = read_transactions(
+            r#"withdrawal,1,1,1
+        dispute,1,1,
+        deposit,2,2,5
+        withdrawal,2,3,1
+        deposit,1,4,2
+        withdrawal,1,5,1"#,
+        )
+        .into_iter()
+        .map(|transaction| state.handle_transaction(transaction).map_err(|e| e.code()))
+        .collect();
+        assert_eq!(
+            results,
+            vec![
+                Err("E_NO_PRIOR_DEPOSIT"),
+                Err("E_NO_PRIOR_DEPOSIT"),
+                Ok(()),
+                Ok(()),
+                Ok(()),
+                Ok(())
+            ]
+        );
+        assert_eq!(state.account(Client::new(1)).unwrap().available, 10_000);
+
+        let mut state = StateBuilder::new().require_deposit_first(true).build();
+        for transaction in read_transactions("withdrawal,3,1,1") {
+            assert!(state.handle_transaction(transaction).is_err());
+        }
+        assert_eq!(state.iter_accounts().count(), 0);
+    }
+
+    #[test]
+    fn freeze_blocks_withdrawals_only() {
+        let mut state = State::new();
+        let results: Vec<_> = read_transactions(
+            r#"deposit,1,1,5
+        freeze,1,2,
+        deposit,1,3,2
+        withdrawal,1,4,1
+        unfreeze,1,5,
+        withdrawal,1,6,1"#,
+        )
+        .into_iter()
+        .map(|transaction| state.handle_transaction(transaction).map_err(|e| e.code()))
+        .collect();
+        assert_eq!(
+            results,
+            vec![Ok(()), Ok(()), Ok(()), Err("E_FROZEN"), Ok(()), Ok(())]
+        );
+        let account = state.account(Client::new(1)).unwrap();
+        assert_eq!(account.available, 60_000);
+        assert!(!account.withdrawals_blocked);
+        assert!(!account.locked);
+    }
+
+    #[test]
+    fn freeze_survives_snapshot() {
+        let mut state = State::new();
+        for transaction in read_transactions("deposit,1,1,5\nfreeze,1,2,") {
+            state.handle_transaction(transaction).unwrap();
+        }
+        let mut snapshot = Vec::new();
+        state.save_snapshot(&mut snapshot).unwrap();
+        let state = StateBuilder::new().load_snapshot(&snapshot[..]).unwrap();
+        assert!(state.account(Client::new(1)).unwrap().withdrawals_blocked);
+    }
+
+    #[test]
+    fn maximum_ids_survive_snapshot() {
+        let mut state = State::new();
+        for transaction in read_transactions(
+            "deposit,4294967295,4294967295,5\ndeposit,0,0,1\ndispute,4294967295,4294967295,",
+        ) {
+            state.handle_transaction(transaction).unwrap();
+        }
+        let mut snapshot = Vec::new();
+        state.save_snapshot(&mut snapshot).unwrap();
+        let mut state = StateBuilder::new().load_snapshot(&snapshot[..]).unwrap();
+        for transaction in read_transactions("resolve,4294967295,4294967295,") {
+            state.handle_transaction(transaction).unwrap();
+        }
+        let clients: Vec<_> = state.iter_accounts().map(|view| view.client).collect();
+        assert_eq!(clients, vec![Client::new(0), Client::new(u32::MAX)]);
+        assert_eq!(
+            state.account(Client::new(u32::MAX)).unwrap().available,
+            50_000
+        );
+    }
+
+    #[test]
+    fn chargeback_records_locking_tx() {
+        let mut account = Account::new(Client::new(1), 0);
+        apply_transactions(
+            &mut account,
+            "deposit,1,122,5\ndeposit,1,123,10\ndispute,1,122,",
+        );
+        assert_eq!(account.view().locked_by, None);
+        apply_transactions(&mut account, "chargeback,1,122,");
+        assert_eq!(account.view().locked_by, Some(Tx::new(122)));
+    }
+
+    #[test]
+    fn held_check_matches_held() {
+        let mut account = Account::new(Client::new(1), 0);
+        apply_transactions(
+            &mut account,
+            "deposit,1,1,5\ndeposit,1,2,10\ndeposit,1,3,2\ndispute,1,1,\ndispute,1,2,\ndispute,1,3,\nresolve,1,3,",
+        );
+        let view = account.view();
+        assert_eq!(view.held, 150_000);
+        assert_eq!(view.held_check, Some(view.held));
+        apply_transactions(&mut account, "chargeback,1,1,");
+        assert_eq!(account.view().held_check, None);
+    }
+
     #[test]
     fn successfull_dispute() {
         let data = r#"deposit, 1, 122, 5.0
@@ -503,4 +3242,176 @@ mod tests {
             .to_string())
         );
     }
+
+    /// Apply `data` with the `policy` for duplicate deposits, returning the
+    /// error code of each transaction and the state.
+    fn apply_with_duplicate_policy(
+        data: &str,
+        policy: DuplicateTx,
+    ) -> (Vec<Result<(), &'static str>>, State) {
+        let mut state = StateBuilder::new().duplicate_tx(policy).build();
+        let results = read_transactions(data)
+            .into_iter()
+            .map(|transaction| state.handle_transaction(transaction).map_err(|e| e.code()))
+            .collect();
+        (results, state)
+    }
+
+    const REPEATED_DEPOSITS: &str = r#"deposit,1,1,5
+        deposit,1,1,11
+        deposit,1,1,3"#;
+
+    #[test]
+    fn duplicate_tx_ignore_policy() {
+        let (results, state) =
+            apply_with_duplicate_policy(REPEATED_DEPOSITS, "ignore".parse().unwrap());
+        assert_eq!(
+            results,
+            vec![Ok(()), Err("E_DUPLICATE_TX"), Err("E_DUPLICATE_TX")]
+        );
+        assert_eq!(state.account(Client::new(1)).unwrap().available, 50_000);
+        assert_eq!(state.duplicates(), 0);
+    }
+
+    #[test]
+    fn duplicate_tx_update_policy() {
+        let (results, state) =
+            apply_with_duplicate_policy(REPEATED_DEPOSITS, "update".parse().unwrap());
+        assert_eq!(results, vec![Ok(()), Ok(()), Ok(())]);
+        assert_eq!(state.account(Client::new(1)).unwrap().available, 30_000);
+
+        // A dispute holds the updated amount.
+        let (results, state) = apply_with_duplicate_policy(
+            r#"deposit,1,1,5
+            deposit,1,1,11
+            dispute,1,1,
+            deposit,1,1,2
+            resolve,1,1,
+            withdrawal,1,2,4
+            deposit,1,1,2"#,
+            DuplicateTx::Update,
+        );
+        assert_eq!(
+            results,
+            vec![
+                Ok(()),
+                Ok(()),
+                Ok(()),
+                Err("E_ALREADY_DISPUTED"),
+                Ok(()),
+                Ok(()),
+                Err("E_INSUFFICIENT_FUNDS"),
+            ]
+        );
+        let account = state.account(Client::new(1)).unwrap();
+        assert_eq!((account.available, account.held), (70_000, 0));
+    }
+
+    #[test]
+    fn duplicate_tx_error_policy() {
+        let (results, state) =
+            apply_with_duplicate_policy(REPEATED_DEPOSITS, "error".parse().unwrap());
+        assert_eq!(
+            results,
+            vec![Ok(()), Err("E_DUPLICATE_TX"), Err("E_DUPLICATE_TX")]
+        );
+        assert_eq!(state.account(Client::new(1)).unwrap().available, 50_000);
+        assert_eq!(state.duplicates(), 2);
+        assert!("replace".parse::<DuplicateTx>().is_err());
+    }
+
+    #[test]
+    fn lenient_resolve_of_charged_back_transaction_rejected() {
+        let mut state = StateBuilder::new().lenient_post_lock(true).build();
+        let data = r#"deposit,1,1,10
+        deposit,1,2,5
+        dispute,1,1,
+        chargeback,1,1,
+        resolve,1,1,"#;
+        let results: Vec<_> = read_transactions(data)
+            .into_iter()
+            .map(|transaction| state.handle_transaction(transaction))
+            .collect();
+        assert!(results[..4].iter().all(Result::is_ok));
+        assert!(matches!(results[4], Err(TransactionError::Locked(_))));
+        let account = &state.accounts[&Client::new(1)];
+        assert_eq!(account.available, 50_000);
+        assert_eq!(account.held, 0);
+        assert!(account.locked);
+    }
+}
+
+#[cfg(test)]
+mod post_lock_tests {
+    use super::*;
+
+    /// A locked account where `held` has drifted below the amount of a
+    /// transaction that is still under dispute.
+    fn locked_account() -> Account {
+        let mut account = Account::new(Client::new(1), 0);
+        account.available = 10_000;
+        account.held = 20_000;
+        account.locked = true;
+        account.open_disputes = 1;
+        account.transactions.insert(
+            Tx::new(1),
+            DepositDetail {
+                amount: 30_000,
+                under_dispute: true,
+                disputed_at: 0,
+                owed: 0,
+                remaining: 30_000,
+            },
+        );
+        account
+    }
+
+    fn resolve() -> Transaction {
+        Transaction {
+            client: Client::new(1),
+            tx: Tx::new(1),
+            detail: Action::Resolve,
+            idempotency_key: None,
+        }
+    }
+
+    #[test]
+    fn resolve_after_lock_rejected_by_default() {
+        let mut account = locked_account();
+        assert!(account
+            .handle_transaction(resolve(), &Config::default())
+            .is_err());
+        assert_eq!(account.held, 20_000);
+        assert_eq!(account.available, 10_000);
+    }
+
+    #[test]
+    fn lenient_resolve_after_lock_clamps_held() {
+        let mut account = locked_account();
+        let config = Config {
+            lenient_post_lock: true,
+            ..Config::default()
+        };
+        account.handle_transaction(resolve(), &config).unwrap();
+        assert_eq!(account.held, 0);
+        assert_eq!(account.available, 40_000);
+        assert!(account.locked);
+        assert!(!account.transactions[&Tx::new(1)].under_dispute);
+    }
+
+    #[test]
+    fn lenient_post_lock_still_rejects_other_transactions() {
+        let mut account = locked_account();
+        let config = Config {
+            lenient_post_lock: true,
+            ..Config::default()
+        };
+        let deposit = Transaction {
+            client: Client::new(1),
+            tx: Tx::new(2),
+            detail: Action::Deposit(1),
+            idempotency_key: None,
+        };
+        assert!(account.handle_transaction(deposit, &config).is_err());
+    }
 }
@@ -0,0 +1,128 @@
+//! On-disk storage for accounts that haven't been used recently, so that the
+//! number of accounts held in memory is bounded.
+use super::Account;
+use crate::transaction::Client;
+use csv::{ReaderBuilder, WriterBuilder};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    fs, io,
+    path::PathBuf,
+};
+
+/// Keeps at most `capacity` accounts in memory, writing the least recently
+/// used ones to a file per client in `dir` (in the snapshot format, see
+/// `State::save_snapshot`). Files are removed when they're read back and when
+/// the store is dropped.
+#[derive(Debug)]
+pub(super) struct SpillStore {
+    dir: PathBuf,
+    capacity: usize,
+    /// Clients whose account is currently on disk rather than in memory.
+    spilled: HashSet<Client>,
+    /// When each client in memory was last used, and the reverse mapping so
+    /// the least recently used can be found quickly.
+    last_used: HashMap<Client, u64>,
+    by_age: BTreeMap<u64, Client>,
+    clock: u64,
+}
+
+fn invalid_data(err: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, err)
+}
+
+impl SpillStore {
+    pub(super) fn new(dir: PathBuf, capacity: usize) -> Self {
+        SpillStore {
+            dir,
+            capacity: capacity.max(1),
+            spilled: HashSet::new(),
+            last_used: HashMap::new(),
+            by_age: BTreeMap::new(),
+            clock: 0,
+        }
+    }
+
+    fn path(&self, client: Client) -> PathBuf {
+        self.dir.join(format!("{}.csv", client))
+    }
+
+    /// The clients whose accounts are on disk.
+    pub(super) fn spilled(&self) -> impl Iterator<Item = Client> + '_ {
+        self.spilled.iter().copied()
+    }
+
+    pub(super) fn is_spilled(&self, client: Client) -> bool {
+        self.spilled.contains(&client)
+    }
+
+    /// Mark `client` as the most recently used.
+    pub(super) fn touch(&mut self, client: Client) {
+        self.clock += 1;
+        if let Some(previous) = self.last_used.insert(client, self.clock) {
+            self.by_age.remove(&previous);
+        }
+        self.by_age.insert(self.clock, client);
+    }
+
+    /// Read a spilled account without moving it back into memory.
+    pub(super) fn load(&self, client: Client) -> io::Result<Account> {
+        let mut reader = ReaderBuilder::new()
+            .has_headers(false)
+            .flexible(true)
+            .from_path(self.path(client))?;
+        let mut records = reader.records();
+        let first = records
+            .next()
+            .ok_or_else(|| invalid_data(format!("spill file for client {} is empty", client)))??;
+        let mut account = Account::from_snapshot(&first).map_err(invalid_data)?;
+        for record in records {
+            account
+                .deposit_from_snapshot(&record?)
+                .map_err(invalid_data)?;
+        }
+        Ok(account)
+    }
+
+    /// Remove `client`'s account from disk if it was spilled.
+    pub(super) fn take(&mut self, client: Client) -> io::Result<Option<Account>> {
+        if !self.is_spilled(client) {
+            return Ok(None);
+        }
+        let account = self.load(client)?;
+        fs::remove_file(self.path(client))?;
+        self.spilled.remove(&client);
+        Ok(Some(account))
+    }
+
+    /// Write the least recently used accounts to disk until no more than
+    /// `capacity` remain in memory.
+    pub(super) fn evict(&mut self, accounts: &mut HashMap<Client, Account>) -> io::Result<()> {
+        while accounts.len() > self.capacity {
+            let (_, client) = match self.by_age.pop_first() {
+                Some(oldest) => oldest,
+                None => return Ok(()),
+            };
+            self.last_used.remove(&client);
+            let account = match accounts.remove(&client) {
+                Some(account) => account,
+                None => continue,
+            };
+            fs::create_dir_all(&self.dir)?;
+            let mut writer = WriterBuilder::new()
+                .flexible(true)
+                .from_path(self.path(client))?;
+            account.write_snapshot(&mut writer)?;
+            writer.flush()?;
+            self.spilled.insert(client);
+        }
+        Ok(())
+    }
+}
+
+impl Drop for SpillStore {
+    fn drop(&mut self) {
+        for client in self.spilled.iter() {
+            let _ = fs::remove_file(self.path(*client));
+        }
+    }
+}
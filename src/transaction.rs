@@ -1,9 +1,11 @@
-use serde::Deserialize;
-use std::{convert::TryFrom, fmt::Display};
+use crate::output::format_amount;
+use csv::StringRecord;
+use serde::{Deserialize, Serialize};
+use std::{collections::HashSet, convert::TryFrom, fmt::Display};
 
 /// Unique identifier for a client.
-#[derive(Copy, Clone, Debug, Eq, Hash, Ord, PartialEq, PartialOrd, Deserialize)]
-pub(crate) struct Client(u16);
+#[derive(Copy, Clone, Debug, Eq, Hash, Ord, PartialEq, PartialOrd, Deserialize, Serialize)]
+pub struct Client(u32);
 
 impl Display for Client {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
@@ -12,26 +14,34 @@ impl Display for Client {
 }
 
 impl Client {
-    #[cfg(test)]
-    pub fn new(id: u16) -> Self {
+    pub fn new(id: u32) -> Self {
         Client(id)
     }
+
+    pub fn id(self) -> u32 {
+        self.0
+    }
 }
 
 /// Unique identifier for a transaction.
-#[derive(Clone, Copy, Debug, Deserialize, Eq, Hash, PartialEq)]
-pub(crate) struct Tx(u32);
+#[derive(Clone, Copy, Debug, Deserialize, Eq, Hash, Ord, PartialEq, PartialOrd, Serialize)]
+pub struct Tx(u32);
+
+impl Display for Tx {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
 
 impl Tx {
-    #[cfg(test)]
     pub fn new(id: u32) -> Self {
         Tx(id)
     }
 }
 
 /// Description of the action a transaction would like to perform.
-#[derive(Debug, PartialEq)]
-pub(crate) enum Action {
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Action {
     /// Amounts for Deposits are `u64`s representing the number of 1/10_000's.
     Deposit(u64),
     /// Amounts for Withdrawals are `u64`s representing the number of 1/10_000's.
@@ -39,57 +49,396 @@ pub(crate) enum Action {
     Dispute,
     Resolve,
     ChargeBack,
+    /// Undo the chargeback that locked the account, if it is within
+    /// `Config::chargeback_grace`.
+    ReverseChargeback,
+    /// An administrative hold blocking withdrawals (but not deposits) until
+    /// an `Unfreeze`. Unlike a chargeback lock it can be lifted.
+    Freeze,
+    Unfreeze,
+    /// An admin correction of `available` by a signed number of 1/10_000's.
+    /// Unlike deposits and withdrawals it isn't recorded, so its tx id can't
+    /// be disputed.
+    Adjust(i64),
 }
 
+/// The transaction types, as written in the `type` column.
+pub const TYPES: [&str; 9] = [
+    "deposit",
+    "withdrawal",
+    "dispute",
+    "resolve",
+    "chargeback",
+    "reverse-chargeback",
+    "freeze",
+    "unfreeze",
+    "adjust",
+];
+
 impl Action {
-    fn from_type_and_amount(type_: &str, amount: Option<f64>) -> Result<Action, String> {
-        fn convert_amount(amount: f64) -> u64 {
-            (amount * 10_000.0).round() as u64
+    /// The name of this action's type in the `type` column.
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Action::Deposit(_) => "deposit",
+            Action::Withdrawal(_) => "withdrawal",
+            Action::Dispute => "dispute",
+            Action::Resolve => "resolve",
+            Action::ChargeBack => "chargeback",
+            Action::ReverseChargeback => "reverse-chargeback",
+            Action::Freeze => "freeze",
+            Action::Unfreeze => "unfreeze",
+            Action::Adjust(_) => "adjust",
         }
+    }
+
+    /// Check that `type_` is a known transaction type and that an amount is
+    /// given exactly when the type needs one (deposits, withdrawals and
+    /// adjustments). This doesn't need any account state, so can be used to
+    /// check a file up front.
+    pub fn validate_row(type_: &str, amount: Option<f64>) -> Result<(), String> {
         match (type_, amount) {
-            ("deposit", Some(amount)) => Ok(Action::Deposit(convert_amount(amount))),
-            ("withdrawal", Some(amount)) => Ok(Action::Withdrawal(convert_amount(amount))),
+            ("deposit" | "withdrawal" | "adjust", Some(_)) => Ok(()),
+            (
+                "dispute" | "resolve" | "chargeback" | "reverse-chargeback" | "freeze" | "unfreeze",
+                None,
+            ) => Ok(()),
+            ("deposit" | "withdrawal" | "adjust", None) => {
+                Err(format!("Transaction type {:?} requires an amount", type_))
+            }
+            (
+                "dispute" | "resolve" | "chargeback" | "reverse-chargeback" | "freeze" | "unfreeze",
+                Some(amount),
+            ) => Err(format!(
+                "Transaction type {:?} can't have an amount, got {}",
+                type_, amount
+            )),
+            _ => Err(format!("Invalid transaction type: {:?}", type_)),
+        }
+    }
+
+    pub fn from_type_and_amount(type_: &str, amount: Option<Amount>) -> Result<Action, String> {
+        Action::validate_row(type_, amount.map(Amount::to_f64))?;
+        match (type_, amount) {
+            ("deposit", Some(amount)) => Ok(Action::Deposit(amount.scaled()?)),
+            ("withdrawal", Some(amount)) => Ok(Action::Withdrawal(amount.scaled()?)),
             ("dispute", None) => Ok(Action::Dispute),
             ("resolve", None) => Ok(Action::Resolve),
             ("chargeback", None) => Ok(Action::ChargeBack),
-            other => Err(format!("Invalid transaction type: {:?}", other)),
+            ("reverse-chargeback", None) => Ok(Action::ReverseChargeback),
+            ("freeze", None) => Ok(Action::Freeze),
+            ("unfreeze", None) => Ok(Action::Unfreeze),
+            ("adjust", Some(amount)) => i64::try_from(amount.scaled()?)
+                .map(Action::Adjust)
+                .map_err(|_| format!("Amount {} is too large", amount.to_f64())),
+            other => unreachable!("validate_row accepted {:?}", other),
         }
     }
 }
 
+/// The number of units in 1 of the currency, i.e. amounts are stored as
+/// 1/10_000's.
+pub const SCALE: u64 = 10_000;
+
+/// The number of decimal places that `SCALE` can represent.
+pub const DECIMALS: usize = 4;
+
+/// A parsed amount field, before it is converted to 1/10_000's.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Amount {
+    /// An amount without a decimal separator, in whole units.
+    Integer(u64),
+    /// An amount with a decimal separator, already in 1/10_000's.
+    Decimal(u64),
+}
+
+impl Amount {
+    /// The amount in whole units, for messages.
+    fn to_f64(self) -> f64 {
+        match self {
+            Amount::Integer(amount) => amount as f64,
+            Amount::Decimal(scaled) => scaled as f64 / SCALE as f64,
+        }
+    }
+
+    /// The number of 1/10_000's.
+    fn scaled(self) -> Result<u64, String> {
+        match self {
+            Amount::Integer(amount) => amount
+                .checked_mul(SCALE)
+                .ok_or_else(|| format!("Amount {} is too large", amount)),
+            Amount::Decimal(scaled) => Ok(scaled),
+        }
+    }
+}
+
+/// Options controlling how a `TransactionRow` is converted into a
+/// `Transaction`.
+#[derive(Clone, Debug)]
+pub struct ParseOptions {
+    /// The maximum number of fractional digits allowed in an amount. Amounts
+    /// with more digits are rejected rather than rounded. Digits past
+    /// `DECIMALS` can't be stored, so they're only allowed if they're zeros.
+    pub max_precision: usize,
+    /// The character between the whole and fractional parts of an amount.
+    pub decimal_separator: char,
+    /// Discard the amount of dispute, resolve, chargeback and
+    /// reverse-chargeback rows rather than rejecting them, for feeds that
+    /// fill it in with the amount of the deposit.
+    pub ignore_dispute_amount: bool,
+}
+
+impl Default for ParseOptions {
+    fn default() -> Self {
+        ParseOptions {
+            max_precision: 4,
+            decimal_separator: '.',
+            ignore_dispute_amount: false,
+        }
+    }
+}
+
+/// Parse a raw amount field, rejecting it if it has more fractional digits
+/// than allowed by `options`.
+///
+/// The accepted grammar is an optional leading `+` followed by ASCII digits
+/// with at most one `.` and at least one digit, e.g. `5`, `+5.0`, `5.` or
+/// `.5`, where `.` is `ParseOptions::decimal_separator`. Any other sign,
+/// exponents, `inf`/`nan` and whitespace (including between the digits) are
+/// rejected. Whitespace around the field is removed by
+/// the CSV reader (`Trim::All`) before it gets here.
+fn parse_amount(raw: &str, options: &ParseOptions) -> Result<Amount, String> {
+    let unsigned = raw.strip_prefix('+').unwrap_or(raw);
+    let (whole, fraction) = unsigned
+        .split_once(options.decimal_separator)
+        .unwrap_or((unsigned, ""));
+    let is_digits = |s: &str| s.bytes().all(|b| b.is_ascii_digit());
+    if !is_digits(whole) || !is_digits(fraction) || whole.len() + fraction.len() == 0 {
+        return Err(format!("Invalid amount {:?}", raw));
+    }
+    if fraction.len() > options.max_precision {
+        return Err(format!(
+            "Amount {:?} has more than {} decimal places",
+            raw, options.max_precision
+        ));
+    }
+    if !unsigned.contains(options.decimal_separator) {
+        // Skip the float conversion for the common case of whole amounts.
+        return whole
+            .parse()
+            .map(Amount::Integer)
+            .map_err(|e| format!("Invalid amount {:?}: {}", raw, e));
+    }
+    // Converted with integers rather than through a float, so that large
+    // amounts are exact and ones that don't fit are rejected.
+    let (digits, rest) = fraction.split_at(fraction.len().min(DECIMALS));
+    if rest.bytes().any(|b| b != b'0') {
+        return Err(format!(
+            "Amount {:?} has more than {} decimal places",
+            raw, DECIMALS
+        ));
+    }
+    let too_large = || format!("Amount {:?} is too large", raw);
+    let whole: u64 = if whole.is_empty() {
+        0
+    } else {
+        whole.parse().map_err(|_| too_large())?
+    };
+    let fraction: u64 = format!("{:0<width$}", digits, width = DECIMALS)
+        .parse()
+        .expect("only digits");
+    whole
+        .checked_mul(SCALE)
+        .and_then(|scaled| scaled.checked_add(fraction))
+        .map(Amount::Decimal)
+        .ok_or_else(too_large)
+}
+
+/// Parse a non-negative amount written as in the input (see `parse_amount`)
+/// into a number of 1/10_000's.
+pub fn parse_scaled_amount(raw: &str, options: &ParseOptions) -> Result<u64, String> {
+    parse_amount(raw, options)?.scaled()
+}
+
 /// A single client transaction.
-#[derive(Debug, PartialEq)]
-pub(crate) struct Transaction {
+#[derive(Clone, Debug, PartialEq)]
+pub struct Transaction {
     pub client: Client,
     pub tx: Tx,
     pub detail: Action,
+    /// An external key identifying the logical operation. A transaction with
+    /// the same key as one already accepted is a retry and is skipped, even
+    /// if it has a different `tx`.
+    pub idempotency_key: Option<String>,
 }
 
 /// A row parsed from the CSV. This needs to be converted to
 /// a `Transaction` for use and it may be invalid (e.g. if the type
 /// is `"withdrawal"`, but there is no amount).
 #[derive(Debug, Deserialize, PartialEq)]
-pub(crate) struct TransactionRow {
+pub struct TransactionRow {
     #[serde(rename = "type")]
     type_: String,
     client: Client,
     tx: Tx,
-    amount: Option<f64>,
+    /// Kept as the raw string so the precision can be checked before it is
+    /// converted.
+    amount: Option<String>,
+    /// Optional column, see `Transaction::idempotency_key`. An empty value is
+    /// the same as no key.
+    #[serde(default)]
+    idempotency_key: Option<String>,
 }
 
-impl TryFrom<TransactionRow> for Transaction {
-    type Error = String;
+impl TransactionRow {
+    /// The row as a record with `COLUMNS` and then `OPTIONAL_COLUMNS`, keeping
+    /// the amount exactly as it was written.
+    pub fn to_record(&self) -> StringRecord {
+        StringRecord::from(vec![
+            self.type_.clone(),
+            self.client.to_string(),
+            self.tx.to_string(),
+            self.amount.clone().unwrap_or_default(),
+            self.idempotency_key.clone().unwrap_or_default(),
+        ])
+    }
+}
 
-    fn try_from(value: TransactionRow) -> Result<Self, Self::Error> {
-        let detail = Action::from_type_and_amount(&value.type_, value.amount)?;
+/// The columns of the input, in the order they're expected in a file without
+/// a header row.
+pub const COLUMNS: [&str; 4] = ["type", "client", "tx", "amount"];
+
+/// Columns that are only read if the file has a header row naming them.
+pub const OPTIONAL_COLUMNS: [&str; 1] = ["idempotency_key"];
+
+/// Whether the first row of a file is a header row rather than a transaction,
+/// i.e. every field is the name of a column.
+pub fn looks_like_header(record: &StringRecord) -> bool {
+    !record.is_empty()
+        && record
+            .iter()
+            .map(str::trim)
+            .all(|field| COLUMNS.contains(&field) || OPTIONAL_COLUMNS.contains(&field))
+}
+
+/// The header row to assume for a file that doesn't have one.
+pub fn default_headers() -> StringRecord {
+    StringRecord::from(COLUMNS.to_vec())
+}
+
+/// Check the header row of the input before any rows are read, so that
+/// problems are reported clearly rather than as a confusing error (or silently
+/// misread field) later on.
+pub fn check_headers(headers: &StringRecord) -> Result<(), String> {
+    let mut seen = HashSet::new();
+    for name in headers {
+        if !seen.insert(name) {
+            return Err(format!("duplicate header column: {}", name));
+        }
+    }
+    Ok(())
+}
+
+/// Whether every field of `record` is empty after trimming, i.e. it came from
+/// a blank or whitespace-only line. Such rows are skipped rather than being
+/// errors, which needs a `flexible` reader as they have fewer fields.
+pub fn is_blank(record: &StringRecord) -> bool {
+    record.iter().all(|field| field.trim().is_empty())
+}
+
+/// Where `record` was read from, as ` in record <n> (line <l>)` to append
+/// to an error about it, or empty if it wasn't read by a reader. Both come
+/// from the reader (records are counted from 0 including any header row, as
+/// in the csv crate's own errors) so they stay right after quoted fields
+/// containing newlines. The line is the one the record starts on.
+pub fn record_position(record: &StringRecord) -> String {
+    record.position().map_or(String::new(), |position| {
+        format!(
+            " in record {} (line {})",
+            position.record(),
+            position.line()
+        )
+    })
+}
+
+/// Check that a row that isn't blank has a field for each header.
+pub fn check_field_count(headers: &StringRecord, record: &StringRecord) -> Result<(), String> {
+    if record.len() == headers.len() {
+        return Ok(());
+    }
+    Err(format!(
+        "Expected {} fields but found {}{}",
+        headers.len(),
+        record.len(),
+        record_position(record)
+    ))
+}
+
+impl Transaction {
+    /// Convert a `TransactionRow` using the given `ParseOptions`.
+    /// Errors name the client and tx of the row, as the row itself may not be
+    /// at hand when they're reported.
+    pub fn from_row(value: TransactionRow, options: &ParseOptions) -> Result<Self, String> {
+        let in_context = |err| format!("Client {} tx {}: {}", value.client, value.tx, err);
+        let refers_to_deposit = matches!(
+            value.type_.as_str(),
+            "dispute" | "resolve" | "chargeback" | "reverse-chargeback"
+        );
+        let (amount, negative) = match &value.amount {
+            None => (None, false),
+            Some(_) if options.ignore_dispute_amount && refers_to_deposit => (None, false),
+            // Only adjustments can be negative.
+            Some(raw) => match raw.strip_prefix('-') {
+                Some(magnitude) if value.type_ == "adjust" && !magnitude.starts_with('+') => (
+                    Some(parse_amount(magnitude, options).map_err(in_context)?),
+                    true,
+                ),
+                _ => (Some(parse_amount(raw, options).map_err(in_context)?), false),
+            },
+        };
+        let detail = match Action::from_type_and_amount(&value.type_, amount) {
+            Ok(Action::Adjust(amount)) if negative => Action::Adjust(-amount),
+            detail => detail.map_err(in_context)?,
+        };
         Ok(Transaction {
             client: value.client,
             tx: value.tx,
             detail,
+            idempotency_key: value.idempotency_key,
         })
     }
 }
 
+impl Transaction {
+    /// The transaction as a record with `COLUMNS` and then
+    /// `OPTIONAL_COLUMNS`, with the amount written exactly, so that
+    /// `Transaction::from_row` gives it back.
+    pub fn to_record(&self) -> StringRecord {
+        let amount = match self.detail {
+            Action::Deposit(amount) | Action::Withdrawal(amount) => format_amount(amount),
+            Action::Adjust(amount) if amount < 0 => {
+                format!("-{}", format_amount(amount.unsigned_abs()))
+            }
+            Action::Adjust(amount) => format_amount(amount.unsigned_abs()),
+            _ => String::new(),
+        };
+        StringRecord::from(vec![
+            self.detail.type_name().to_string(),
+            self.client.to_string(),
+            self.tx.to_string(),
+            amount,
+            self.idempotency_key.clone().unwrap_or_default(),
+        ])
+    }
+}
+
+impl TryFrom<TransactionRow> for Transaction {
+    type Error = String;
+
+    fn try_from(value: TransactionRow) -> Result<Self, Self::Error> {
+        Transaction::from_row(value, &ParseOptions::default())
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use csv::ReaderBuilder;
@@ -97,6 +446,18 @@ mod tests {
 
     use super::*;
 
+    #[test]
+    fn row_to_record_keeps_raw_amount() {
+        let headers = default_headers();
+        let row: TransactionRow = StringRecord::from(vec!["deposit", "1", "2", "5.00"])
+            .deserialize(Some(&headers))
+            .unwrap();
+        assert_eq!(
+            row.to_record(),
+            StringRecord::from(vec!["deposit", "1", "2", "5.00", ""])
+        );
+    }
+
     #[test]
     fn read_with_headers() {
         let data = r#"type, client, tx, amount
@@ -114,7 +475,8 @@ mod tests {
                     type_: "deposit".to_string(),
                     client: Client::new(0),
                     tx: Tx::new(1),
-                    amount: Some(2.0),
+                    amount: Some("2".to_string()),
+                    idempotency_key: None,
                 }
             ),
         }
@@ -132,6 +494,103 @@ mod tests {
         transaction_row.try_into()
     }
 
+    #[test]
+    fn duplicate_header_is_error() {
+        let mut rdr = ReaderBuilder::new()
+            .trim(csv::Trim::All)
+            .from_reader("type, client, client, amount\ndeposit, 1, 1, 1".as_bytes());
+        assert_eq!(
+            check_headers(rdr.headers().unwrap()),
+            Err("duplicate header column: client".to_string())
+        );
+    }
+
+    #[test]
+    fn distinct_headers_are_ok() {
+        let mut rdr = ReaderBuilder::new()
+            .trim(csv::Trim::All)
+            .from_reader("type, client, tx, amount".as_bytes());
+        assert_eq!(check_headers(rdr.headers().unwrap()), Ok(()));
+    }
+
+    #[test]
+    fn validate_row_combinations() {
+        for type_ in &["deposit", "withdrawal", "adjust"] {
+            assert_eq!(Action::validate_row(type_, Some(1.0)), Ok(()));
+            assert!(Action::validate_row(type_, None).is_err());
+        }
+        // An adjustment taking funds has a negative amount.
+        assert_eq!(Action::validate_row("adjust", Some(-1.0)), Ok(()));
+        for type_ in &[
+            "dispute",
+            "resolve",
+            "chargeback",
+            "reverse-chargeback",
+            "freeze",
+            "unfreeze",
+        ] {
+            assert_eq!(Action::validate_row(type_, None), Ok(()));
+            assert!(Action::validate_row(type_, Some(1.0)).is_err());
+        }
+        for type_ in &["refund", "Deposit", ""] {
+            assert!(Action::validate_row(type_, Some(1.0)).is_err());
+            assert!(Action::validate_row(type_, None).is_err());
+        }
+    }
+
+    #[test]
+    fn blank_rows() {
+        let mut rdr = ReaderBuilder::new()
+            .trim(csv::Trim::All)
+            .has_headers(false)
+            .flexible(true)
+            .from_reader("deposit,1,1,1\n   \n , , , \ndispute,1,1\n".as_bytes());
+        let records: Vec<StringRecord> = rdr.records().map(Result::unwrap).collect();
+        let blank: Vec<bool> = records.iter().map(is_blank).collect();
+        assert_eq!(blank, vec![false, true, true, false]);
+        let headers = default_headers();
+        assert_eq!(check_field_count(&headers, &records[0]), Ok(()));
+        assert_eq!(
+            check_field_count(&headers, &records[3]),
+            Err("Expected 4 fields but found 3 in record 3 (line 4)".to_string())
+        );
+    }
+
+    #[test]
+    fn position_after_multiline_field() {
+        let mut rdr = ReaderBuilder::new()
+            .has_headers(false)
+            .flexible(true)
+            .from_reader(
+                "deposit,1,1,\"1\n.0\"\ndeposit,1,2\ndeposit,1,3,\"\n\n\"\nx,1\n".as_bytes(),
+            );
+        let records: Vec<StringRecord> = rdr.records().map(Result::unwrap).collect();
+        let positions: Vec<String> = records.iter().map(record_position).collect();
+        assert_eq!(
+            positions,
+            vec![
+                " in record 0 (line 1)",
+                " in record 1 (line 3)",
+                " in record 2 (line 4)",
+                " in record 3 (line 7)",
+            ]
+        );
+    }
+
+    #[test]
+    fn detect_header_row() {
+        assert!(looks_like_header(&StringRecord::from(vec![
+            "type", "client", "tx", "amount"
+        ])));
+        assert!(looks_like_header(&StringRecord::from(vec![
+            "client", " type", "amount ", "tx"
+        ])));
+        assert!(!looks_like_header(&StringRecord::from(vec![
+            "deposit", "1", "1", "1.0"
+        ])));
+        assert!(!looks_like_header(&StringRecord::new()));
+    }
+
     #[test]
     fn read_deposit() {
         assert_eq!(
@@ -139,7 +598,8 @@ mod tests {
             Ok(Transaction {
                 client: Client::new(4),
                 tx: Tx::new(5),
-                detail: Action::Deposit(6_0000)
+                detail: Action::Deposit(6_0000),
+                idempotency_key: None,
             })
         )
     }
@@ -151,7 +611,8 @@ mod tests {
             Ok(Transaction {
                 client: Client::new(0),
                 tx: Tx::new(0),
-                detail: Action::Withdrawal(0)
+                detail: Action::Withdrawal(0),
+                idempotency_key: None,
             })
         )
     }
@@ -163,7 +624,8 @@ mod tests {
             Ok(Transaction {
                 client: Client::new(0),
                 tx: Tx::new(0),
-                detail: Action::Dispute
+                detail: Action::Dispute,
+                idempotency_key: None,
             })
         )
     }
@@ -175,6 +637,208 @@ mod tests {
         assert!(read_line("dispute,0,0").is_err())
     }
 
+    fn read_line_with_precision(s: &str, max_precision: usize) -> Result<Transaction, String> {
+        let mut rdr = ReaderBuilder::new()
+            .has_headers(false)
+            .from_reader(s.as_bytes());
+        let transaction_row: TransactionRow = rdr
+            .deserialize()
+            .next()
+            .unwrap()
+            .map_err(|e| e.to_string())?;
+        let options = ParseOptions {
+            max_precision,
+            ..ParseOptions::default()
+        };
+        Transaction::from_row(transaction_row, &options)
+    }
+
+    #[test]
+    fn amount_within_precision() {
+        assert_eq!(
+            read_line_with_precision("deposit,1,1,1.23", 4).map(|t| t.detail),
+            Ok(Action::Deposit(1_2300))
+        )
+    }
+
+    #[test]
+    fn amount_at_max_precision() {
+        assert_eq!(
+            read_line_with_precision("deposit,1,1,1.2345", 4).map(|t| t.detail),
+            Ok(Action::Deposit(1_2345))
+        );
+        assert_eq!(
+            read_line_with_precision("withdrawal,1,1,1.23", 2).map(|t| t.detail),
+            Ok(Action::Withdrawal(1_2300))
+        );
+        // Places past those that are stored are rejected, not rounded.
+        assert!(read_line_with_precision("deposit,1,1,1.123456", 6).is_err());
+        assert_eq!(
+            read_line_with_precision("deposit,1,1,1.123400", 6).map(|t| t.detail),
+            Ok(Action::Deposit(1_1234))
+        );
+    }
+
+    #[test]
+    fn integer_and_decimal_amounts_are_equal() {
+        for (integer, decimal) in [
+            ("deposit,1,1,5", "deposit,1,1,5.0000"),
+            ("withdrawal,1,1,+7", "withdrawal,1,1,7."),
+            ("deposit,1,1,123456789", "deposit,1,1,123456789.0"),
+            ("adjust,1,1,-3", "adjust,1,1,-3.00"),
+        ] {
+            let integer = read_line(integer).map(|t| t.detail);
+            assert!(integer.is_ok());
+            assert_eq!(integer, read_line(decimal).map(|t| t.detail));
+        }
+        assert_eq!(
+            parse_amount("18446744073709551615", &ParseOptions::default()),
+            Ok(Amount::Integer(u64::MAX))
+        );
+        assert!(read_line("deposit,1,1,18446744073709551615").is_err());
+    }
+
+    #[test]
+    fn large_decimal_amounts_are_exact_or_rejected() {
+        assert_eq!(
+            read_line("deposit,1,1,1844674407370955.1615").map(|t| t.detail),
+            Ok(Action::Deposit(u64::MAX))
+        );
+        assert_eq!(
+            read_line("deposit,1,1,123456789012345.6789").map(|t| t.detail),
+            Ok(Action::Deposit(1_234_567_890_123_456_789))
+        );
+        for line in [
+            "deposit,1,1,123456789012345678.0",
+            "deposit,1,1,1844674407370955.1616",
+            "withdrawal,1,1,99999999999999999999.5",
+            "adjust,1,1,922337203685477.5808",
+            "adjust,1,1,-123456789012345678.0",
+        ] {
+            let err = read_line(line).unwrap_err();
+            assert!(err.ends_with("is too large"), "{}: {}", line, err);
+        }
+        assert_eq!(
+            read_line("adjust,1,1,-922337203685477.5807").map(|t| t.detail),
+            Ok(Action::Adjust(-i64::MAX))
+        );
+    }
+
+    #[test]
+    fn amount_error_names_client_and_tx() {
+        let err = read_line("deposit,7,42,1/3").unwrap_err();
+        assert_eq!(err, r#"Client 7 tx 42: Invalid amount "1/3""#);
+        let err = read_line("refund,7,42,1").unwrap_err();
+        assert!(err.starts_with("Client 7 tx 42: "), "{}", err);
+    }
+
+    #[test]
+    fn amount_over_precision_is_error() {
+        assert!(read_line_with_precision("deposit,1,1,1.23456", 4).is_err());
+        assert!(read_line_with_precision("deposit,1,1,1.234", 2).is_err());
+    }
+
+    #[test]
+    fn amount_with_plus_sign() {
+        assert_eq!(
+            read_line("deposit,1,1,+5.0").map(|t| t.detail),
+            Ok(Action::Deposit(5_0000))
+        );
+    }
+
+    #[test]
+    fn amount_with_internal_space_is_error() {
+        assert!(read_line("deposit,1,1,5 .0").is_err());
+        assert!(read_line("deposit,1,1,+ 5.0").is_err());
+    }
+
+    #[test]
+    fn amount_with_surrounding_space_is_trimmed() {
+        let mut rdr = ReaderBuilder::new()
+            .trim(csv::Trim::All)
+            .has_headers(false)
+            .from_reader("deposit, 1, 1,  5.0 ".as_bytes());
+        let row: TransactionRow = rdr.deserialize().next().unwrap().unwrap();
+        assert_eq!(
+            Transaction::try_from(row).map(|t| t.detail),
+            Ok(Action::Deposit(5_0000))
+        );
+    }
+
+    #[test]
+    fn amount_grammar() {
+        for ok in &["5", "5.", ".5", "+.5", "0005.1000"] {
+            assert!(read_line(&format!("deposit,1,1,{}", ok)).is_ok(), "{}", ok);
+        }
+        for bad in &["-5", "++5", "1e3", "inf", "NaN", ".", "+", "5.0.0", "0x10"] {
+            assert!(
+                read_line(&format!("deposit,1,1,{}", bad)).is_err(),
+                "{}",
+                bad
+            );
+        }
+    }
+
+    #[test]
+    fn ignored_dispute_amount() {
+        let row = |line: &str| {
+            ReaderBuilder::new()
+                .has_headers(false)
+                .from_reader(line.as_bytes())
+                .deserialize::<TransactionRow>()
+                .next()
+                .unwrap()
+                .unwrap()
+        };
+        assert!(Transaction::from_row(row("dispute,1,3,5.0"), &ParseOptions::default()).is_err());
+        let options = ParseOptions {
+            ignore_dispute_amount: true,
+            ..ParseOptions::default()
+        };
+        assert_eq!(
+            Transaction::from_row(row("dispute,1,3,5.0"), &options),
+            Ok(Transaction {
+                client: Client::new(1),
+                tx: Tx::new(3),
+                detail: Action::Dispute,
+                idempotency_key: None,
+            })
+        );
+        // Amounts of other types are still required.
+        assert_eq!(
+            Transaction::from_row(row("withdrawal,1,4,"), &options).map(|t| t.detail),
+            Err("Client 1 tx 4: Transaction type \"withdrawal\" requires an amount".to_string())
+        );
+    }
+
+    #[test]
+    fn comma_decimal_separator() {
+        let options = ParseOptions {
+            decimal_separator: ',',
+            ..ParseOptions::default()
+        };
+        assert_eq!(parse_amount("5,50", &options), Ok(Amount::Decimal(5_5000)));
+        assert_eq!(parse_amount(",5", &options), Ok(Amount::Decimal(5000)));
+        assert_eq!(parse_amount("5", &options), Ok(Amount::Integer(5)));
+        assert!(parse_amount("5.50", &options).is_err());
+        assert!(parse_amount("5,5,0", &options).is_err());
+    }
+
+    #[test]
+    fn read_adjust() {
+        assert_eq!(
+            read_line("adjust,1,1,+1.5").map(|t| t.detail),
+            Ok(Action::Adjust(1_5000))
+        );
+        assert_eq!(
+            read_line("adjust,1,1,-0.25").map(|t| t.detail),
+            Ok(Action::Adjust(-2500))
+        );
+        assert!(read_line("adjust,1,1,").is_err());
+        assert!(read_line("adjust,1,1,-+1").is_err());
+        assert!(read_line("deposit,1,1,-1").is_err());
+    }
+
     #[test]
     fn read_resolve() {
         assert_eq!(
@@ -182,7 +846,8 @@ mod tests {
             Ok(Transaction {
                 client: Client::new(0),
                 tx: Tx::new(0),
-                detail: Action::Resolve
+                detail: Action::Resolve,
+                idempotency_key: None,
             })
         )
     }
@@ -194,7 +859,8 @@ mod tests {
             Ok(Transaction {
                 client: Client::new(0),
                 tx: Tx::new(0),
-                detail: Action::ChargeBack
+                detail: Action::ChargeBack,
+                idempotency_key: None,
             })
         )
     }
@@ -0,0 +1,121 @@
+use crate::transaction::{Action, Client, Transaction, Tx};
+use std::{collections::HashSet, str::FromStr};
+
+/// What to do when a deposit or withdrawal reuses a transaction id.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum TxUniqueness {
+    /// Don't check. Accounts still reject a deposit that reuses the id of an
+    /// earlier deposit for the same client.
+    #[default]
+    Off,
+    /// Reject the row that reuses the id.
+    Row,
+    /// Abort the whole run.
+    Run,
+}
+
+impl FromStr for TxUniqueness {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "off" => Ok(TxUniqueness::Off),
+            "row" => Ok(TxUniqueness::Row),
+            "run" => Ok(TxUniqueness::Run),
+            other => Err(format!("Unknown tx uniqueness mode: {:?}", other)),
+        }
+    }
+}
+
+/// Over which transactions ids must be unique.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum TxScope {
+    /// Different clients may use the same transaction id.
+    #[default]
+    Account,
+    /// Transaction ids must be unique across all clients.
+    Global,
+}
+
+impl FromStr for TxScope {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "account" => Ok(TxScope::Account),
+            "global" => Ok(TxScope::Global),
+            other => Err(format!("Unknown tx scope: {:?}", other)),
+        }
+    }
+}
+
+/// The ids of all deposits and withdrawals seen so far. Disputes, resolves
+/// and chargebacks refer to an existing transaction so they don't count.
+#[derive(Debug, Default)]
+pub struct SeenTxs {
+    scope: TxScope,
+    /// The client is `None` when the scope is global.
+    seen: HashSet<(Option<Client>, Tx)>,
+}
+
+impl SeenTxs {
+    pub fn new(scope: TxScope) -> Self {
+        SeenTxs {
+            scope,
+            seen: HashSet::new(),
+        }
+    }
+
+    /// Record the id of `transaction`, returning an error if it was already
+    /// used.
+    pub fn check(&mut self, transaction: &Transaction) -> Result<(), String> {
+        match transaction.detail {
+            Action::Deposit(_) | Action::Withdrawal(_) => (),
+            _ => return Ok(()),
+        }
+        let client = match self.scope {
+            TxScope::Account => Some(transaction.client),
+            TxScope::Global => None,
+        };
+        if self.seen.insert((client, transaction.tx)) {
+            Ok(())
+        } else {
+            Err(format!("Transaction id {} was reused", transaction.tx))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn transaction(client: u32, tx: u32, detail: Action) -> Transaction {
+        Transaction {
+            client: Client::new(client),
+            tx: Tx::new(tx),
+            detail,
+            idempotency_key: None,
+        }
+    }
+
+    #[test]
+    fn reuse_within_account() {
+        let mut seen = SeenTxs::new(TxScope::Account);
+        assert!(seen.check(&transaction(1, 1, Action::Deposit(1))).is_ok());
+        assert!(seen.check(&transaction(2, 1, Action::Deposit(1))).is_ok());
+        assert!(seen.check(&transaction(1, 1, Action::Dispute)).is_ok());
+        assert!(seen
+            .check(&transaction(1, 1, Action::Withdrawal(1)))
+            .is_err());
+    }
+
+    #[test]
+    fn reuse_across_accounts() {
+        let mut seen = SeenTxs::new(TxScope::Global);
+        assert!(seen.check(&transaction(1, 1, Action::Deposit(1))).is_ok());
+        assert_eq!(
+            seen.check(&transaction(2, 1, Action::Deposit(1))),
+            Err("Transaction id 1 was reused".to_string())
+        );
+    }
+}
@@ -0,0 +1,37 @@
+//! How much is reported on stderr. Set once by the binary, and checked by
+//! `report!` wherever something is reported.
+use std::sync::atomic::{AtomicU8, Ordering};
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, PartialOrd)]
+pub enum Verbosity {
+    /// Nothing but fatal errors.
+    Quiet,
+    /// Warnings and summaries.
+    #[default]
+    Normal,
+    /// Also each rejected transaction.
+    Verbose,
+    /// Also each applied transaction.
+    Debug,
+}
+
+static LEVEL: AtomicU8 = AtomicU8::new(Verbosity::Normal as u8);
+
+pub fn set(verbosity: Verbosity) {
+    LEVEL.store(verbosity as u8, Ordering::Relaxed);
+}
+
+/// Whether messages at `verbosity` are reported.
+pub fn enabled(verbosity: Verbosity) -> bool {
+    LEVEL.load(Ordering::Relaxed) >= verbosity as u8
+}
+
+/// `eprintln!` if messages at the given `Verbosity` are reported.
+#[macro_export]
+macro_rules! report {
+    ($verbosity:expr, $($arg:tt)*) => {
+        if $crate::verbosity::enabled($verbosity) {
+            eprintln!($($arg)*);
+        }
+    };
+}
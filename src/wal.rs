@@ -0,0 +1,156 @@
+//! A write-ahead log of transactions, so that the state can be rebuilt after
+//! a crash by replaying every transaction that was logged.
+//!
+//! Each entry is a CSV row of `Transaction::to_record` written and synced
+//! before the transaction is applied, see `State::apply_with_wal`. Rejected
+//! transactions are logged too: applying the same transactions with the same
+//! `Config` rejects them again.
+use crate::state::State;
+use crate::transaction::{ParseOptions, Transaction, TransactionRow, COLUMNS, OPTIONAL_COLUMNS};
+use csv::{ReaderBuilder, StringRecord, WriterBuilder};
+use std::{
+    fs::File,
+    io::{self, Read, Write},
+};
+
+/// A writer that can make what was written durable.
+pub trait SyncWrite: Write {
+    /// Flush and wait until the written data is stored.
+    fn sync(&mut self) -> io::Result<()>;
+}
+
+impl SyncWrite for File {
+    fn sync(&mut self) -> io::Result<()> {
+        self.flush()?;
+        self.sync_data()
+    }
+}
+
+/// For tests and logs that only need to survive a panic, not a crash.
+impl SyncWrite for Vec<u8> {
+    fn sync(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Write `transaction` as a complete entry to `wal` and sync it.
+pub(crate) fn append<W: SyncWrite>(wal: &mut W, transaction: &Transaction) -> io::Result<()> {
+    let mut writer = WriterBuilder::new()
+        .has_headers(false)
+        .from_writer(&mut *wal);
+    writer.write_record(&transaction.to_record())?;
+    writer.flush()?;
+    drop(writer);
+    wal.sync()
+}
+
+/// Apply every transaction logged in `wal` to `state`, returning how many
+/// there were. A partly written last entry (from a crash while appending it)
+/// is ignored, as that transaction was never applied.
+pub fn replay<R: Read>(state: &mut State, mut wal: R) -> Result<usize, String> {
+    let mut data = Vec::new();
+    wal.read_to_end(&mut data).map_err(|e| e.to_string())?;
+    let complete = data.iter().rposition(|&b| b == b'\n').map_or(0, |i| i + 1);
+    let headers: StringRecord = COLUMNS.iter().chain(OPTIONAL_COLUMNS.iter()).collect();
+    let mut reader = ReaderBuilder::new()
+        .has_headers(false)
+        .from_reader(&data[..complete]);
+    let mut count = 0;
+    for (line, record) in reader.records().enumerate() {
+        let with_line = |e: String| format!("WAL entry {}: {}", line + 1, e);
+        let record = record.map_err(|e| with_line(e.to_string()))?;
+        let row: TransactionRow = record
+            .deserialize(Some(&headers))
+            .map_err(|e| with_line(e.to_string()))?;
+        let transaction =
+            Transaction::from_row(row, &ParseOptions::default()).map_err(with_line)?;
+        let _possible_client_error = state.handle_transaction(transaction);
+        count += 1;
+    }
+    Ok(count)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transaction::{Action, Client, Tx};
+
+    fn transaction(client: u32, tx: u32, detail: Action) -> Transaction {
+        Transaction {
+            client: Client::new(client),
+            tx: Tx::new(tx),
+            detail,
+            idempotency_key: None,
+        }
+    }
+
+    #[test]
+    fn replay_after_crash_rebuilds_state() {
+        let transactions = vec![
+            transaction(1, 1, Action::Deposit(50_000)),
+            transaction(2, 2, Action::Deposit(12_345)),
+            transaction(1, 3, Action::Withdrawal(100_000)),
+            transaction(1, 1, Action::Dispute),
+            transaction(2, 4, Action::Adjust(-2_345)),
+            Transaction {
+                idempotency_key: Some("key".to_string()),
+                ..transaction(2, 5, Action::Withdrawal(1))
+            },
+        ];
+        let mut expected = State::new();
+        for transaction in transactions.clone() {
+            let _possible_client_error = expected.handle_transaction(transaction);
+        }
+
+        // The last transaction is logged, then the process crashes before it
+        // is applied and while writing the entry of another one.
+        let mut wal = Vec::new();
+        let mut crashed = State::new();
+        let (last, applied) = transactions.split_last().unwrap();
+        for transaction in applied {
+            let _possible_client_error = crashed
+                .apply_with_wal(transaction.clone(), &mut wal)
+                .unwrap();
+        }
+        append(&mut wal, last).unwrap();
+        wal.extend_from_slice(b"deposit,3,6,");
+
+        let mut recovered = State::new();
+        assert_eq!(replay(&mut recovered, &wal[..]), Ok(transactions.len()));
+        assert_eq!(recovered.accounts_snapshot(), expected.accounts_snapshot());
+        assert_ne!(crashed.accounts_snapshot(), expected.accounts_snapshot());
+    }
+
+    #[test]
+    fn largest_amounts_round_trip() {
+        let transactions = vec![
+            transaction(1, 1, Action::Deposit(u64::MAX)),
+            transaction(1, 2, Action::Withdrawal(u64::MAX - 1)),
+            transaction(2, 3, Action::Deposit(9_007_199_254_740_993)),
+            transaction(2, 4, Action::Adjust(-9_007_199_254_740_993)),
+            transaction(3, 5, Action::Adjust(i64::MAX)),
+        ];
+        let mut wal = Vec::new();
+        let mut applied = State::new();
+        for transaction in &transactions {
+            applied
+                .apply_with_wal(transaction.clone(), &mut wal)
+                .unwrap()
+                .unwrap();
+        }
+        let mut recovered = State::new();
+        assert_eq!(replay(&mut recovered, &wal[..]), Ok(transactions.len()));
+        assert_eq!(recovered.accounts_snapshot(), applied.accounts_snapshot());
+        assert_eq!(recovered.account(Client::new(1)).unwrap().available, 1);
+        assert_eq!(recovered.account(Client::new(2)).unwrap().available, 0);
+    }
+
+    #[test]
+    fn invalid_entry() {
+        let mut state = State::new();
+        assert_eq!(
+            replay(&mut state, &b"deposit,1,1,1,\nbogus,1,2,,\n"[..]),
+            Err("WAL entry 2: Client 1 tx 2: Invalid transaction type: \"bogus\"".to_string())
+        );
+    }
+}
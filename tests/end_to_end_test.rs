@@ -9,16 +9,1346 @@ fn usage_error() {
     command.assert().stderr(predicate::str::contains("Usage:"));
 }
 
+const SAMPLE_OUTPUT: &str = r#"client,available,held,total,locked
+1,1.5,0,1.5,false
+2,2,0,2,false
+"#;
+
+fn test_file(name: &str) -> PathBuf {
+    let manifest_path: PathBuf = env::var("CARGO_MANIFEST_DIR").unwrap().parse().unwrap();
+    manifest_path.join("tests").join(name)
+}
+
+fn sample_input() -> PathBuf {
+    test_file("sample_input.csv")
+}
+
 #[test]
 fn correct_run() {
-    let expected = r#"client,available,held,total,locked
+    let mut command = Command::cargo_bin("atm").unwrap();
+    command.arg(sample_input().to_str().unwrap());
+    command.assert().success();
+    command.assert().stdout(predicate::eq(SAMPLE_OUTPUT));
+}
+
+#[test]
+fn sharded_run() {
+    for shard in &["modulo", "hash"] {
+        let mut command = Command::cargo_bin("atm").unwrap();
+        command.args(["--workers", "3", "--shard", shard]);
+        command.arg(sample_input().to_str().unwrap());
+        command.assert().success();
+        command.assert().stdout(predicate::eq(SAMPLE_OUTPUT));
+    }
+}
+
+#[test]
+fn header_only_input() {
+    let mut command = Command::cargo_bin("atm").unwrap();
+    command.arg(test_file("header_only.csv").to_str().unwrap());
+    command.assert().success();
+    command
+        .assert()
+        .stdout(predicate::eq("client,available,held,total,locked\n"));
+}
+
+#[test]
+fn header_only_input_fails_on_empty() {
+    let mut command = Command::cargo_bin("atm").unwrap();
+    command.arg("--fail-on-empty");
+    command.arg(test_file("header_only.csv").to_str().unwrap());
+    command.assert().failure();
+    command
+        .assert()
+        .stderr(predicate::str::contains("No transactions were found"));
+}
+
+#[cfg(feature = "http")]
+#[test]
+fn http_input() {
+    use std::{
+        fs,
+        io::{BufRead, BufReader, Write},
+        net::TcpListener,
+        thread,
+    };
+
+    let body = fs::read_to_string(sample_input()).unwrap();
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}/sample.csv", listener.local_addr().unwrap());
+    let server = thread::spawn(move || {
+        let (stream, _) = listener.accept().unwrap();
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        let mut authorized = false;
+        loop {
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+            if line.trim().is_empty() {
+                break;
+            }
+            authorized |= line
+                .to_lowercase()
+                .starts_with("authorization: bearer secret");
+        }
+        let response = if authorized {
+            format!(
+                "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                body.len(),
+                body
+            )
+        } else {
+            "HTTP/1.1 401 Unauthorized\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+                .to_string()
+        };
+        let mut stream = stream;
+        stream.write_all(response.as_bytes()).unwrap();
+    });
+
+    let mut command = Command::cargo_bin("atm").unwrap();
+    command.args(["--header", "Authorization: Bearer secret", &url]);
+    command
+        .assert()
+        .success()
+        .stdout(predicate::eq(SAMPLE_OUTPUT));
+    server.join().unwrap();
+}
+
+#[test]
+fn selftest() {
+    let mut command = Command::cargo_bin("atm").unwrap();
+    command.arg("selftest");
+    command.assert().success();
+}
+
+#[test]
+fn duplicate_header_column() {
+    let mut command = Command::cargo_bin("atm").unwrap();
+    command.arg(test_file("duplicate_header.csv").to_str().unwrap());
+    command.assert().failure();
+    command
+        .assert()
+        .stderr(predicate::str::contains("duplicate header column: client"));
+}
+
+#[test]
+fn tx_uniqueness_modes() {
+    let input = test_file("reused_tx.csv");
+    let input = input.to_str().unwrap();
+
+    let mut command = Command::cargo_bin("atm").unwrap();
+    command.args(["--tx-uniqueness", "run", "--tx-scope", "global", input]);
+    command.assert().failure();
+    command
+        .assert()
+        .stderr(predicate::str::contains("Transaction id 2 was reused"));
+
+    let mut command = Command::cargo_bin("atm").unwrap();
+    command.args(["--tx-uniqueness", "row", "--tx-scope", "global", input]);
+    command.assert().success();
+    command.assert().stdout(predicate::eq(
+        r#"client,available,held,total,locked
+1,3,0,3,false
+2,2,0,2,false
+"#,
+    ));
+
+    let mut command = Command::cargo_bin("atm").unwrap();
+    command.args(["--tx-uniqueness", "off", input]);
+    command.assert().success();
+    command.assert().stdout(predicate::eq(
+        r#"client,available,held,total,locked
+1,3,0,3,false
+2,2,0,2,false
+3,5,0,5,false
+"#,
+    ));
+}
+
+#[test]
+fn same_input_twice() {
+    let input = sample_input();
+    let input = input.to_str().unwrap();
+
+    let mut command = Command::cargo_bin("atm").unwrap();
+    command.args([input, input]);
+    command.assert().failure();
+    command
+        .assert()
+        .stderr(predicate::str::contains("was given more than once"));
+
+    let mut command = Command::cargo_bin("atm").unwrap();
+    command.args(["--allow-duplicate-inputs", input, input]);
+    command.assert().success();
+    command.assert().stdout(predicate::eq(SAMPLE_OUTPUT));
+    command
+        .assert()
+        .stderr(predicate::str::contains("skipping repeated input"));
+
+    // Standard input isn't a file, but can't be read twice either.
+    let mut command = Command::cargo_bin("atm").unwrap();
+    command.args(["-", "-"]);
+    command.assert().failure().stderr(predicate::str::contains(
+        "Input \"-\" was given more than once",
+    ));
+}
+
+#[test]
+fn stdin_input() {
+    // Not memory mapped, even with --mmap.
+    let args: &[&str] = if cfg!(feature = "mmap") {
+        &["--mmap", "-"]
+    } else {
+        &["-"]
+    };
+    let mut command = assert_cmd::Command::cargo_bin("atm").unwrap();
+    command
+        .args(args)
+        .write_stdin(std::fs::read(sample_input()).unwrap());
+    command
+        .assert()
+        .success()
+        .stdout(predicate::eq(SAMPLE_OUTPUT));
+
+    // Standard input can be combined with files.
+    let mut command = assert_cmd::Command::cargo_bin("atm").unwrap();
+    command
+        .arg(test_file("reused_tx.csv"))
+        .arg("-")
+        .write_stdin("withdrawal, 3, 9, 1.0\n");
+    command.assert().success().stdout(predicate::eq(
+        r#"client,available,held,total,locked
+1,3,0,3,false
+2,2,0,2,false
+3,4,0,4,false
+"#,
+    ));
+}
+
+#[test]
+fn multiple_inputs() {
+    let mut command = Command::cargo_bin("atm").unwrap();
+    command.args([
+        sample_input().to_str().unwrap(),
+        test_file("reused_tx.csv").to_str().unwrap(),
+    ]);
+    command.assert().success();
+    command.assert().stdout(predicate::eq(
+        r#"client,available,held,total,locked
 1,1.5,0,1.5,false
 2,2,0,2,false
+3,5,0,5,false
+"#,
+    ));
+}
+
+#[test]
+fn duplicate_tx_policies() {
+    let input = test_file("duplicate_deposit.csv");
+    let input = input.to_str().unwrap();
+
+    let mut command = Command::cargo_bin("atm").unwrap();
+    command.args(["--duplicate-tx", "update", input]);
+    command.assert().success().stdout(predicate::eq(
+        r#"client,available,held,total,locked
+1,4,0,4,false
+2,2,0,2,false
+"#,
+    ));
+
+    let mut command = Command::cargo_bin("atm").unwrap();
+    command.args(["--duplicate-tx", "error", input]);
+    command
+        .assert()
+        .failure()
+        .stdout(predicate::str::is_empty())
+        .stderr(predicate::str::contains(
+            "Rejected 1 duplicate transactions",
+        ));
+}
+
+#[test]
+fn maximum_ids() {
+    // Ids are u32s: the largest sorts last and one past it is rejected
+    // rather than wrapped.
+    let expected = r#"client,available,held,total,locked
+0,1.5,0,1.5,false
+1,1,0,1,false
+4294967295,0,2,2,false
 "#;
-    let manifest_path: PathBuf = env::var("CARGO_MANIFEST_DIR").unwrap().parse().unwrap();
-    let test_file = manifest_path.join("tests").join("sample_input.csv");
+    for workers in ["1", "3"] {
+        let mut command = Command::cargo_bin("atm").unwrap();
+        command.args([
+            "--workers",
+            workers,
+            test_file("max_ids.csv").to_str().unwrap(),
+        ]);
+        command
+            .assert()
+            .success()
+            .stdout(predicate::eq(expected))
+            .stderr(predicate::str::contains("number too large"));
+    }
+}
+
+#[test]
+fn json_summary() {
+    let mut command = Command::cargo_bin("atm").unwrap();
+    command.arg("--json-summary").arg(sample_input());
+    let output = command.output().unwrap();
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), SAMPLE_OUTPUT);
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    let lines: Vec<&str> = stderr.lines().collect();
+    assert_eq!(lines.len(), 1, "{}", stderr);
+    let summary: serde_json::Value = serde_json::from_str(lines[0]).unwrap();
+    assert_eq!(
+        summary,
+        serde_json::json!({
+            "accounts": 2,
+            "locked": 0,
+            "rejected": 1,
+            "total_available": "3.5",
+            "total_held": "0",
+        })
+    );
+}
+
+#[test]
+fn audit_reconstructs_balances() {
+    let dir = env::temp_dir().join(format!("atm-audit-{}", std::process::id()));
+    let mut command = Command::cargo_bin("atm").unwrap();
+    command.arg("--audit").arg(&dir).arg(test_file("audit.csv"));
+    command.assert().success().stdout(predicate::eq(
+        r#"client,available,held,total,locked
+1,7,5,12,false
+2,1.5,0,1.5,false
+"#,
+    ));
+
+    let trail = std::fs::read_to_string(dir.join("1.csv")).unwrap();
+    assert_eq!(
+        trail,
+        r#"tx,type,amount,available_change,held_change,available,held
+1,deposit,5,5,0,5,0
+2,deposit,10,10,0,15,0
+3,withdrawal,3,-3,0,12,0
+1,dispute,,-5,5,7,5
+"#
+    );
+    // The changes add up to the final balances.
+    let mut reader = csv::Reader::from_path(dir.join("1.csv")).unwrap();
+    let (available, held) = reader
+        .records()
+        .map(|row| {
+            let row = row.unwrap();
+            (
+                row[3].parse::<f64>().unwrap(),
+                row[4].parse::<f64>().unwrap(),
+            )
+        })
+        .fold((0.0, 0.0), |(a, h), (da, dh)| (a + da, h + dh));
+    assert_eq!((available, held), (7.0, 5.0));
+    assert!(dir.join("2.csv").exists());
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn audit_uses_output_dialect() {
+    let dir = env::temp_dir().join(format!("atm-audit-semicolon-{}", std::process::id()));
+    let mut command = Command::cargo_bin("atm").unwrap();
+    command
+        .args(["--decimal-sep", ",", "--delimiter", ";", "--audit"])
+        .arg(&dir)
+        .arg(test_file("comma_decimal.csv"));
+    command.assert().success();
+    let trail = std::fs::read_to_string(dir.join("2.csv")).unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+    assert_eq!(
+        trail,
+        r#"tx;type;amount;available_change;held_change;available;held
+2;deposit;1,75;1,75;0;1,75;0
+4;deposit;0,5;0,5;0;2,25;0
+2;dispute;;-1,75;1,75;0,5;1,75
+"#
+    );
+}
+
+#[test]
+fn max_memory_with_workers_is_rejected() {
+    let mut command = Command::cargo_bin("atm").unwrap();
+    command
+        .args(["--max-memory", "100000", "--workers", "2"])
+        .arg(sample_input());
+    command
+        .assert()
+        .failure()
+        .stdout(predicate::str::is_empty())
+        .stderr(predicate::str::contains(
+            "--max-memory can't be used with multiple --workers",
+        ));
+}
+
+#[test]
+fn snapshot_verifies() {
+    let snapshot = env::temp_dir().join(format!("atm-snapshot-{}.csv", std::process::id()));
+    let mut command = Command::cargo_bin("atm").unwrap();
+    command.arg("--snapshot").arg(&snapshot).arg(sample_input());
+    command
+        .assert()
+        .success()
+        .stdout(predicate::eq(SAMPLE_OUTPUT));
+
+    let mut command = Command::cargo_bin("atm").unwrap();
+    command.arg("verify-snapshot").arg(&snapshot);
+    command
+        .assert()
+        .success()
+        .stdout(predicate::eq("Snapshot is valid\n"));
+    std::fs::remove_file(snapshot).unwrap();
+}
+
+#[test]
+fn corrupt_snapshot_fails_verification() {
+    let mut command = Command::cargo_bin("atm").unwrap();
+    command
+        .arg("verify-snapshot")
+        .arg(test_file("corrupt_snapshot.csv"));
+    command.assert().failure().stderr(predicate::eq(
+        "Client 2: held is 30000 but disputed transactions total 20000\n",
+    ));
+}
+
+#[test]
+fn injected_faults_are_skipped() {
+    let mut command = Command::cargo_bin("atm").unwrap();
+    command
+        .args(["--inject-fault", "every:2:bad-type"])
+        .arg(test_file("four_deposits.csv"));
+    let output = command.output().unwrap();
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        r#"client,available,held,total,locked
+1,1,0,1,false
+3,3,0,3,false
+"#
+    );
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert_eq!(stderr.matches("Skipping row:").count(), 2);
+}
+
+#[test]
+fn split_output_by_lock_status() {
+    let dir = env::temp_dir();
+    let active = dir.join(format!("atm-active-{}.csv", std::process::id()));
+    let locked = dir.join(format!("atm-locked-{}.csv", std::process::id()));
+    let mut command = Command::cargo_bin("atm").unwrap();
+    command
+        .arg("--split-output")
+        .arg(format!(
+            "active={},locked={}",
+            active.display(),
+            locked.display()
+        ))
+        .arg(test_file("locked_and_active.csv"));
+    command.assert().success().stdout(predicate::eq(""));
+    assert_eq!(
+        std::fs::read_to_string(&active).unwrap(),
+        r#"client,available,held,total,locked
+1,1,0,1,false
+3,0,3,3,false
+"#
+    );
+    assert_eq!(
+        std::fs::read_to_string(&locked).unwrap(),
+        r#"client,available,held,total,locked
+2,0,0,0,true
+4,0,0,0,true
+"#
+    );
+    std::fs::remove_file(active).unwrap();
+    std::fs::remove_file(locked).unwrap();
+}
+
+#[test]
+fn as_of_tx() {
+    let mut command = Command::cargo_bin("atm").unwrap();
+    command.arg(test_file("as_of_tx.csv"));
+    command.assert().success().stdout(predicate::eq(
+        r#"client,available,held,total,locked
+1,3.5,0,3.5,false
+2,3,0,3,false
+"#,
+    ));
+
+    let mut command = Command::cargo_bin("atm").unwrap();
+    command
+        .args(["--as-of-tx", "2"])
+        .arg(test_file("as_of_tx.csv"));
+    command.assert().success().stdout(predicate::eq(
+        r#"client,available,held,total,locked
+1,5,0,5,false
+2,2,0,2,false
+"#,
+    ));
+}
+
+#[test]
+fn errors_file_has_codes() {
+    let errors = env::temp_dir().join(format!("atm-errors-{}.csv", std::process::id()));
     let mut command = Command::cargo_bin("atm").unwrap();
-    command.arg(test_file.to_str().unwrap());
+    command
+        .arg("--errors")
+        .arg(&errors)
+        .arg(test_file("locked_and_active.csv"));
     command.assert().success();
-    command.assert().stdout(predicate::eq(expected));
+    let log = std::fs::read_to_string(&errors).unwrap();
+    assert_eq!(log.lines().next(), Some("client,tx,code,message"));
+    assert_eq!(log.lines().count(), 1);
+    std::fs::remove_file(errors).unwrap();
+
+    let errors = env::temp_dir().join(format!("atm-errors-2-{}.csv", std::process::id()));
+    let mut command = Command::cargo_bin("atm").unwrap();
+    command.arg("--errors").arg(&errors).arg(sample_input());
+    command.assert().success();
+    let log = std::fs::read_to_string(&errors).unwrap();
+    assert!(log.starts_with("client,tx,code,message\n2,5,E_INSUFFICIENT_FUNDS,"));
+    assert_eq!(log.lines().count(), 2);
+    std::fs::remove_file(errors).unwrap();
+}
+
+#[test]
+fn headered_and_headerless_inputs() {
+    let expected = r#"client,available,held,total,locked
+1,1,0,1,false
+2,2,0,2,false
+3,4,0,4,false
+"#;
+    let mut command = Command::cargo_bin("atm").unwrap();
+    command.arg(sample_input()).arg(test_file("headerless.csv"));
+    command.assert().success().stdout(predicate::eq(expected));
+
+    let mut command = Command::cargo_bin("atm").unwrap();
+    command.arg(test_file("headerless.csv")).arg(sample_input());
+    command.assert().success().stdout(predicate::eq(
+        r#"client,available,held,total,locked
+1,1.5,0,1.5,false
+2,2,0,2,false
+3,4,0,4,false
+"#,
+    ));
+}
+
+#[test]
+fn repl_session() {
+    let mut command = assert_cmd::Command::cargo_bin("atm").unwrap();
+    command.arg("repl").write_stdin(
+        r#"deposit, 1, 1, 5.0
+withdrawal, 1, 2, 7.5
+deposit, 2, 3, 1.25
+dispute, 1, 1
+:show 1
+:show 9
+:dump
+:quit
+deposit, 1, 4, 100.0
+"#,
+    );
+    command.assert().success().stdout(predicate::eq(
+        r#"client=1 available=5 held=0 total=5 locked=false
+Rejected [E_INSUFFICIENT_FUNDS]: Insufficient funds for transaction: Transaction { client: Client(1), tx: Tx(2), detail: Withdrawal(75000), idempotency_key: None }
+client=2 available=1.25 held=0 total=1.25 locked=false
+client=1 available=0 held=5 total=5 locked=false
+client=1 available=0 held=5 total=5 locked=false
+No account for client 9
+client,available,held,total,locked
+1,0,5,5,false
+2,1.25,0,1.25,false
+"#,
+    ));
+}
+
+#[test]
+fn conserve_check() {
+    let mut command = Command::cargo_bin("atm").unwrap();
+    command.arg("--conserve-check").arg(sample_input());
+    command
+        .assert()
+        .success()
+        .stdout(predicate::eq(SAMPLE_OUTPUT))
+        .stderr(predicate::eq(""));
+}
+
+#[test]
+fn conserve_check_with_duplicate_update_is_rejected() {
+    let mut command = Command::cargo_bin("atm").unwrap();
+    command
+        .args(["--conserve-check", "--duplicate-tx", "update"])
+        .arg(sample_input());
+    command
+        .assert()
+        .failure()
+        .stdout(predicate::str::is_empty())
+        .stderr(predicate::str::contains(
+            "--conserve-check can't be used with --duplicate-tx update",
+        ));
+}
+
+#[test]
+fn repeated_idempotency_keys_are_skipped() {
+    let mut command = Command::cargo_bin("atm").unwrap();
+    command.arg(test_file("idempotency_keys.csv"));
+    command
+        .assert()
+        .success()
+        .stdout(predicate::eq(
+            r#"client,available,held,total,locked
+1,4,0,4,false
+"#,
+        ))
+        .stderr(predicate::str::contains(
+            r#"Skipping retry of transaction: Transaction { client: Client(1), tx: Tx(2), detail: Deposit(50000), idempotency_key: Some("abc") }"#,
+        ));
+}
+
+#[test]
+fn sort_by_first_seen() {
+    let mut command = Command::cargo_bin("atm").unwrap();
+    command
+        .arg("--sort-by")
+        .arg("first-seen")
+        .arg(test_file("first_seen.csv"));
+    command.assert().success().stdout(predicate::eq(
+        r#"client,available,held,total,locked
+2,2,0,2,false
+1,1,0,1,false
+"#,
+    ));
+}
+
+#[test]
+fn comma_decimal_separator() {
+    let mut command = Command::cargo_bin("atm").unwrap();
+    command
+        .args(["--decimal-sep", ",", "--delimiter", ";"])
+        .arg(test_file("comma_decimal.csv"));
+    command.assert().success().stdout(predicate::eq(
+        r#"client;available;held;total;locked
+1;3,25;0;3,25;false
+2;0,5;1,75;2,25;false
+"#,
+    ));
+}
+
+#[test]
+fn closed_stdout_is_not_a_panic() {
+    use std::{fmt::Write, fs, process::Stdio};
+
+    // Enough accounts that the output doesn't fit in the pipe's buffer.
+    let mut input = String::from("type, client, tx, amount\n");
+    for client in 1..=20_000 {
+        writeln!(input, "deposit, {}, {}, 1.0", client, client).unwrap();
+    }
+    let path = env::temp_dir().join(format!("atm-broken-pipe-{}.csv", std::process::id()));
+    fs::write(&path, input).unwrap();
+
+    let mut child = Command::cargo_bin("atm")
+        .unwrap()
+        .arg(&path)
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    // Close our end of stdout without reading anything, like `head -0`.
+    drop(child.stdout.take());
+    let output = child.wait_with_output().unwrap();
+    fs::remove_file(&path).unwrap();
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(!stderr.contains("panicked"), "{}", stderr);
+    assert!(output.status.success(), "{:?}", output.status);
+}
+
+#[test]
+fn checksum_round_trip() {
+    use std::fs;
+
+    let mut command = Command::cargo_bin("atm").unwrap();
+    command.arg("--checksum").arg(sample_input());
+    let output = command.output().unwrap();
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(stdout.starts_with(SAMPLE_OUTPUT));
+
+    let path = env::temp_dir().join(format!("atm-checksum-{}.csv", std::process::id()));
+    fs::write(&path, &stdout).unwrap();
+    let mut verify = Command::cargo_bin("atm").unwrap();
+    verify.arg("verify-checksum").arg(&path);
+    verify.assert().success().stdout("Checksum is valid\n");
+
+    fs::write(&path, stdout.replace("1.5", "1.6")).unwrap();
+    let mut verify = Command::cargo_bin("atm").unwrap();
+    verify.arg("verify-checksum").arg(&path);
+    verify
+        .assert()
+        .failure()
+        .stderr(predicate::str::starts_with("Checksum mismatch"));
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn disabled_withdrawals() {
+    let mut command = Command::cargo_bin("atm").unwrap();
+    command
+        .args(["--disable", "withdrawal"])
+        .arg(sample_input());
+    command
+        .assert()
+        .success()
+        .stdout(predicate::eq(
+            r#"client,available,held,total,locked
+1,3,0,3,false
+2,2,0,2,false
+"#,
+        ))
+        .stderr(predicate::eq("Rejected 2 transactions of disabled types\n"));
+}
+
+#[test]
+fn blank_lines_are_skipped() {
+    let mut command = Command::cargo_bin("atm").unwrap();
+    command.arg(test_file("blank_lines.csv"));
+    command
+        .assert()
+        .success()
+        .stdout(predicate::eq(
+            r#"client,available,held,total,locked
+1,0.5,0,0.5,false
+"#,
+        ))
+        .stderr(predicate::eq(
+            "Skipping row: Expected 4 fields but found 3 in record 3 (line 5)\n",
+        ));
+}
+
+#[test]
+fn positions_after_multiline_fields() {
+    let mut command = Command::cargo_bin("atm").unwrap();
+    command.arg(test_file("multiline_field.csv"));
+    command
+        .assert()
+        .success()
+        .stdout(predicate::eq(
+            r#"client,available,held,total,locked
+1,3,0,3,false
+"#,
+        ))
+        .stderr(predicate::eq(
+            r#"Skipping row: Client 1 tx 2: Invalid amount "-1.0" in record 2 (line 4)
+Skipping row: Expected 5 fields but found 3 in record 4 (line 8)
+"#,
+        ));
+}
+
+#[test]
+fn with_lock_tx() {
+    let mut command = Command::cargo_bin("atm").unwrap();
+    command
+        .args(["--with-lock-tx", "--columns", "client,locked"])
+        .arg(test_file("locked_and_active.csv"));
+    command.assert().success().stdout(predicate::eq(
+        r#"client,locked,locked_by_tx
+1,false,
+2,true,2
+3,false,
+4,true,4
+"#,
+    ));
+}
+
+#[test]
+fn merge_by_tx() {
+    // Applied one file after the other the withdrawal of 4 (tx 2) would fail,
+    // but merged by tx id both withdrawals succeed.
+    let mut command = Command::cargo_bin("atm").unwrap();
+    command
+        .arg("--merge-by-tx")
+        .arg(test_file("merge_a.csv"))
+        .arg(test_file("merge_b.csv"));
+    command.assert().success().stdout(predicate::eq(
+        r#"client,available,held,total,locked
+1,0,0,0,false
+"#,
+    ));
+}
+
+#[test]
+fn perf_report() {
+    let mut command = Command::cargo_bin("atm").unwrap();
+    command.arg("--perf").arg(sample_input());
+    let output = command.output().unwrap();
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), SAMPLE_OUTPUT);
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    let line = stderr
+        .lines()
+        .find(|line| line.starts_with("perf: "))
+        .unwrap_or_else(|| panic!("no perf line in {:?}", stderr));
+    let fields: Vec<&str> = line["perf: ".len()..]
+        .split(' ')
+        .map(|field| field.split('=').next().unwrap())
+        .collect();
+    assert_eq!(
+        fields,
+        vec!["rows", "elapsed_ms", "rows_per_sec", "p50_ns", "p99_ns"]
+    );
+    assert!(line.contains("rows=5 "), "{}", line);
+}
+
+#[test]
+fn rewrite_keeps_raw_amounts() {
+    let rewrite = env::temp_dir().join(format!("atm-rewrite-{}.csv", std::process::id()));
+    let mut command = Command::cargo_bin("atm").unwrap();
+    command
+        .arg("--rewrite")
+        .arg(&rewrite)
+        .arg(test_file("rewrite.csv"));
+    command
+        .assert()
+        .success()
+        .stderr(predicate::str::contains("Skipping row"));
+    assert_eq!(
+        std::fs::read_to_string(&rewrite).unwrap(),
+        r#"type,client,tx,amount,idempotency_key
+deposit,1,1,5.00,
+withdrawal,1,3,0.50,
+dispute,1,1,,
+"#
+    );
+    std::fs::remove_file(rewrite).unwrap();
+}
+
+#[test]
+fn rewrite_uses_input_delimiter() {
+    let rewrite = env::temp_dir().join(format!("atm-rewrite-semicolon-{}.csv", std::process::id()));
+    let mut command = Command::cargo_bin("atm").unwrap();
+    command
+        .args(["--decimal-sep", ",", "--delimiter", ";", "--rewrite"])
+        .arg(&rewrite)
+        .arg(test_file("comma_decimal.csv"));
+    command.assert().success();
+    assert_eq!(
+        std::fs::read_to_string(&rewrite).unwrap(),
+        r#"type;client;tx;amount;idempotency_key
+deposit;1;1;5,50;
+deposit;2;2;1,75;
+withdrawal;1;3;2,25;
+deposit;2;4;,5;
+dispute;2;2;;
+"#
+    );
+    std::fs::remove_file(rewrite).unwrap();
+}
+
+#[test]
+fn trace_has_an_event_per_transaction() {
+    let trace = env::temp_dir().join(format!("atm-trace-{}.jsonl", std::process::id()));
+    let mut command = Command::cargo_bin("atm").unwrap();
+    command
+        .arg("--trace")
+        .arg(&trace)
+        .arg(test_file("trace.csv"));
+    command.assert().success();
+    let events: Vec<serde_json::Value> = std::fs::read_to_string(&trace)
+        .unwrap()
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    assert_eq!(events.len(), 4);
+    let rejected: Vec<_> = events
+        .iter()
+        .filter(|event| event["decision"] == "rejected")
+        .collect();
+    assert_eq!(rejected.len(), 1);
+    assert_eq!(rejected[0]["code"], "E_INSUFFICIENT_FUNDS");
+    assert_eq!(rejected[0]["available"], "1");
+    let deposits: Vec<_> = events
+        .iter()
+        .filter(|event| event["type"] == "deposit")
+        .collect();
+    assert_eq!(deposits.len(), 2);
+    for deposit in deposits {
+        assert_eq!(deposit["decision"], "applied", "{}", deposit);
+    }
+    assert_eq!(events[3]["held"], "2");
+    std::fs::remove_file(trace).unwrap();
+}
+
+#[cfg(feature = "mmap")]
+#[test]
+fn mmap_matches_read() {
+    let output = |mmap: bool| {
+        let mut command = Command::cargo_bin("atm").unwrap();
+        if mmap {
+            command.arg("--mmap");
+        }
+        command
+            .arg(test_file("locked_and_active.csv"))
+            .arg(sample_input());
+        command.output().unwrap()
+    };
+    let (read, mapped) = (output(false), output(true));
+    assert!(mapped.status.success());
+    assert!(!mapped.stdout.is_empty());
+    assert_eq!(read.stdout, mapped.stdout);
+}
+
+#[cfg(feature = "zstd")]
+#[test]
+fn zstd_matches_plain() {
+    let compressed = env::temp_dir().join(format!("atm-zstd-{}.csv.zst", std::process::id()));
+    let plain = std::fs::read(sample_input()).unwrap();
+    std::fs::write(&compressed, zstd::encode_all(&plain[..], 0).unwrap()).unwrap();
+    let output = |input: PathBuf| {
+        Command::cargo_bin("atm")
+            .unwrap()
+            .arg(input)
+            .output()
+            .unwrap()
+    };
+    let (plain, decompressed) = (output(sample_input()), output(compressed.clone()));
+    std::fs::remove_file(compressed).unwrap();
+    assert!(decompressed.status.success());
+    assert!(!decompressed.stdout.is_empty());
+    assert_eq!(plain.stdout, decompressed.stdout);
+}
+
+#[cfg(not(feature = "zstd"))]
+#[test]
+fn zstd_requires_feature() {
+    let compressed = env::temp_dir().join(format!("atm-zstd-{}.csv.zst", std::process::id()));
+    std::fs::write(&compressed, [0x28, 0xb5, 0x2f, 0xfd, 0]).unwrap();
+    let mut command = Command::cargo_bin("atm").unwrap();
+    command.arg(&compressed);
+    command
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("built without the `zstd` feature"));
+    std::fs::remove_file(compressed).unwrap();
+}
+
+#[test]
+fn limit() {
+    let mut command = Command::cargo_bin("atm").unwrap();
+    command
+        .args(["--limit", "3"])
+        .arg(test_file("five_rows.csv"));
+    command.assert().success().stdout(predicate::eq(
+        r#"client,available,held,total,locked
+1,3,0,3,false
+2,3,0,3,false
+"#,
+    ));
+}
+
+#[test]
+fn skip_and_limit() {
+    // Only the deposit for client 2 and the withdrawal for client 1, which
+    // fails as the deposits for client 1 were skipped.
+    let mut command = Command::cargo_bin("atm").unwrap();
+    command
+        .args(["--skip", "2", "--limit", "2"])
+        .arg(test_file("five_rows.csv"));
+    command.assert().success().stdout(predicate::eq(
+        r#"client,available,held,total,locked
+1,0,0,0,false
+2,3,0,3,false
+"#,
+    ));
+}
+
+#[test]
+fn skip_and_limit_count_invalid_rows() {
+    // The invalid second row is within the window and counts towards it, so
+    // the window is rows 2-4 and the deposit in row 5 isn't processed.
+    let mut command = Command::cargo_bin("atm").unwrap();
+    command
+        .args(["--skip", "1", "--limit", "3"])
+        .arg(test_file("window_invalid_row.csv"));
+    command
+        .assert()
+        .success()
+        .stdout(predicate::eq(
+            r#"client,available,held,total,locked
+1,0,0,0,false
+2,3,0,3,false
+"#,
+        ))
+        .stderr(predicate::str::contains("Invalid amount \"x\""));
+}
+
+#[test]
+fn max_precision_above_stored_decimals_is_rejected() {
+    let mut command = Command::cargo_bin("atm").unwrap();
+    command.args(["--max-precision", "6"]).arg(sample_input());
+    command.assert().failure().stderr(predicate::str::contains(
+        "--max-precision can't be more than 4",
+    ));
+}
+
+#[test]
+fn no_header() {
+    let mut command = Command::cargo_bin("atm").unwrap();
+    command
+        .arg("--no-header")
+        .arg(test_file("failed_withdrawal.csv"));
+    command.assert().success().stdout(predicate::eq(
+        r#"1,1,0,1,false
+2,0,0,0,false
+3,0,0,0,false
+"#,
+    ));
+}
+
+#[test]
+fn header_names() {
+    let mut command = Command::cargo_bin("atm").unwrap();
+    command
+        .arg("--columns")
+        .arg("client,total")
+        .arg("--header-names")
+        .arg("id,balance")
+        .arg(test_file("failed_withdrawal.csv"));
+    command.assert().success().stdout(predicate::eq(
+        r#"id,balance
+1,1
+2,0
+3,0
+"#,
+    ));
+
+    let mut command = Command::cargo_bin("atm").unwrap();
+    command
+        .arg("--header-names")
+        .arg("id,balance")
+        .arg(test_file("failed_withdrawal.csv"));
+    command.assert().failure().stderr(predicate::str::contains(
+        "--header-names has 2 names but there are 5 columns",
+    ));
+}
+
+#[test]
+fn output_csv_and_json() {
+    let dir = env::temp_dir();
+    let csv_path = dir.join(format!("atm-output-{}.csv", std::process::id()));
+    let json_path = dir.join(format!("atm-output-{}.jsonl", std::process::id()));
+    let mut command = Command::cargo_bin("atm").unwrap();
+    command
+        .arg("--output-csv")
+        .arg(&csv_path)
+        .arg("--output-json")
+        .arg(&json_path)
+        .arg(test_file("locked_and_active.csv"));
+    command.assert().success().stdout(predicate::eq(""));
+
+    let rows: Vec<csv::StringRecord> = csv::Reader::from_path(&csv_path)
+        .unwrap()
+        .records()
+        .map(Result::unwrap)
+        .collect();
+    let objects: Vec<serde_json::Value> = std::fs::read_to_string(&json_path)
+        .unwrap()
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    assert!(!rows.is_empty());
+    assert_eq!(rows.len(), objects.len());
+    for (row, object) in rows.iter().zip(&objects) {
+        assert_eq!(row[0], object["client"].to_string());
+        assert_eq!(row[1], object["available"]);
+        assert_eq!(row[2], object["held"]);
+        assert_eq!(row[3], object["total"]);
+        assert_eq!(row[4], object["locked"].to_string());
+    }
+    std::fs::remove_file(csv_path).unwrap();
+    std::fs::remove_file(json_path).unwrap();
+}
+
+#[test]
+fn anonymize_round_trips_through_mapping() {
+    let mapping_path = env::temp_dir().join(format!("atm-mapping-{}.csv", std::process::id()));
+    let output = |anonymize: bool| {
+        let mut command = Command::cargo_bin("atm").unwrap();
+        if anonymize {
+            command
+                .arg("--anonymize")
+                .arg("--mapping")
+                .arg(&mapping_path);
+        }
+        command.arg("--sort-by").arg("total").arg(sample_input());
+        let output = command.output().unwrap();
+        assert!(output.status.success());
+        String::from_utf8(output.stdout).unwrap()
+    };
+    let (plain, anonymized) = (output(false), output(true));
+    let mapping: std::collections::HashMap<String, String> = csv::Reader::from_path(&mapping_path)
+        .unwrap()
+        .records()
+        .map(|record| {
+            let record = record.unwrap();
+            (record[0].to_string(), record[1].to_string())
+        })
+        .collect();
+    std::fs::remove_file(&mapping_path).unwrap();
+
+    let mut ids = Vec::new();
+    let mut restored = Vec::new();
+    for (i, line) in anonymized.lines().enumerate() {
+        match line.split_once(',') {
+            Some((id, rest)) if i > 0 => {
+                ids.push(id.to_string());
+                restored.push(format!("{},{}", mapping[id], rest));
+            }
+            _ => restored.push(line.to_string()),
+        }
+    }
+    assert_eq!(
+        ids,
+        (1..=mapping.len())
+            .map(|id| id.to_string())
+            .collect::<Vec<_>>()
+    );
+    assert_eq!(restored, plain.lines().collect::<Vec<_>>());
+}
+
+#[test]
+fn anonymize_with_unsorted_is_rejected() {
+    let mut command = Command::cargo_bin("atm").unwrap();
+    command
+        .args(["--anonymize", "--unsorted"])
+        .arg(sample_input());
+    command
+        .assert()
+        .failure()
+        .stdout(predicate::str::is_empty())
+        .stderr(predicate::str::contains(
+            "--unsorted can't be used with --anonymize",
+        ));
+}
+
+#[test]
+fn reconcile_within_tolerance() {
+    let mut command = Command::cargo_bin("atm").unwrap();
+    command
+        .args(["--tolerance", "0.001", "--reconcile"])
+        .arg(test_file("reconcile_match.csv"))
+        .arg(sample_input());
+    command.assert().success();
+
+    let mut command = Command::cargo_bin("atm").unwrap();
+    command
+        .arg("--reconcile")
+        .arg(test_file("reconcile_match.csv"))
+        .arg(sample_input());
+    command.assert().failure().stderr(predicate::str::contains(
+        "Client 1: available is 1.5 but expected 1.5005",
+    ));
+}
+
+#[test]
+fn reconcile_mismatch() {
+    let mut command = Command::cargo_bin("atm").unwrap();
+    command
+        .args(["--tolerance", "0.001", "--reconcile"])
+        .arg(test_file("reconcile_mismatch.csv"))
+        .arg(sample_input());
+    command
+        .assert()
+        .failure()
+        .stdout(predicate::eq(""))
+        .stderr(predicate::eq(
+            r#"Client 1: available is 1.5 but expected 1.6
+Client 1: total is 1.5 but expected 1.6
+Client 2: not in the expected balances
+Client 3: missing from the output
+Reconciliation failed: 4 differences
+"#,
+        ));
+}
+
+#[test]
+fn unsorted_has_same_accounts() {
+    let output = |unsorted: bool| {
+        let mut command = Command::cargo_bin("atm").unwrap();
+        if unsorted {
+            command.arg("--unsorted");
+        }
+        command
+            .arg(test_file("locked_and_active.csv"))
+            .arg(sample_input());
+        let output = command.output().unwrap();
+        assert!(output.status.success());
+        String::from_utf8(output.stdout).unwrap()
+    };
+    let sorted = output(false);
+    let unsorted = output(true);
+    let mut lines: Vec<_> = unsorted.lines().collect();
+    // Keep the header first and sort the accounts by client.
+    lines[1..].sort_by_key(|line| line.split(',').next().unwrap().parse::<u32>().unwrap());
+    assert!(lines.len() > 2);
+    assert_eq!(lines, sorted.lines().collect::<Vec<_>>());
+}
+
+#[test]
+fn dead_letter_has_rejected_rows() {
+    let dead_letter = env::temp_dir().join(format!("atm-dead-letter-{}.csv", std::process::id()));
+    let mut command = Command::cargo_bin("atm").unwrap();
+    command
+        .arg("--dead-letter")
+        .arg(&dead_letter)
+        .arg(test_file("dead_letter.csv"));
+    command.assert().success().stdout(predicate::eq(
+        r#"client,available,held,total,locked
+1,1,0,1,false
+2,2,0,2,false
+"#,
+    ));
+    let rows: Vec<csv::StringRecord> = csv::ReaderBuilder::new()
+        .has_headers(false)
+        .flexible(true)
+        .from_path(&dead_letter)
+        .unwrap()
+        .records()
+        .map(Result::unwrap)
+        .collect();
+    std::fs::remove_file(dead_letter).unwrap();
+    assert_eq!(rows.len(), 2);
+    assert_eq!(
+        &rows[0].iter().take(4).collect::<Vec<_>>(),
+        &["withdrawal", "1", "2", "5.0"]
+    );
+    assert!(
+        rows[0][4].starts_with("Insufficient funds"),
+        "{:?}",
+        rows[0]
+    );
+    assert_eq!(
+        &rows[1].iter().take(4).collect::<Vec<_>>(),
+        &["deposit", "2", "3", "abc"]
+    );
+    assert_eq!(
+        &rows[1][4],
+        "Client 2 tx 3: Invalid amount \"abc\" in record 3 (line 4)"
+    );
+}
+
+#[test]
+fn dead_letter_uses_input_delimiter() {
+    let dead_letter = env::temp_dir().join(format!(
+        "atm-dead-letter-semicolon-{}.csv",
+        std::process::id()
+    ));
+    let mut command = Command::cargo_bin("atm").unwrap();
+    command
+        .args(["--decimal-sep", ",", "--delimiter", ";"])
+        .args(["--tx-uniqueness", "row", "--dead-letter"])
+        .arg(&dead_letter)
+        .arg(test_file("dead_letter_semicolon.csv"));
+    command.assert().success();
+    let rows: Vec<csv::StringRecord> = csv::ReaderBuilder::new()
+        .has_headers(false)
+        .flexible(true)
+        .delimiter(b';')
+        .from_path(&dead_letter)
+        .unwrap()
+        .records()
+        .map(Result::unwrap)
+        .collect();
+    std::fs::remove_file(dead_letter).unwrap();
+    assert_eq!(rows.len(), 2);
+    assert_eq!(
+        &rows[0].iter().take(4).collect::<Vec<_>>(),
+        &["withdrawal", "1", "2", "9"]
+    );
+    // Dropped for reusing tx 2 rather than rejected by the account.
+    assert_eq!(
+        &rows[1].iter().take(4).collect::<Vec<_>>(),
+        &["deposit", "1", "2", "1,75"]
+    );
+}
+
+#[test]
+fn hide_empty() {
+    // Client 2's only transaction is a withdrawal that fails.
+    let output = |hide_empty: bool| {
+        let mut command = Command::cargo_bin("atm").unwrap();
+        if hide_empty {
+            command.arg("--hide-empty");
+        }
+        command.arg(test_file("failed_withdrawal.csv"));
+        command.assert().success()
+    };
+    output(false).stdout(predicate::eq(
+        r#"client,available,held,total,locked
+1,1,0,1,false
+2,0,0,0,false
+3,0,0,0,false
+"#,
+    ));
+    output(true).stdout(predicate::eq(
+        r#"client,available,held,total,locked
+1,1,0,1,false
+3,0,0,0,false
+"#,
+    ));
+}
+
+#[test]
+fn merkle_root_is_stable() {
+    let root = |input: &str| {
+        let mut command = Command::cargo_bin("atm").unwrap();
+        command.arg("--merkle-root").arg(test_file(input));
+        let output = command.output().unwrap();
+        assert!(output.status.success());
+        let stderr = String::from_utf8(output.stderr).unwrap();
+        stderr
+            .lines()
+            .find_map(|line| line.strip_prefix("merkle_root="))
+            .unwrap()
+            .to_string()
+    };
+    assert_eq!(root("five_rows.csv"), root("five_rows.csv"));
+    assert_eq!(root("five_rows.csv").len(), 64);
+    assert_ne!(root("five_rows.csv"), root("failed_withdrawal.csv"));
+}
+
+#[test]
+fn quiet_has_no_stderr() {
+    // The input has an invalid row, which is otherwise reported.
+    let mut command = Command::cargo_bin("atm").unwrap();
+    command.arg("--quiet").arg(test_file("rewrite.csv"));
+    command
+        .assert()
+        .success()
+        .stdout(predicate::str::starts_with("client,"))
+        .stderr(predicate::eq(""));
+}
+
+#[test]
+fn very_verbose_reports_each_row() {
+    let mut command = Command::cargo_bin("atm").unwrap();
+    command.arg("-vv").arg(test_file("trace.csv"));
+    let output = command.output().unwrap();
+    assert!(output.status.success());
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert_eq!(
+        stderr.lines().collect::<Vec<_>>(),
+        vec![
+            "Applied deposit of client 1 tx 1",
+            "Rejected withdrawal of client 1 tx 2 [E_INSUFFICIENT_FUNDS]: Insufficient funds for \
+             transaction: Transaction { client: Client(1), tx: Tx(2), detail: Withdrawal(50000), \
+             idempotency_key: None }",
+            "Applied deposit of client 2 tx 3",
+            "Applied dispute of client 2 tx 3",
+        ]
+    );
 }
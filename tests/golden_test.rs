@@ -0,0 +1,53 @@
+//! Runs every `tests/cases/<name>.csv` through `process_csv` and compares the
+//! output with `tests/cases/<name>.expected.csv`. Set `UPDATE_GOLDEN=1` to
+//! write the current output as the expected output instead, e.g. after adding
+//! a new case.
+use std::{env, fs, path::PathBuf};
+
+const EXPECTED_SUFFIX: &str = ".expected.csv";
+
+fn cases_dir() -> PathBuf {
+    let manifest_path: PathBuf = env::var("CARGO_MANIFEST_DIR").unwrap().parse().unwrap();
+    manifest_path.join("tests").join("cases")
+}
+
+#[test]
+fn golden_files() {
+    let update = env::var("UPDATE_GOLDEN").is_ok_and(|value| value == "1");
+    let mut inputs: Vec<PathBuf> = fs::read_dir(cases_dir())
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| {
+            let name = path.file_name().unwrap().to_str().unwrap();
+            name.ends_with(".csv") && !name.ends_with(EXPECTED_SUFFIX)
+        })
+        .collect();
+    inputs.sort();
+    assert!(!inputs.is_empty(), "no cases in {:?}", cases_dir());
+
+    let mut failures = Vec::new();
+    for input in inputs {
+        let name = input.file_stem().unwrap().to_str().unwrap();
+        let expected_path = input.with_file_name(format!("{}{}", name, EXPECTED_SUFFIX));
+        let mut output = Vec::new();
+        atm::process_csv(fs::File::open(&input).unwrap(), &mut output)
+            .unwrap_or_else(|err| panic!("case {} failed: {}", name, err));
+        let output = String::from_utf8(output).unwrap();
+        if update {
+            fs::write(&expected_path, &output).unwrap();
+            continue;
+        }
+        match fs::read_to_string(&expected_path) {
+            Ok(expected) if expected == output => (),
+            Ok(expected) => failures.push(format!(
+                "case {}:\n--- expected\n{}--- actual\n{}",
+                name, expected, output
+            )),
+            Err(err) => failures.push(format!(
+                "case {}: can't read {:?} ({}), run with UPDATE_GOLDEN=1 to create it",
+                name, expected_path, err
+            )),
+        }
+    }
+    assert!(failures.is_empty(), "{}", failures.join("\n"));
+}